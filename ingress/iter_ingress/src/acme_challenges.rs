@@ -0,0 +1,288 @@
+//! # Cluster-wide ACME Challenges
+//!
+//! The ingress runs on every node, and the CA's HTTP-01 validation request may land on any of them. So challenges
+//! aren't only kept in the memory of the replica which started the order: they are published as config maps in
+//! the ingress' namespace, labelled with [`CHALLENGE_LABEL`], which every replica watches and serves from.
+//!
+//! Before the CA is told a challenge is ready, the publishing replica asks every ready ingress pod for the token
+//! over http and waits until all of them answer with it. Every replica deletes published challenges once they are
+//! older than [`CHALLENGE_TTL`], checking every [`EXPIRY_INTERVAL`] while it watches them.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::header::HOST;
+use hyper::{Body, Request};
+use iter_letsencrypt::account::ServesChallenge;
+use iter_letsencrypt::challenge::Http01Challenge;
+use k8s_openapi::api::core::v1::{ConfigMap, Pod};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::{DeleteParams, ListParams, ObjectMeta, PostParams};
+use kube::runtime::watcher::Event;
+use kube::{runtime, Api, Client, ResourceExt};
+use tracing::{info, warn};
+
+use crate::cert_storage::name_hash;
+use crate::certificate_state::CertificateState;
+
+pub const CHALLENGE_LABEL: &str = "iter.earth/acme-challenge";
+
+/// How long a published challenge is kept, orders which take longer than this have failed anyway.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(600);
+
+/// How often published challenges are checked for ones past [`CHALLENGE_TTL`].
+pub const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const PROPAGATION_TIMEOUT: Duration = Duration::from_secs(30);
+const PROPAGATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct ClusterChallenges {
+    client: Client,
+    namespace: String,
+    /// label selector matching every ingress pod
+    peer_selector: String,
+    /// port the ingress pods serve http on
+    http_port: u16,
+    cert_state: Arc<CertificateState>,
+}
+
+impl ClusterChallenges {
+    pub fn new(client: Client, namespace: String, peer_selector: String, http_port: u16, cert_state: Arc<CertificateState>) -> ClusterChallenges {
+        ClusterChallenges {
+            client,
+            namespace,
+            peer_selector,
+            http_port,
+            cert_state,
+        }
+    }
+
+    fn config_maps(&self) -> Api<ConfigMap> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// Serves every challenge published by any replica, and deletes the expired ones.
+    pub async fn watch(self: Arc<Self>) -> Result<(), anyhow::Error> {
+        let params = ListParams::default().labels(CHALLENGE_LABEL);
+        let mut stream = Box::pin(runtime::watcher(self.config_maps(), params));
+        // `(domain, path)` of every challenge served from a config map
+        let mut served: HashSet<(String, String)> = HashSet::new();
+        let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+
+        loop {
+            let event = tokio::select! {
+                event = stream.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = expiry.tick() => {
+                    self.remove_expired().await;
+                    continue;
+                }
+            };
+
+            match event {
+                Ok(Event::Applied(config_map)) => {
+                    if let Some(challenge) = challenge_from_config_map(&config_map) {
+                        served.insert((challenge.domain.clone(), challenge.path.clone()));
+                        self.cert_state.apply_challenge(challenge).await;
+                    }
+                }
+                Ok(Event::Deleted(config_map)) => {
+                    if let Some(challenge) = challenge_from_config_map(&config_map) {
+                        served.remove(&(challenge.domain.clone(), challenge.path.clone()));
+                        self.cert_state.remove_challenge(&challenge.domain, &challenge.path).await;
+                    }
+                }
+                Ok(Event::Restarted(config_maps)) => {
+                    let challenges: Vec<Http01Challenge> = config_maps.iter().filter_map(challenge_from_config_map).collect();
+                    let published: HashSet<(String, String)> = challenges
+                        .iter()
+                        .map(|challenge| (challenge.domain.clone(), challenge.path.clone()))
+                        .collect();
+
+                    // challenges deleted while the watch was down
+                    for (domain, path) in served.difference(&published) {
+                        self.cert_state.remove_challenge(domain, path).await;
+                    }
+                    for challenge in challenges {
+                        self.cert_state.apply_challenge(challenge).await;
+                    }
+                    served = published;
+                }
+                Err(e) => warn!("acme_challenges: error watching challenges: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish(&self, challenge: &Http01Challenge) -> Result<(), kube::Error> {
+        let config_map = challenge_config_map(&self.namespace, challenge);
+        match self.config_maps().create(&PostParams::default(), &config_map).await {
+            Ok(_) => Ok(()),
+            // the same token is published again when an order is retried
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes challenges published longer than [`CHALLENGE_TTL`] ago, by any replica.
+    async fn remove_expired(&self) {
+        let config_maps = self.config_maps();
+        let published = match config_maps.list(&ListParams::default().labels(CHALLENGE_LABEL)).await {
+            Ok(published) => published,
+            Err(e) => return warn!("acme_challenges: could not list challenges: {}", e),
+        };
+
+        let expired_before = Utc::now() - chrono::Duration::from_std(CHALLENGE_TTL).unwrap();
+        for config_map in published {
+            let expired = config_map.metadata.creation_timestamp.as_ref().map(|Time(created)| *created < expired_before).unwrap_or(false);
+            if expired {
+                let _ = config_maps.delete(&config_map.name(), &DeleteParams::default()).await;
+            }
+        }
+    }
+
+    /// Addresses of every ready ingress pod.
+    async fn peers(&self) -> Result<Vec<String>, kube::Error> {
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &self.namespace);
+        let pods = pods.list(&ListParams::default().labels(&self.peer_selector)).await?;
+
+        Ok(pods
+            .into_iter()
+            .filter_map(|pod| pod.status)
+            .filter(|status| status.conditions
+                .iter()
+                .flatten()
+                .any(|condition| condition.type_ == "Ready" && condition.status == "True"))
+            .filter_map(|status| status.pod_ip)
+            .collect())
+    }
+
+    /// Waits until every ready ingress pod serves the challenge, returning whether they all do.
+    async fn wait_until_visible(&self, challenge: &Http01Challenge) -> bool {
+        let client = hyper::Client::new();
+
+        let visible = tokio::time::timeout(PROPAGATION_TIMEOUT, async {
+            loop {
+                let peers = self.peers().await.unwrap_or_else(|e| {
+                    warn!("acme_challenges: could not list ingress pods: {}", e);
+                    Vec::new()
+                });
+
+                let mut missing = 0;
+                for peer in &peers {
+                    if !serves_challenge(&client, peer, self.http_port, challenge).await {
+                        missing += 1;
+                    }
+                }
+
+                if !peers.is_empty() && missing == 0 {
+                    return;
+                }
+
+                tokio::time::sleep(PROPAGATION_POLL_INTERVAL).await;
+            }
+        }).await;
+
+        visible.is_ok()
+    }
+}
+
+#[async_trait::async_trait]
+impl ServesChallenge for ClusterChallenges {
+    async fn prepare_challenge(self: &Arc<Self>, challenge: Http01Challenge) {
+        // served here straight away, the other replicas pick it up from the published config map
+        self.cert_state.apply_challenge(challenge.clone()).await;
+
+        if let Err(e) = self.publish(&challenge).await {
+            return warn!("acme_challenges: could not publish challenge for {}: {}", challenge.domain, e);
+        }
+
+        match self.wait_until_visible(&challenge).await {
+            true => info!("acme_challenges: challenge for {} is served by every replica", challenge.domain),
+            false => warn!("acme_challenges: challenge for {} is not served by every replica after {:?}, validating anyway", challenge.domain, PROPAGATION_TIMEOUT),
+        }
+    }
+}
+
+async fn serves_challenge(client: &hyper::Client<hyper::client::HttpConnector>, peer: &str, port: u16, challenge: &Http01Challenge) -> bool {
+    // ipv6 pod addresses need brackets in a uri
+    let authority = match peer.contains(':') {
+        true => format!("[{}]:{}", peer, port),
+        false => format!("{}:{}", peer, port),
+    };
+
+    let request = match Request::get(format!("http://{}{}", authority, challenge.path)).header(HOST, &challenge.domain).body(Body::empty()) {
+        Ok(request) => request,
+        Err(_) => return false,
+    };
+
+    let served: Option<bool> = try {
+        let response = tokio::time::timeout(PROPAGATION_POLL_INTERVAL * 4, client.request(request)).await.ok()?.ok()?;
+        let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+        body == challenge.contents.as_bytes()
+    };
+
+    served.unwrap_or(false)
+}
+
+/// Tokens may contain characters which aren't allowed in names, so config maps are named by a hash of the token.
+fn config_map_name(challenge: &Http01Challenge) -> String {
+    format!("acme-challenge-{}", name_hash(&challenge.path, 10))
+}
+
+pub fn challenge_config_map(namespace: &str, challenge: &Http01Challenge) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(config_map_name(challenge)),
+            namespace: Some(namespace.to_string()),
+            labels: Some([(CHALLENGE_LABEL.to_string(), "true".to_string())].into_iter().collect()),
+            ..Default::default()
+        },
+        data: Some([
+            ("domain".to_string(), challenge.domain.clone()),
+            ("path".to_string(), challenge.path.clone()),
+            ("contents".to_string(), challenge.contents.clone()),
+            ("challenge_url".to_string(), challenge.challenge_url.clone()),
+        ].into_iter().collect()),
+        ..Default::default()
+    }
+}
+
+pub fn challenge_from_config_map(config_map: &ConfigMap) -> Option<Http01Challenge> {
+    let data = config_map.data.as_ref()?;
+
+    Some(Http01Challenge {
+        domain: data.get("domain")?.clone(),
+        path: data.get("path")?.clone(),
+        contents: data.get("contents")?.clone(),
+        challenge_url: data.get("challenge_url").cloned().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_challenges_through_config_maps() {
+        let challenge = Http01Challenge {
+            path: "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0".to_string(),
+            contents: "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0.9jg46WB3rR_AHD-EBXdN7cBkH1WOu0tA3M9fm21mqTI".to_string(),
+            challenge_url: "https://acme.example/chall/1".to_string(),
+            domain: "example.com".to_string(),
+        };
+
+        let config_map = challenge_config_map("iter", &challenge);
+        let name = config_map.name();
+        assert!(name.len() <= 63 && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+
+        let parsed = challenge_from_config_map(&config_map).unwrap();
+        assert_eq!((parsed.domain, parsed.path, parsed.contents), (challenge.domain, challenge.path, challenge.contents));
+    }
+}
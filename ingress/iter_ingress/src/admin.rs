@@ -0,0 +1,297 @@
+//! # Admin API
+//!
+//! An authenticated http listener for inspecting and controlling a running ingress.
+//!
+//! Every request must carry `Authorization: Bearer <token>`. The listener only starts when `admin_token` is set in
+//! the [`Settings`](crate::settings::Settings), on `admin_addr` (default `127.0.0.1:9090`).
+//!
+//! ## Endpoints
+//! - `GET /routes` - the routing table, by host
+//! - `GET /certificates` - loaded certificates with their SANs and expiry
+//! - `GET /challenges` - pending ACME challenges
+//! - `GET /upstreams` - upstream health
+//! - `GET /connections` - active client connections
+//! - `GET /metrics` - prometheus metrics
+//! - `POST /resync` - list every ingress again and rebuild the routing table
+//! - `POST /certificates/{host}/renew` - issue a new certificate for the host on the next certificate check
+//! - `POST /certificates/{host}/revoke` - revoke the host's certificates, whose keys are believed compromised, and
+//!   issue new ones
+//! - `POST /caches/purge` - forget upstream health, rebuild the routing table and fetch OCSP responses again
+//! - `GET /local-ca.crt` - the PEM encoded root of the [local CA](crate::local_ca), for clients' trust stores
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::certificate_state::CertificateState;
+use crate::kube_config_tracker::RoutingTable;
+use crate::local_ca::LocalCa;
+use crate::metrics::Metrics;
+use crate::ocsp::OcspStapler;
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    pub token: Option<String>,
+}
+
+pub struct AdminApi {
+    pub routing_table: Arc<RoutingTable>,
+    pub cert_state: Arc<CertificateState>,
+    pub metrics: Arc<Metrics>,
+    pub local_ca: Option<Arc<LocalCa>>,
+    /// `None` when OCSP stapling is off
+    pub ocsp: Option<Arc<OcspStapler>>,
+}
+
+impl AdminApi {
+    pub async fn serve(self: Arc<Self>, config: AdminConfig) -> Result<(), hyper::Error> {
+        let token = match config.token {
+            Some(token) => Arc::new(token),
+            None => {
+                info!("admin: no admin_token is set, the admin api is disabled");
+                return Ok(());
+            }
+        };
+
+        let make_service = make_service_fn(move |_| {
+            let api = self.clone();
+            let token = token.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let api = api.clone();
+                    let token = token.clone();
+                    async move { Ok::<_, Infallible>(api.handle(req, &token).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&config.addr)?.serve(make_service);
+        info!("admin: listening on {}", config.addr);
+        server.await
+    }
+
+    async fn handle(&self, req: Request<Body>, token: &str) -> Response<Body> {
+        if !is_authorized(&req, token) {
+            return json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+        }
+
+        let path = req.uri().path().trim_end_matches('/');
+        let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["routes"]) => json_response(StatusCode::OK, self.routes().await),
+            (&Method::GET, ["certificates"]) => json_response(StatusCode::OK, self.certificates().await),
+            (&Method::GET, ["challenges"]) => json_response(StatusCode::OK, self.challenges().await),
+            (&Method::GET, ["upstreams"]) => json_response(StatusCode::OK, self.upstreams().await),
+            (&Method::GET, ["connections"]) => json_response(StatusCode::OK, json!({
+                "active": self.metrics.active_connections.load(Ordering::Relaxed),
+            })),
+            (&Method::GET, ["metrics"]) => Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(self.metrics.render().await))
+                .unwrap(),
+            (&Method::POST, ["resync"]) => {
+                self.routing_table.request_resync();
+                json_response(StatusCode::ACCEPTED, json!({ "resync": "requested" }))
+            }
+            (&Method::POST, ["certificates", host, "renew"]) => {
+                self.cert_state.request_renewal(host).await;
+                json_response(StatusCode::ACCEPTED, json!({ "renewal": "requested", "host": host }))
+            }
+            (&Method::POST, ["certificates", host, "revoke"]) => {
+                self.cert_state.request_revocation(host).await;
+                json_response(StatusCode::ACCEPTED, json!({ "revocation": "requested", "host": host }))
+            }
+            (&Method::POST, ["caches", "purge"]) => {
+                self.metrics.purge_upstreams().await;
+                self.routing_table.request_resync();
+                let mut purged = vec!["upstreams", "routes"];
+                if let Some(ocsp) = &self.ocsp {
+                    ocsp.purge().await;
+                    purged.push("ocsp");
+                }
+                json_response(StatusCode::ACCEPTED, json!({ "purged": purged }))
+            }
+            (&Method::GET, ["local-ca.crt"]) => {
+                let root = match &self.local_ca {
+                    Some(local_ca) => local_ca.root_pem().await,
+                    None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "the local CA is not enabled" })),
+                };
+                match root {
+                    Some(root) => Response::builder()
+                        .header(CONTENT_TYPE, "application/x-pem-file")
+                        .body(Body::from(root))
+                        .unwrap(),
+                    None => json_response(StatusCode::NOT_FOUND, json!({ "error": "the local CA has not issued a certificate yet" })),
+                }
+            }
+            _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        }
+    }
+
+    async fn routes(&self) -> Value {
+        let backends_by_host = self.routing_table.backends_by_host.read().await;
+
+        backends_by_host.iter().map(|(host, backends)| {
+            let backends = backends.iter().map(|backend| json!({
+                "path": backend.path_pattern(),
+                "service": backend.service_name,
+                "port": backend.port,
+            })).collect::<Vec<_>>();
+
+            (host.clone(), json!(backends))
+        }).collect::<serde_json::Map<_, _>>().into()
+    }
+
+    async fn certificates(&self) -> Value {
+        let certs = self.cert_state.certs.read().await;
+        let user_certs = self.cert_state.user_certs.read().await;
+        let renewals = self.cert_state.renewals.read().await;
+        let revocations = self.cert_state.revocations.read().await;
+
+        let issued = if self.local_ca.is_some() { "local-ca" } else { "acme" };
+        let sources = user_certs.iter().map(|cert| ("secret", cert))
+            .chain(certs.iter().filter(|(host, _)| !user_certs.contains_key(*host)).map(move |cert| (issued, cert)));
+
+        sources.flat_map(|(source, (host, certs))| certs.iter().map(move |cert| (source, host, cert))).map(|(source, host, cert)| {
+            let info = cert.info();
+            json!({
+                "host": host,
+                "source": source,
+                "key_type": cert.key_type.to_string(),
+                "subject_alt_names": info.as_ref().map(|info| info.subject_alt_names.clone()),
+                "not_after": info.as_ref().map(|info| info.not_after.clone()),
+                "valid_days_left": info.as_ref().map(|info| info.valid_days_left),
+                "renewal_requested": renewals.contains(host),
+                "revocation_requested": revocations.contains(host),
+                "ocsp_stapled": cert.ocsp().is_some(),
+            })
+        }).collect::<Vec<_>>().into()
+    }
+
+    async fn challenges(&self) -> Value {
+        let challenges = self.cert_state.challenges.read().await;
+
+        challenges.values().map(|challenge| json!({
+            "domain": challenge.domain,
+            "path": challenge.path,
+            "challenge_url": challenge.challenge_url,
+        })).collect::<Vec<_>>().into()
+    }
+
+    async fn upstreams(&self) -> Value {
+        let upstreams = self.metrics.upstreams.read().await;
+        let unix_secs = |time: Option<std::time::SystemTime>| {
+            time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs())
+        };
+
+        upstreams.iter().map(|(upstream, health)| json!({
+            "upstream": upstream,
+            "healthy": health.is_healthy(),
+            "requests": health.requests,
+            "failures": health.failures,
+            "consecutive_failures": health.consecutive_failures,
+            "last_error": health.last_error,
+            "last_success": unix_secs(health.last_success),
+            "last_failure": unix_secs(health.last_failure),
+        })).collect::<Vec<_>>().into()
+    }
+}
+
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) => constant_time_eq(provided.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_api() -> AdminApi {
+        AdminApi {
+            routing_table: Arc::new(RoutingTable::new()),
+            cert_state: Arc::new(CertificateState::new()),
+            metrics: Arc::new(Metrics::new()),
+            local_ca: None,
+            ocsp: None,
+        }
+    }
+
+    fn request(method: Method, path: &str, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_or_wrong_token() {
+        let api = admin_api();
+
+        let response = api.handle(request(Method::GET, "/routes", None), "secret").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = api.handle(request(Method::GET, "/routes", Some("wrong")), "secret").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn requests_certificate_renewal() {
+        let api = admin_api();
+
+        let response = api.handle(request(Method::POST, "/certificates/example.com/renew", Some("secret")), "secret").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(api.cert_state.renewals.read().await.contains("example.com"));
+
+        let response = api.handle(request(Method::GET, "/certificates/example.com", Some("secret")), "secret").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    #[tokio::test]
+    async fn purges_upstream_health() {
+        let api = admin_api();
+        api.metrics.record_upstream_failure("web.default.svc.cluster.local:80", "connection refused").await;
+
+        let response = api.handle(request(Method::POST, "/caches/purge", Some("secret")), "secret").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(api.metrics.upstreams.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_certificate_revocation() {
+        let api = admin_api();
+
+        let response = api.handle(request(Method::POST, "/certificates/example.com/revoke", Some("secret")), "secret").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(api.cert_state.revocations.read().await.contains("example.com"));
+    }
+}
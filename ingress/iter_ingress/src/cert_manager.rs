@@ -0,0 +1,949 @@
+//! # Certificate Manager
+//!
+//! Keeps a certificate of each of [`CertManagerSettings::key_types`] for every host the ingress terminates tls for.
+//! The leader has its [`CertIssuer`], ACME or the [local CA](crate::local_ca), issue one as soon as a host shows up in
+//! the routing table, and renews certificates once they have fewer than [`CertManagerSettings::renew_before_days`]
+//! left, or a third of their lifetime for certificates which don't live that long to begin with. Renewals are spread
+//! out with a random delay, so certificates issued together aren't all renewed in the same minute. Issued certificates
+//! are stored, and every replica loads them from storage with [`follow_certificates`].
+//!
+//! Hosts approved for [on-demand issuance](crate::on_demand) get certificates like ingress hosts do.
+//!
+//! Certificates of hosts which no longer need one, because no ingress routes them or they bring their own, are
+//! removed once [`CertManagerSettings::orphan_grace`] has passed, and stop being served by every replica. They are
+//! archived under the issuer's name with `-archived` appended, or deleted, and optionally revoked first. The grace
+//! period starts over when the leader changes. A certificate whose key is believed compromised is revoked and
+//! replaced right away once its host is passed to [`CertificateState::request_revocation`].
+//!
+//! Every certificate is ordered in a task of its own. A certificate whose order fails is retried with exponential
+//! backoff, up to [`CertManagerSettings::max_retry`], without holding up any other. The failure is logged, counted in
+//! the metrics and published as an event on the host's ingresses, like issued and renewed certificates are. Orders
+//! wait for as long as a CA which refused one asks in its `Retry-After`, and stay within the issuer's [`RateLimits`]
+//! rather than have orders refused. Attempts, failures and issued certificates are saved in storage, so the backoff
+//! and the limits hold across restarts and leader changes.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::StreamExt;
+use kube::runtime::events::EventType;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify, Semaphore};
+use tracing::{info, warn};
+
+use crate::cert_storage::{CertStorage, TlsChange, TlsEntry};
+use crate::certificate_state::{cert_key_from_pem, cert_key_to_pem, CertKey, CertificateState, Host, KeyType};
+use crate::error::IngressLoadBalancerError;
+use crate::events::IngressEvents;
+use crate::kube_config_tracker::RoutingTable;
+use crate::metrics::Metrics;
+use crate::on_demand::OnDemand;
+
+/// How often certificates are checked for expiry when the routing table doesn't change.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Wait before the first retry of a failed order, doubled with every further failure.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+/// Renewals are delayed by up to this long.
+const RENEWAL_JITTER: Duration = Duration::from_secs(6 * 60 * 60);
+/// Orders running at the same time, the CA limits how many it takes from one account anyway.
+const MAX_CONCURRENT_ORDERS: usize = 4;
+/// Key of the issuance log in its storage entry.
+const ISSUANCE_LOG_KEY: &str = "log.json";
+
+#[derive(Debug, Clone)]
+pub struct CertManagerSettings {
+    /// renew certificates with fewer than this many days left
+    pub renew_before_days: u32,
+    /// longest wait before retrying a failed order
+    pub max_retry: Duration,
+    /// every host gets a certificate of each type, at most one RSA and one ECDSA
+    pub key_types: Vec<KeyType>,
+    /// how long a host goes without needing its certificates before they are removed
+    pub orphan_grace: Duration,
+    pub orphan_action: OrphanAction,
+    /// revoke certificates before removing them
+    pub revoke_orphans: bool,
+}
+
+/// What happens to the certificates of hosts which no longer need them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// stored under `<issuer>-archived`, where nothing loads them from
+    Archive,
+    Delete,
+}
+
+impl std::str::FromStr for OrphanAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "archive" => Ok(OrphanAction::Archive),
+            "delete" => Ok(OrphanAction::Delete),
+            other => Err(format!("expected archive or delete, got {:?}", other)),
+        }
+    }
+}
+
+/// Why a certificate is revoked, with its RFC 5280 reason code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevocationReason {
+    KeyCompromise = 1,
+    CessationOfOperation = 5,
+}
+
+/// Where certificates come from, e.g. an ACME account.
+#[async_trait::async_trait]
+pub trait CertIssuer: Send + Sync {
+    /// Labels the issuer's certificates in storage, e.g. `letsencrypt-production`.
+    fn name(&self) -> String;
+
+    /// A new certificate for the host, for a new key of `key_type`. Only called on the leader.
+    async fn issue(&self, host: &str, key_type: KeyType) -> Result<CertKey, IngressLoadBalancerError>;
+
+    /// Revokes a certificate the issuer issued. Issuers which publish no revocation status have nothing to do.
+    async fn revoke(&self, _cert: &CertKey, _reason: RevocationReason) -> Result<(), IngressLoadBalancerError> {
+        Ok(())
+    }
+
+    /// The limits orders are kept within, none by default.
+    fn limits(&self) -> RateLimits {
+        RateLimits::default()
+    }
+}
+
+/// Issuance limits of a CA, each a number of events in a sliding window.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    /// certificates issued for hosts under the same registered domain
+    pub certificates_per_domain: Option<(usize, Duration)>,
+    /// failed orders for the same host
+    pub failures_per_host: Option<(usize, Duration)>,
+}
+
+/// Why a host needs a new certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Missing,
+    Requested,
+    Expiring,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct HostSchedule {
+    next_attempt: Option<SystemTime>,
+    /// failed orders since the last certificate was issued
+    failures: u32,
+    /// the next attempt waits for a rate limit, whatever the reason to order
+    rate_limited: bool,
+    last_error: Option<String>,
+    #[serde(skip)]
+    ordering: bool,
+}
+
+/// Orders, failures and issued certificates, as far as backoff and rate limits need to remember them.
+#[derive(Debug, Default)]
+struct IssuanceLog {
+    schedule: HashMap<(Host, KeyType), HostSchedule>,
+    /// when certificates were issued, by registered domain
+    issued: HashMap<String, Vec<SystemTime>>,
+    /// when orders failed, by host
+    failed: HashMap<Host, Vec<SystemTime>>,
+}
+
+/// How an [`IssuanceLog`] is saved, json has no tuple keys.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredIssuanceLog {
+    schedule: Vec<(Host, String, HostSchedule)>,
+    issued: HashMap<String, Vec<SystemTime>>,
+    failed: HashMap<Host, Vec<SystemTime>>,
+}
+
+impl IssuanceLog {
+    /// When orders for the host may go ahead again without exceeding `limits`, `None` if they may now. Orders in
+    /// progress count as issued.
+    fn limited_until(&self, host: &str, limits: &RateLimits, now: SystemTime) -> Option<(SystemTime, &'static str)> {
+        let domain = registered_domain(host);
+        let ordering = self.schedule.iter().filter(|((host, _), entry)| entry.ordering && registered_domain(host) == domain).count();
+
+        let per_domain = limits.certificates_per_domain.and_then(|(limit, window)| {
+            let issued = self.issued.get(&domain).map(Vec::as_slice).unwrap_or_default();
+            until_below(issued, ordering, limit, window, now).map(|until| (until, "certificates per registered domain"))
+        });
+        let per_host = limits.failures_per_host.and_then(|(limit, window)| {
+            let failed = self.failed.get(host).map(Vec::as_slice).unwrap_or_default();
+            until_below(failed, 0, limit, window, now).map(|until| (until, "failed orders per host"))
+        });
+
+        per_domain.into_iter().chain(per_host).max_by_key(|(until, _)| *until)
+    }
+
+    /// Forgets events which no longer count towards `limits`.
+    fn prune(&mut self, limits: &RateLimits, now: SystemTime) {
+        let keep = |times: &mut Vec<SystemTime>, limit: Option<(usize, Duration)>| {
+            let window = limit.map(|(_, window)| window).unwrap_or_default();
+            times.retain(|time| now.duration_since(*time).unwrap_or_default() < window);
+            !times.is_empty()
+        };
+        self.issued.retain(|_, times| keep(times, limits.certificates_per_domain));
+        self.failed.retain(|_, times| keep(times, limits.failures_per_host));
+    }
+
+    fn to_stored(&self) -> StoredIssuanceLog {
+        StoredIssuanceLog {
+            schedule: self.schedule.iter().map(|((host, key_type), entry)| (host.clone(), key_type.to_string(), entry.clone())).collect(),
+            issued: self.issued.clone(),
+            failed: self.failed.clone(),
+        }
+    }
+
+    fn from_stored(stored: StoredIssuanceLog) -> IssuanceLog {
+        IssuanceLog {
+            schedule: stored.schedule
+                .into_iter()
+                .filter_map(|(host, key_type, entry)| Some(((host, key_type.parse().ok()?), entry)))
+                .collect(),
+            issued: stored.issued,
+            failed: stored.failed,
+        }
+    }
+}
+
+/// When fewer than `limit` of `times` plus `pending` fall within `window`, `None` if they already do.
+fn until_below(times: &[SystemTime], pending: usize, limit: usize, window: Duration, now: SystemTime) -> Option<SystemTime> {
+    let mut recent: Vec<SystemTime> = times.iter().copied().filter(|time| now.duration_since(*time).unwrap_or_default() < window).collect();
+    if recent.len() + pending < limit {
+        return None;
+    }
+
+    // pending orders never fall out of the window, when they are all that's left the wait is a full window
+    recent.sort();
+    let expiring = (recent.len() + pending + 1).saturating_sub(limit);
+    Some(recent.get(expiring - 1).map(|time| *time + window).unwrap_or(now + window))
+}
+
+/// The domain a host is registered under, which CAs count certificates by. Without the public suffix list it's the
+/// last two labels, which lumps hosts under suffixes like `co.uk` together and so only ever errs on the safe side.
+fn registered_domain(host: &str) -> String {
+    let labels: Vec<&str> = host.trim_end_matches('.').rsplit('.').take(2).collect();
+    labels.into_iter().rev().collect::<Vec<_>>().join(".")
+}
+
+pub struct CertManager {
+    routing_table: Arc<RoutingTable>,
+    state: Arc<CertificateState>,
+    storage: CertStorage,
+    issuer: Arc<dyn CertIssuer>,
+    settings: CertManagerSettings,
+    on_demand: Option<Arc<OnDemand>>,
+    log: Mutex<IssuanceLog>,
+    /// saves of the issuance log one at a time, so an older one never replaces a newer one
+    saving: tokio::sync::Mutex<()>,
+    /// certificates issued again since their host's renewal was requested
+    renewed: Mutex<HashSet<(Host, KeyType)>>,
+    /// hosts with certificates which don't need them, since when
+    orphaned: Mutex<HashMap<Host, Instant>>,
+    orders: Semaphore,
+    changed: Arc<Notify>,
+    metrics: Arc<Metrics>,
+    events: Option<Arc<IngressEvents>>,
+}
+
+impl CertManager {
+    pub async fn new(
+        routing_table: Arc<RoutingTable>,
+        state: Arc<CertificateState>,
+        storage: CertStorage,
+        issuer: Arc<dyn CertIssuer>,
+        settings: CertManagerSettings,
+        metrics: Arc<Metrics>,
+        events: Option<Arc<IngressEvents>>,
+    ) -> Arc<CertManager> {
+        let on_demand = state.on_demand.read().await.clone();
+        let changed = Arc::new(Notify::new());
+        routing_table.subscribe(Box::new({
+            let changed = changed.clone();
+            move |_| changed.notify_one()
+        })).await;
+
+        Arc::new(CertManager {
+            routing_table,
+            state,
+            storage,
+            issuer,
+            settings,
+            on_demand,
+            log: Mutex::new(IssuanceLog::default()),
+            saving: tokio::sync::Mutex::new(()),
+            renewed: Mutex::new(HashSet::new()),
+            orphaned: Mutex::new(HashMap::new()),
+            orders: Semaphore::new(MAX_CONCURRENT_ORDERS),
+            changed,
+            metrics,
+            events,
+        })
+    }
+
+    /// Issues and renews certificates while `leader` is true, so followers never talk to the CA.
+    pub async fn run(self: Arc<Self>, mut leader: watch::Receiver<bool>) {
+        let mut was_leader = false;
+        loop {
+            let is_leader = *leader.borrow_and_update();
+            if !is_leader {
+                was_leader = false;
+                if leader.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            // the previous leader's orders count towards the backoff and the limits too
+            if !was_leader {
+                self.load_log().await;
+                was_leader = true;
+            }
+
+            self.remove_certificates().await;
+
+            for (host, key_type) in self.due_certificates().await {
+                tokio::spawn(self.clone().order(host, key_type));
+            }
+
+            let on_demand_changed = async {
+                match &self.on_demand {
+                    Some(on_demand) => on_demand.changed.notified().await,
+                    None => futures::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+                _ = self.changed.notified() => {}
+                _ = on_demand_changed => {}
+                _ = leader.changed() => {}
+            }
+        }
+    }
+
+    /// Hosts which should have certificates.
+    async fn hosts(&self) -> HashSet<Host> {
+        let mut hosts = self.routing_table.tls_hosts().await;
+        if let Some(on_demand) = &self.on_demand {
+            hosts.extend(on_demand.hosts());
+        }
+        hosts
+    }
+
+    /// Revokes and replaces the certificates whose revocation was requested, and removes those of hosts which
+    /// haven't needed them for the grace period.
+    async fn remove_certificates(&self) {
+        let revocations: Vec<Host> = self.state.revocations.read().await.iter().cloned().collect();
+        for host in revocations {
+            // the host is ordered a new certificate as one which is missing, if it still needs one
+            match self.remove(&host, Some(RevocationReason::KeyCompromise), OrphanAction::Delete).await {
+                Ok(()) => {
+                    self.state.revocations.write().await.remove(&host);
+                    info!("cert_manager: revoked the certificates for {}", host);
+                }
+                Err(e) => warn!("cert_manager: could not revoke the certificates for {}, retrying: {}", host, e),
+            }
+        }
+
+        let hosts = self.hosts().await;
+        let orphans = {
+            let certs = self.state.certs.read().await;
+            let mut orphaned = self.orphaned.lock().unwrap();
+            let orphans = expired_orphans(&mut orphaned, certs.keys(), &hosts, Instant::now(), self.settings.orphan_grace);
+            self.metrics.orphaned_certificate_hosts.store(orphaned.len() as i64, Ordering::Relaxed);
+            orphans
+        };
+
+        let reason = self.settings.revoke_orphans.then_some(RevocationReason::CessationOfOperation);
+        for host in orphans {
+            match self.remove(&host, reason, self.settings.orphan_action).await {
+                Ok(()) => {
+                    self.orphaned.lock().unwrap().remove(&host);
+                    let removed = match self.settings.orphan_action {
+                        OrphanAction::Archive => "archived",
+                        OrphanAction::Delete => "deleted",
+                    };
+                    info!("cert_manager: {} the certificates for {}, which no longer needs them", removed, host);
+                }
+                Err(e) => warn!("cert_manager: could not remove the certificates for {}, retrying: {}", host, e),
+            }
+        }
+    }
+
+    /// Revokes the host's certificates if there's a `reason`, then archives or deletes them. Each one stops being
+    /// served once it's done, so a failure only leaves the rest to retry.
+    async fn remove(&self, host: &Host, reason: Option<RevocationReason>, action: OrphanAction) -> Result<(), IngressLoadBalancerError> {
+        let certs = match self.state.certs.read().await.get(host) {
+            Some(certs) => certs.clone(),
+            None => return Ok(()),
+        };
+        let issuer = self.issuer.name();
+
+        for cert in certs.iter() {
+            if let Some(reason) = reason {
+                self.issuer.revoke(cert, reason).await?;
+                self.metrics.certificates_revoked_total.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if action == OrphanAction::Archive {
+                let (cert_pem, key_pem) = cert_key_to_pem(cert)?;
+                self.storage.save_tls(&TlsEntry {
+                    host: host.clone(),
+                    issuer: format!("{}-archived", issuer),
+                    key_type: cert.key_type,
+                    cert_pem,
+                    key_pem,
+                }).await?;
+            }
+            self.storage.delete_tls(&issuer, host, cert.key_type).await?;
+
+            let counter = match action {
+                OrphanAction::Archive => &self.metrics.certificates_archived_total,
+                OrphanAction::Delete => &self.metrics.certificates_deleted_total,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            remove_cert(&self.state, host, cert.key_type).await;
+        }
+
+        Ok(())
+    }
+
+    /// Certificates which should be ordered now, marked as being ordered.
+    async fn due_certificates(&self) -> Vec<(Host, KeyType)> {
+        let hosts = self.hosts().await;
+        let certs = self.state.certs.read().await;
+        let renewals = self.state.renewals.read().await;
+
+        let now = SystemTime::now();
+        let limits = self.issuer.limits();
+        let mut log = self.log.lock().unwrap();
+        log.schedule.retain(|(host, key_type), _| hosts.contains(host) && self.settings.key_types.contains(key_type));
+        log.prune(&limits, now);
+        let mut renewed = self.renewed.lock().unwrap();
+        renewed.retain(|(host, _)| renewals.contains(host));
+
+        let mut due = Vec::new();
+        for host in hosts {
+            for &key_type in &self.settings.key_types {
+                let cert = certs.get(&host).and_then(|certs| certs.get(key_type));
+                let info = cert.map(|cert| cert.info());
+                let valid_days_left = info.as_ref().map(|info| info.as_ref().map(|info| info.valid_days_left));
+                let renew_before_days = match info.flatten() {
+                    Some(info) => renew_before_days(self.settings.renew_before_days, info.lifetime_days),
+                    None => self.settings.renew_before_days,
+                };
+                let key = (host.clone(), key_type);
+
+                let requested = renewals.contains(&host) && !renewed.contains(&key);
+                let reason = match needs_certificate(valid_days_left, requested, renew_before_days) {
+                    Some(reason) => reason,
+                    None => {
+                        log.schedule.remove(&key);
+                        continue;
+                    }
+                };
+
+                let limited_until = log.limited_until(&host, &limits, now);
+                let entry = log.schedule.entry(key.clone()).or_default();
+                if entry.ordering {
+                    continue;
+                }
+
+                let next_attempt = match (reason, entry.next_attempt) {
+                    // failed and rate limited orders wait whatever the reason
+                    (_, Some(next_attempt)) if entry.failures > 0 || entry.rate_limited => next_attempt,
+                    (Reason::Expiring, Some(next_attempt)) => next_attempt,
+                    (Reason::Expiring, None) => *entry.next_attempt.insert(now + random_up_to(RENEWAL_JITTER)),
+                    (Reason::Missing | Reason::Requested, _) => now,
+                };
+
+                if next_attempt > now {
+                    continue;
+                }
+
+                if let Some((until, limit)) = limited_until {
+                    entry.next_attempt = Some(until);
+                    entry.rate_limited = true;
+                    warn!("cert_manager: deferring {} certificate for {} for {:?}, the issuer's limit of {} is reached",
+                        key_type, host, until.duration_since(now).unwrap_or_default(), limit);
+                } else {
+                    entry.ordering = true;
+                    info!("cert_manager: ordering {} certificate for {} ({:?})", key_type, host, reason);
+                    due.push(key);
+                }
+            }
+        }
+
+        due
+    }
+
+    async fn order(self: Arc<Self>, host: Host, key_type: KeyType) {
+        let result = {
+            let _permit = self.orders.acquire().await;
+            self.issuer.issue(&host, key_type).await
+        };
+
+        let mut renewed = false;
+        let result = match result {
+            Ok(cert) => {
+                self.log.lock().unwrap().issued.entry(registered_domain(&host)).or_default().push(SystemTime::now());
+
+                // served only once stored, so followers get it too and it isn't ordered again after a restart
+                self.store(&host, &cert).await;
+                {
+                    let mut certs = self.state.certs.write().await;
+                    let host_certs = certs.entry(host.clone()).or_default();
+                    renewed = host_certs.get(key_type).is_some();
+                    host_certs.insert(cert);
+                }
+                self.finish_renewal(&host, key_type).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+
+        let (event, failure) = {
+            let now = SystemTime::now();
+            let mut log = self.log.lock().unwrap();
+            let entry = log.schedule.entry((host.clone(), key_type)).or_default();
+            entry.ordering = false;
+
+            let outcome = match result {
+                Ok(()) => {
+                    info!("cert_manager: issued {} certificate for {}", key_type, host);
+                    *entry = HostSchedule::default();
+                    let reason = if renewed { "CertificateRenewed" } else { "CertificateIssued" };
+                    ((EventType::Normal, reason, format!("issued {} certificate for {}", key_type, host)), None)
+                }
+                Err(e) => {
+                    entry.failures += 1;
+                    entry.last_error = Some(e.to_string());
+                    let backoff = retry_delay(entry.failures, self.settings.max_retry);
+                    // the CA says how long to wait, and orders it refused don't count as failed validations
+                    entry.rate_limited = matches!(e, IngressLoadBalancerError::RateLimited(..));
+                    let retry = match e {
+                        IngressLoadBalancerError::RateLimited(Some(retry_after), _) => retry_after.max(backoff),
+                        _ => backoff,
+                    };
+                    entry.next_attempt = Some(now + retry);
+                    if !entry.rate_limited {
+                        log.failed.entry(host.clone()).or_default().push(now);
+                    }
+
+                    let note = format!("could not issue {} certificate for {}, retrying in {:?}: {}", key_type, host, retry, e);
+                    warn!("cert_manager: {}", note);
+                    let reason = failure_reason(&e);
+                    ((EventType::Warning, "CertificateFailed", note), Some(reason))
+                }
+            };
+
+            let backing_off = log.schedule.values().filter(|entry| entry.failures > 0 || entry.rate_limited).count();
+            self.metrics.certificates_backing_off.store(backing_off as i64, Ordering::Relaxed);
+            outcome
+        };
+
+        if let Some(reason) = failure {
+            self.metrics.record_certificate_failure(&reason).await;
+        }
+        if let Some(events) = &self.events {
+            let (type_, reason, note) = event;
+            events.publish_for_host(&host, type_, reason, "Issue", &note).await;
+        }
+
+        self.save_log().await;
+    }
+
+    fn log_name(&self) -> String {
+        format!("{}-issuance", self.issuer.name())
+    }
+
+    /// Loads the issuance log the previous leader saved, so its failures and issued certificates count here too.
+    async fn load_log(&self) {
+        let stored = match self.storage.load(&self.log_name()).await {
+            Ok(Some(data)) => match data.get(ISSUANCE_LOG_KEY).map(|log| serde_json::from_slice::<StoredIssuanceLog>(log)) {
+                Some(Ok(stored)) => IssuanceLog::from_stored(stored),
+                Some(Err(e)) => return warn!("cert_manager: ignoring unreadable issuance log {}: {}", self.log_name(), e),
+                None => return,
+            },
+            Ok(None) => return,
+            Err(e) => return warn!("cert_manager: could not load issuance log {}: {}", self.log_name(), e),
+        };
+
+        let mut log = self.log.lock().unwrap();
+        for (key, entry) in stored.schedule {
+            if !log.schedule.get(&key).is_some_and(|current| current.ordering) {
+                log.schedule.insert(key, entry);
+            }
+        }
+        log.issued = stored.issued;
+        log.failed = stored.failed;
+    }
+
+    async fn save_log(&self) {
+        let _saving = self.saving.lock().await;
+
+        let stored = self.log.lock().unwrap().to_stored();
+        let log = match serde_json::to_vec(&stored) {
+            Ok(log) => log,
+            Err(e) => return warn!("cert_manager: could not serialize the issuance log: {}", e),
+        };
+
+        let data = [(ISSUANCE_LOG_KEY.to_string(), log)].into_iter().collect();
+        if let Err(e) = self.storage.save(&self.log_name(), data).await {
+            warn!("cert_manager: could not save the issuance log: {}", e);
+        }
+    }
+
+    /// Stores the certificate, where the other replicas load it from.
+    async fn persist(&self, host: &str, cert: &CertKey) -> Result<(), IngressLoadBalancerError> {
+        let (cert_pem, key_pem) = cert_key_to_pem(cert)?;
+
+        self.storage.save_tls(&TlsEntry {
+            host: host.to_string(),
+            issuer: self.issuer.name(),
+            key_type: cert.key_type,
+            cert_pem,
+            key_pem,
+        }).await
+    }
+
+    /// Saves an issued certificate, retrying with backoff until it is stored. Ordering it again instead would count
+    /// against the issuer's limits.
+    async fn store(&self, host: &Host, cert: &CertKey) {
+        let mut failures = 0;
+        while let Err(e) = self.persist(host, cert).await {
+            failures += 1;
+            let retry = retry_delay(failures, self.settings.max_retry);
+            let note = format!("could not store the issued {} certificate for {}, retrying in {:?}: {}", cert.key_type, host, retry, e);
+            warn!("cert_manager: {}", note);
+
+            self.metrics.record_certificate_failure(&failure_reason(&e)).await;
+            if let (1, Some(events)) = (failures, &self.events) {
+                events.publish_for_host(host, EventType::Warning, "CertificateNotStored", "Store", &note).await;
+            }
+
+            tokio::time::sleep(retry).await;
+        }
+    }
+
+    /// A requested renewal is done once every key type has been issued again since the request.
+    async fn finish_renewal(&self, host: &Host, key_type: KeyType) {
+        let mut renewals = self.state.renewals.write().await;
+        if !renewals.contains(host) {
+            return;
+        }
+
+        let mut renewed = self.renewed.lock().unwrap();
+        renewed.insert((host.clone(), key_type));
+
+        if self.settings.key_types.iter().all(|&key_type| renewed.contains(&(host.clone(), key_type))) {
+            renewals.remove(host);
+            renewed.retain(|(renewed_host, _)| renewed_host != host);
+        }
+    }
+}
+
+/// Why a host with a certificate `valid_days_left` days from expiry needs a new one, if it does. `None` is a host
+/// without a certificate, `Some(None)` one whose certificate can't be read.
+fn needs_certificate(valid_days_left: Option<Option<i32>>, requested: bool, renew_before_days: u32) -> Option<Reason> {
+    match valid_days_left {
+        None | Some(None) => Some(Reason::Missing),
+        Some(_) if requested => Some(Reason::Requested),
+        Some(Some(days)) if days < renew_before_days as i32 => Some(Reason::Expiring),
+        Some(Some(_)) => None,
+    }
+}
+
+/// Certificates living no longer than `renew_before_days` are renewed with a third of their lifetime left instead.
+fn renew_before_days(renew_before_days: u32, lifetime_days: i32) -> u32 {
+    match lifetime_days > renew_before_days as i32 {
+        true => renew_before_days,
+        false => (lifetime_days.max(1) as u32).div_ceil(3),
+    }
+}
+
+/// Hosts in `orphaned` whose grace period is over. Hosts with `certified` certificates which aren't in `hosts` are
+/// added, hosts which are again or no longer have certificates removed.
+fn expired_orphans<'a>(
+    orphaned: &mut HashMap<Host, Instant>,
+    certified: impl Iterator<Item = &'a Host>,
+    hosts: &HashSet<Host>,
+    now: Instant,
+    grace: Duration,
+) -> Vec<Host> {
+    let certified: HashSet<&Host> = certified.filter(|host| !hosts.contains(*host)).collect();
+    orphaned.retain(|host, _| certified.contains(host));
+    for host in certified {
+        orphaned.entry(host.clone()).or_insert(now);
+    }
+
+    orphaned.iter().filter(|(_, since)| now.duration_since(**since) >= grace).map(|(host, _)| host.clone()).collect()
+}
+
+async fn remove_cert(state: &CertificateState, host: &Host, key_type: KeyType) {
+    let mut certs = state.certs.write().await;
+    if let Some(host_certs) = certs.get_mut(host) {
+        host_certs.remove(key_type);
+        if host_certs.is_empty() {
+            certs.remove(host);
+        }
+    }
+}
+
+/// Loads certificates from the issuer into the certificate state whenever the stored ones change, which is how
+/// replicas which aren't the leader get the certificates it issues, and stop serving the ones it removes.
+pub async fn follow_certificates(storage: CertStorage, issuer: String, state: Arc<CertificateState>) {
+    let mut changes = storage.watch_tls(&issuer);
+
+    while let Some(change) = changes.next().await {
+        match change {
+            TlsChange::Saved(entry) => match cert_key_from_pem(&entry.cert_pem, &entry.key_pem) {
+                Ok(cert) => {
+                    info!("cert_manager: loaded {} certificate for {} from storage", cert.key_type, entry.host);
+                    state.certs.write().await.entry(entry.host).or_default().insert(cert);
+                }
+                Err(e) => warn!("cert_manager: ignoring unreadable stored certificate for {}: {}", entry.host, e),
+            },
+            TlsChange::Deleted { host, key_type } => {
+                info!("cert_manager: {} certificate for {} was removed from storage", key_type, host);
+                remove_cert(&state, &host, key_type).await;
+            }
+        }
+    }
+}
+
+/// Labels a failed order in the metrics.
+fn failure_reason(e: &IngressLoadBalancerError) -> String {
+    match e {
+        IngressLoadBalancerError::General(code, _) => code.to_string(),
+        IngressLoadBalancerError::RateLimited(..) => "RateLimited".to_string(),
+        _ => "Other".to_string(),
+    }
+}
+
+/// Exponential backoff after `failures` failed orders, with up to a tenth of random jitter on top.
+fn retry_delay(failures: u32, max: Duration) -> Duration {
+    let backoff = INITIAL_RETRY
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(max);
+
+    backoff + random_up_to(backoff / 10)
+}
+
+fn random_up_to(max: Duration) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max.as_millis() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_storage::MemoryStore;
+    use crate::local_ca::{LocalCa, LocalCaSettings};
+
+    #[test]
+    fn orders_missing_requested_and_expiring_certificates() {
+        assert_eq!(needs_certificate(None, false, 30), Some(Reason::Missing));
+        assert_eq!(needs_certificate(Some(None), false, 30), Some(Reason::Missing));
+        assert_eq!(needs_certificate(Some(Some(80)), true, 30), Some(Reason::Requested));
+        assert_eq!(needs_certificate(Some(Some(29)), false, 30), Some(Reason::Expiring));
+        assert_eq!(needs_certificate(Some(Some(30)), false, 30), None);
+    }
+
+    #[test]
+    fn renews_short_lived_certificates_with_a_third_left() {
+        assert_eq!(renew_before_days(30, 90), 30);
+        assert_eq!(renew_before_days(30, 7), 3);
+        assert_eq!(renew_before_days(30, 1), 1);
+    }
+
+    #[test]
+    fn removes_orphans_after_the_grace_period() {
+        let grace = Duration::from_secs(60);
+        let start = Instant::now();
+        let certified = ["a.example.com".to_string(), "b.example.com".to_string()];
+        let mut orphaned = HashMap::new();
+
+        let hosts: HashSet<Host> = ["a.example.com".to_string()].into_iter().collect();
+        assert!(expired_orphans(&mut orphaned, certified.iter(), &hosts, start, grace).is_empty());
+        assert_eq!(orphaned.keys().collect::<Vec<_>>(), vec!["b.example.com"]);
+        assert_eq!(expired_orphans(&mut orphaned, certified.iter(), &hosts, start + grace, grace), vec!["b.example.com"]);
+
+        // routed again before the grace period is over, the clock starts over next time
+        let hosts: HashSet<Host> = certified.iter().cloned().collect();
+        assert!(expired_orphans(&mut orphaned, certified.iter(), &hosts, start + grace, grace).is_empty());
+        assert!(orphaned.is_empty());
+    }
+
+    #[test]
+    fn counts_certificates_by_registered_domain() {
+        assert_eq!(registered_domain("www.example.com"), "example.com");
+        assert_eq!(registered_domain("example.com."), "example.com");
+        assert_eq!(registered_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn waits_until_the_oldest_events_leave_the_window() {
+        let now = SystemTime::now();
+        let window = Duration::from_secs(3600);
+        let times = [now - Duration::from_secs(3000), now - Duration::from_secs(600), now - Duration::from_secs(4000)];
+
+        assert_eq!(until_below(&times, 0, 3, window, now), None);
+        assert_eq!(until_below(&times, 1, 3, window, now), Some(times[0] + window));
+        assert_eq!(until_below(&times, 2, 3, window, now), Some(times[1] + window));
+        assert_eq!(until_below(&[], 1, 1, window, now), Some(now + window));
+    }
+
+    #[test]
+    fn defers_orders_over_the_limits() {
+        let now = SystemTime::now();
+        let limits = RateLimits {
+            certificates_per_domain: Some((2, Duration::from_secs(7 * 24 * 3600))),
+            failures_per_host: Some((1, Duration::from_secs(3600))),
+        };
+        let mut log = IssuanceLog::default();
+        log.issued.insert("example.com".to_string(), vec![now - Duration::from_secs(60)]);
+        assert_eq!(log.limited_until("a.example.com", &limits, now), None);
+
+        // an order in progress under the same domain takes the last certificate
+        log.schedule.insert(("b.example.com".to_string(), KeyType::Rsa), HostSchedule { ordering: true, ..HostSchedule::default() });
+        let (until, _) = log.limited_until("a.example.com", &limits, now).unwrap();
+        assert_eq!(until, now - Duration::from_secs(60) + Duration::from_secs(7 * 24 * 3600));
+        assert_eq!(log.limited_until("a.example.org", &limits, now), None);
+
+        log.failed.insert("a.example.org".to_string(), vec![now - Duration::from_secs(60)]);
+        assert_eq!(log.limited_until("a.example.org", &limits, now), Some((now + Duration::from_secs(3540), "failed orders per host")));
+
+        log.prune(&limits, now + Duration::from_secs(3600));
+        assert!(log.failed.is_empty());
+        assert_eq!(log.issued.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_next_leader_loads_the_issuance_log() {
+        let storage: CertStorage = Arc::new(MemoryStore::new());
+        let issuer = Arc::new(LocalCa::new(LocalCaSettings { secret: "iter-local-ca".to_string(), cert_days: 7 }, storage.clone()));
+        let settings = CertManagerSettings {
+            renew_before_days: 30,
+            max_retry: Duration::from_secs(3600),
+            key_types: vec![KeyType::Rsa],
+            orphan_grace: Duration::ZERO,
+            orphan_action: OrphanAction::Archive,
+            revoke_orphans: false,
+        };
+        let new_manager = || CertManager::new(
+            Arc::new(RoutingTable::new()),
+            Arc::new(CertificateState::new()),
+            storage.clone(),
+            issuer.clone(),
+            settings.clone(),
+            Arc::new(Metrics::new()),
+            None,
+        );
+
+        let now = SystemTime::now();
+        let leader = new_manager().await;
+        {
+            let mut log = leader.log.lock().unwrap();
+            log.failed.insert("a.example.com".to_string(), vec![now]);
+            log.schedule.insert(("a.example.com".to_string(), KeyType::Rsa), HostSchedule {
+                next_attempt: Some(now + Duration::from_secs(60)),
+                failures: 1,
+                last_error: Some("Error: ChallengeFailed: invalid response".to_string()),
+                ..HostSchedule::default()
+            });
+        }
+        leader.save_log().await;
+
+        let next_leader = new_manager().await;
+        next_leader.load_log().await;
+        let log = next_leader.log.lock().unwrap();
+        assert_eq!(log.failed["a.example.com"], vec![now]);
+        let entry = &log.schedule[&("a.example.com".to_string(), KeyType::Rsa)];
+        assert_eq!(entry.failures, 1);
+        assert_eq!(entry.next_attempt, Some(now + Duration::from_secs(60)));
+        assert!(!entry.ordering);
+    }
+
+    #[tokio::test]
+    async fn archives_orphans_and_deletes_revoked_certificates() {
+        let storage: CertStorage = Arc::new(MemoryStore::new());
+        let issuer = Arc::new(LocalCa::new(LocalCaSettings { secret: "iter-local-ca".to_string(), cert_days: 7 }, storage.clone()));
+        let state = Arc::new(CertificateState::new());
+        let metrics = Arc::new(Metrics::new());
+        let settings = CertManagerSettings {
+            renew_before_days: 30,
+            max_retry: Duration::from_secs(3600),
+            key_types: vec![KeyType::EcdsaP256],
+            orphan_grace: Duration::ZERO,
+            orphan_action: OrphanAction::Archive,
+            revoke_orphans: false,
+        };
+        let manager = CertManager::new(Arc::new(RoutingTable::new()), state.clone(), storage.clone(), issuer.clone(), settings, metrics.clone(), None).await;
+
+        for host in ["old.example.com", "leaked.example.com"] {
+            let cert = issuer.issue(host, KeyType::EcdsaP256).await.unwrap();
+            manager.persist(host, &cert).await.unwrap();
+            state.certs.write().await.insert(host.to_string(), cert.into());
+        }
+
+        state.request_revocation("leaked.example.com").await;
+        manager.remove_certificates().await;
+
+        assert!(state.certs.read().await.is_empty());
+        assert!(state.revocations.read().await.is_empty());
+        assert!(storage.list_tls("local-ca").await.unwrap().is_empty());
+        let archived = storage.list_tls("local-ca-archived").await.unwrap();
+        assert_eq!(archived.iter().map(|entry| entry.host.as_str()).collect::<Vec<_>>(), vec!["old.example.com"]);
+        assert_eq!(metrics.certificates_revoked_total.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.certificates_archived_total.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.certificates_deleted_total.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn leaves_hosts_with_certificate_files_alone() {
+        let storage: CertStorage = Arc::new(MemoryStore::new());
+        let issuer = Arc::new(LocalCa::new(LocalCaSettings { secret: "iter-local-ca".to_string(), cert_days: 7 }, storage.clone()));
+        let settings = CertManagerSettings {
+            renew_before_days: 30,
+            max_retry: Duration::from_secs(3600),
+            key_types: vec![KeyType::Rsa, KeyType::EcdsaP256],
+            orphan_grace: Duration::ZERO,
+            orphan_action: OrphanAction::Archive,
+            revoke_orphans: false,
+        };
+        let routing_table = Arc::new(RoutingTable::new());
+        let config = crate::file_config::FileConfig::parse(std::path::Path::new("ingress.yaml"), r#"
+hosts:
+  - host: static.example.com
+    paths: [{ upstream: "a:80" }]
+    tls: { certificate: static.crt, key: static.key }
+  - host: issued.example.com
+    paths: [{ upstream: "b:80" }]
+"#).unwrap();
+        routing_table.replace(config.backends().unwrap()).await;
+        let manager = CertManager::new(routing_table, Arc::new(CertificateState::new()), storage, issuer, settings, Arc::new(Metrics::new()), None).await;
+
+        let mut due = manager.due_certificates().await;
+        due.sort_by_key(|(_, key_type)| key_type.to_string());
+        assert_eq!(due, vec![("issued.example.com".to_string(), KeyType::EcdsaP256), ("issued.example.com".to_string(), KeyType::Rsa)]);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_limit() {
+        let max = Duration::from_secs(3600);
+        let within = |delay: Duration, expected: u64| delay >= Duration::from_secs(expected) && delay <= Duration::from_secs(expected) * 11 / 10;
+
+        assert!(within(retry_delay(1, max), 60));
+        assert!(within(retry_delay(2, max), 120));
+        assert!(within(retry_delay(4, max), 480));
+        assert!(within(retry_delay(10, max), 3600));
+        assert!(within(retry_delay(u32::MAX, max), 3600));
+    }
+}
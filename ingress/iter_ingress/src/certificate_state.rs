@@ -1,29 +1,215 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc};
 
 use hyper::{Response, Body};
+use iter_letsencrypt::account::ServesChallenge;
 use iter_letsencrypt::challenge::Http01Challenge;
-use rustls::{sign::CertifiedKey, ServerConfig};
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey};
+use openssl::x509::X509;
+use rustls::{sign::CertifiedKey, ServerConfig, SignatureScheme};
 use serde::{Serialize, Deserialize};
 use iter_tls_acceptor::tls_acceptor::ResolvesServerConf;
 use tokio::sync::RwLock;
+use tracing::{debug, info};
+
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::metrics::{Metrics, TlsFallback};
+use crate::on_demand::OnDemand;
+use crate::tls_policy::HostTlsPolicy;
 
 
 pub type Host = String;
 pub type Path = String;
 
 pub struct CertificateState {
-    pub certs: RwLock<HashMap<String, CertKey>>,
+    pub certs: RwLock<HashMap<String, HostCerts>>,
     pub challenges: RwLock<HashMap<(Host, Path), Http01Challenge>>,
+    /// hosts whose certificate should be issued again on the next check, even though one exists
+    pub renewals: RwLock<HashSet<Host>>,
+    /// hosts whose certificates should be revoked because their keys are believed compromised, and then replaced
+    pub revocations: RwLock<HashSet<Host>>,
+    /// certificates from the secrets named in ingresses' `spec.tls`, served instead of `certs`
+    pub user_certs: RwLock<HashMap<Host, HostCerts>>,
+    /// served to clients without sni or for hosts without a certificate, see [`crate::fallback_cert`]
+    pub fallback: RwLock<Option<CertKey>>,
+    /// handshake settings of hosts which don't use the defaults, see [`crate::tls_policy`]
+    pub tls_policies: RwLock<HashMap<Host, Arc<HostTlsPolicy>>>,
+    /// issues certificates for hosts without one while their handshake waits, see [`crate::on_demand`]
+    pub on_demand: RwLock<Option<Arc<OnDemand>>>,
+    metrics: Option<Arc<Metrics>>,
 }
 #[derive(Clone)]
 pub struct CertKey {
     // DER encoded
     pub certs: Vec<Vec<u8>>,
+    /// PKCS#8
     pub private_key: Vec<u8>,
-    pub certified_key: Arc<CertifiedKey>,
+    pub key_type: KeyType,
+    pub certified_key: Arc<StapledKey>,
     pub server_config: Arc<ServerConfig>,
 }
 
+/// The [`CertifiedKey`] a [`CertKey`]'s server config serves, replaced by one with an OCSP response stapled once
+/// [`crate::ocsp`] has fetched one. Clones of a `CertKey` share it, so stapling one staples them all.
+pub struct StapledKey(std::sync::RwLock<Arc<CertifiedKey>>);
+
+impl rustls::server::ResolvesServerCert for StapledKey {
+    fn resolve(&self, _: rustls::server::ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// The kind of key a certificate is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyType {
+    Rsa,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl KeyType {
+    pub fn is_ecdsa(&self) -> bool {
+        *self != KeyType::Rsa
+    }
+
+    /// Whether a client offering `schemes` can verify signatures made with this kind of key.
+    fn supported_by(&self, schemes: &[SignatureScheme]) -> bool {
+        match self {
+            KeyType::Rsa => schemes.iter().any(|scheme| matches!(scheme,
+                SignatureScheme::RSA_PKCS1_SHA256 | SignatureScheme::RSA_PKCS1_SHA384 | SignatureScheme::RSA_PKCS1_SHA512 |
+                SignatureScheme::RSA_PSS_SHA256 | SignatureScheme::RSA_PSS_SHA384 | SignatureScheme::RSA_PSS_SHA512)),
+            KeyType::EcdsaP256 => schemes.contains(&SignatureScheme::ECDSA_NISTP256_SHA256),
+            KeyType::EcdsaP384 => schemes.contains(&SignatureScheme::ECDSA_NISTP384_SHA384),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyType::Rsa => write!(f, "rsa"),
+            KeyType::EcdsaP256 => write!(f, "ecdsa-p256"),
+            KeyType::EcdsaP384 => write!(f, "ecdsa-p384"),
+        }
+    }
+}
+
+impl std::str::FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rsa" => Ok(KeyType::Rsa),
+            "ecdsa-p256" => Ok(KeyType::EcdsaP256),
+            "ecdsa-p384" => Ok(KeyType::EcdsaP384),
+            other => Err(format!("expected rsa, ecdsa-p256 or ecdsa-p384, got {:?}", other)),
+        }
+    }
+}
+
+/// A host's certificates, at most one RSA and one ECDSA certificate. Clients get the ECDSA one when they support
+/// it, for a faster handshake, and the RSA one otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct HostCerts {
+    pub rsa: Option<CertKey>,
+    pub ecdsa: Option<CertKey>,
+}
+
+impl HostCerts {
+    pub fn insert(&mut self, cert: CertKey) {
+        match cert.key_type.is_ecdsa() {
+            true => self.ecdsa = Some(cert),
+            false => self.rsa = Some(cert),
+        }
+    }
+
+    pub fn remove(&mut self, key_type: KeyType) -> Option<CertKey> {
+        match key_type.is_ecdsa() {
+            true => self.ecdsa.take_if(|cert| cert.key_type == key_type),
+            false => self.rsa.take(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rsa.is_none() && self.ecdsa.is_none()
+    }
+
+    pub fn get(&self, key_type: KeyType) -> Option<&CertKey> {
+        match key_type.is_ecdsa() {
+            true => self.ecdsa.as_ref().filter(|cert| cert.key_type == key_type),
+            false => self.rsa.as_ref(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CertKey> {
+        self.ecdsa.iter().chain(self.rsa.iter())
+    }
+
+    /// The certificate to serve a client offering the signature `schemes`.
+    pub fn select(&self, schemes: &[SignatureScheme]) -> Option<&CertKey> {
+        self.iter()
+            .find(|cert| cert.key_type.supported_by(schemes))
+            // a client which can't verify any of them fails the handshake either way
+            .or_else(|| self.iter().next())
+    }
+}
+
+impl From<CertKey> for HostCerts {
+    fn from(cert: CertKey) -> HostCerts {
+        let mut host_certs = HostCerts::default();
+        host_certs.insert(cert);
+        host_certs
+    }
+}
+
+/// Details of the leaf certificate of a [`CertKey`].
+#[derive(Debug, Serialize)]
+pub struct CertInfo {
+    pub subject_alt_names: Vec<String>,
+    pub not_after: String,
+    pub valid_days_left: i32,
+    /// days between not before and not after
+    pub lifetime_days: i32,
+}
+
+impl CertKey {
+    pub fn info(&self) -> Option<CertInfo> {
+        let leaf = X509::from_der(self.certs.first()?).ok()?;
+
+        let subject_alt_names = leaf
+            .subject_alt_names()
+            .map(|names| names.iter().filter_map(|name| name.dnsname().map(|n| n.to_string())).collect())
+            .unwrap_or_default();
+
+        let valid_days_left = Asn1Time::days_from_now(0)
+            .ok()
+            .and_then(|now| now.diff(leaf.not_after()).ok())
+            .map(|diff| diff.days)?;
+        let lifetime_days = leaf.not_before().diff(leaf.not_after()).ok()?.days;
+
+        Some(CertInfo {
+            subject_alt_names,
+            not_after: leaf.not_after().to_string(),
+            valid_days_left,
+            lifetime_days,
+        })
+    }
+
+    /// The stapled OCSP response, if there is one.
+    pub fn ocsp(&self) -> Option<Vec<u8>> {
+        self.certified_key.0.read().unwrap().ocsp.clone()
+    }
+
+    /// Staples `ocsp` to every handshake from now on, or stops stapling with `None`.
+    pub fn staple(&self, ocsp: Option<Vec<u8>>) {
+        let mut certified_key = self.certified_key.0.write().unwrap();
+        let mut stapled = CertifiedKey::clone(&certified_key);
+        stapled.ocsp = ocsp;
+        *certified_key = Arc::new(stapled);
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CertData {
     pub private_key: Vec<u8>,
@@ -36,12 +222,50 @@ impl CertificateState {
         CertificateState {
             certs: RwLock::new(HashMap::new()),
             challenges: RwLock::new(HashMap::new()),
+            renewals: RwLock::new(HashSet::new()),
+            revocations: RwLock::new(HashSet::new()),
+            user_certs: RwLock::new(HashMap::new()),
+            fallback: RwLock::new(None),
+            tls_policies: RwLock::new(HashMap::new()),
+            on_demand: RwLock::new(None),
+            metrics: None,
         }
     }
 
+    /// Counts the handshakes completed with the fallback certificate in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> CertificateState {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    async fn fallback_server_config(&self, reason: TlsFallback) -> Option<Arc<ServerConfig>> {
+        let config = self.fallback.read().await.as_ref()?.server_config.clone();
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record_tls_fallback(reason);
+        }
+        Some(config)
+    }
+
+    pub async fn request_renewal(&self, host: &str) {
+        self.renewals.write().await.insert(host.to_string());
+        info!("renewal requested for: {}", host);
+    }
+
+    pub async fn request_revocation(&self, host: &str) {
+        self.revocations.write().await.insert(host.to_string());
+        info!("revocation requested for: {}", host);
+    }
+
     pub async fn apply_challenge(&self, challenge: Http01Challenge) {
         self.challenges.write().await.insert((challenge.domain.clone(), challenge.path.clone()), challenge.clone());
-        println!("applied challenge on: {}{}", challenge.domain, challenge.path);
+        info!("applied challenge on: {}{}", challenge.domain, challenge.path);
+    }
+
+    pub async fn remove_challenge(&self, host: &str, path: &str) {
+        if self.challenges.write().await.remove(&(host.to_string(), path.to_string())).is_some() {
+            info!("removed challenge on: {}{}", host, path);
+        }
     }
 
     #[inline]
@@ -52,7 +276,7 @@ impl CertificateState {
             .await
             .get(&(host.to_string(), path.to_string()))
         {
-            println!("serving incoming challenge on: {}{}", host, path);
+            debug!("serving incoming challenge on: {}{}", host, path);
             return Some(Response::new(Body::from(challenge.contents.clone())));
         }
         None
@@ -60,6 +284,14 @@ impl CertificateState {
 }
 
 
+/// Serves challenges from this replica only, for an ingress running outside kubernetes.
+#[async_trait::async_trait]
+impl ServesChallenge for CertificateState {
+    async fn prepare_challenge(self: &Arc<Self>, challenge: Http01Challenge) {
+        self.apply_challenge(challenge).await;
+    }
+}
+
 #[async_trait::async_trait]
 impl ResolvesServerConf for CertificateState {
     async fn resolve_server_config(self: Arc<Self>, hello: &rustls::server::ClientHello) -> Option<Arc<ServerConfig>> {
@@ -68,17 +300,34 @@ impl ResolvesServerConf for CertificateState {
         match name {
             Some(name) => {
                 let state = self.clone();
-                let certs = state.certs.read().await;
-                let cert = certs.get(name);
+                let schemes = hello.signature_schemes();
+                let policy = state.tls_policies.read().await.get(name).cloned();
+                let server_config = |cert: &CertKey| match &policy {
+                    Some(policy) => policy.server_config(cert),
+                    None => Some(cert.server_config.clone()),
+                };
+
+                if let Some(cert) = state.user_certs.read().await.get(name).and_then(|certs| certs.select(schemes)) {
+                    return server_config(cert);
+                }
+
+                if let Some(cert) = state.certs.read().await.get(name).and_then(|certs| certs.select(schemes)) {
+                    return server_config(cert);
+                }
 
-                match cert {
-                    Some(cert) => {
-                        Some(cert.server_config.clone())
+                let on_demand = state.on_demand.read().await.clone();
+                if let Some(on_demand) = on_demand {
+                    if on_demand.certificate(&state, name).await {
+                        let host = name.to_ascii_lowercase();
+                        if let Some(cert) = state.certs.read().await.get(&host).and_then(|certs| certs.select(schemes)) {
+                            return server_config(cert);
+                        }
                     }
-                    None => None,
                 }
+
+                self.fallback_server_config(TlsFallback::UnknownHost).await
             }
-            None => None,
+            None => self.fallback_server_config(TlsFallback::NoSni).await,
         }
     }
 }
@@ -116,10 +365,10 @@ impl<'de> Deserialize<'de> for CertKey {
 
         let cert_key_minimal = CertKeyMinimal::deserialize(deserializer)?;
 
-        Ok(cert_key_from(
+        cert_key_from(
             cert_key_minimal.certs,
             cert_key_minimal.private_key,
-        ))
+        ).map_err(serde::de::Error::custom)
     }
 }
 
@@ -134,29 +383,103 @@ impl std::fmt::Debug for CertKey {
     }
 }
 
-pub fn cert_key_from(certs: Vec<Vec<u8>>, private_key: Vec<u8>) -> CertKey {
-    let key = rustls::sign::RsaSigningKey::new(&rustls::PrivateKey(private_key.clone())).unwrap();
-
-    CertKey {
-        certified_key: Arc::new(CertifiedKey::new(
-            certs
-                .iter()
-                .map(|cert| rustls::Certificate(cert.clone()))
-                .collect(),
-            Arc::new(key),
-        )),
-        server_config: Arc::new(rustls::ServerConfig::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(certs.clone().iter()
-            .map(|cert| rustls::Certificate(cert.clone()))
-            .collect(), rustls::PrivateKey(private_key.clone()))
-            .unwrap()),
+/// Builds a [`CertKey`] from DER encoded certificates and an RSA or ECDSA P-256/P-384 private key, in PKCS#1,
+/// SEC1 or PKCS#8 DER.
+pub fn cert_key_from(certs: Vec<Vec<u8>>, private_key: Vec<u8>) -> Result<CertKey, IngressLoadBalancerError> {
+    let invalid = |msg: String| IngressLoadBalancerError::general(Code::InvalidCertificate, msg);
+
+    let pkey = PKey::private_key_from_der(&private_key).map_err(|e| invalid(format!("could not read private key: {}", e)))?;
+    let key_type = match pkey.id() {
+        Id::RSA => KeyType::Rsa,
+        Id::EC => match pkey.ec_key().ok().and_then(|key| key.group().curve_name()) {
+            Some(Nid::X9_62_PRIME256V1) => KeyType::EcdsaP256,
+            Some(Nid::SECP384R1) => KeyType::EcdsaP384,
+            curve => return Err(invalid(format!("unsupported ECDSA curve {:?}, only P-256 and P-384 are", curve))),
+        },
+        id => return Err(invalid(format!("unsupported private key type {:?}, only RSA and ECDSA are", id))),
+    };
+
+    // rustls only reads ECDSA keys in PKCS#8
+    let private_key = pkey.private_key_to_pkcs8().map_err(|e| invalid(e.to_string()))?;
+
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(private_key.clone()))
+        .map_err(|_| invalid(format!("{} private key is not supported", key_type)))?;
+    let chain: Vec<rustls::Certificate> = certs.iter().map(|cert| rustls::Certificate(cert.clone())).collect();
+    if chain.is_empty() {
+        return Err(invalid("no certificates".to_string()));
+    }
+
+    let certified_key = Arc::new(StapledKey(std::sync::RwLock::new(Arc::new(CertifiedKey::new(chain, key)))));
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certified_key.clone());
+
+    Ok(CertKey {
+        certified_key,
+        server_config: Arc::new(server_config),
         certs,
         private_key,
+        key_type,
+    })
+}
+
+/// Builds a [`CertKey`] from a PEM encoded certificate chain and private key.
+pub fn cert_key_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertKey, IngressLoadBalancerError> {
+    let invalid = |msg: &str| IngressLoadBalancerError::general(Code::InvalidCertificate, msg);
+
+    let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+        .map_err(|_| invalid("could not read certificate pem"))?;
+
+    if certs.is_empty() {
+        return Err(invalid("no certificates found in pem"));
+    }
+
+    let private_key = rustls_pemfile::read_all(&mut &key_pem[..])
+        .map_err(|_| invalid("could not read private key pem"))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid("no private key found in pem"))?;
+
+    cert_key_from(certs, private_key)
+}
+
+/// PEM encodes the certificate chain and private key, the format `kubernetes.io/tls` secrets hold them in.
+pub fn cert_key_to_pem(cert_key: &CertKey) -> Result<(Vec<u8>, Vec<u8>), IngressLoadBalancerError> {
+    let invalid = |e: openssl::error::ErrorStack| IngressLoadBalancerError::general(Code::InvalidCertificate, e.to_string());
+
+    let mut cert_pem = Vec::new();
+    for cert in &cert_key.certs {
+        cert_pem.extend(X509::from_der(cert).and_then(|cert| cert.to_pem()).map_err(invalid)?);
+    }
+
+    let key_pem = PKey::private_key_from_der(&cert_key.private_key)
+        .and_then(|key| key.private_key_to_pem_pkcs8())
+        .map_err(invalid)?;
+
+    Ok((cert_pem, key_pem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fallback_cert::self_signed;
+
+    #[test]
+    fn serves_ecdsa_to_clients_which_support_it() {
+        let mut certs = HostCerts::from(self_signed("example.com", KeyType::Rsa, 30).unwrap());
+        certs.insert(self_signed("example.com", KeyType::EcdsaP256, 30).unwrap());
+
+        let modern = [SignatureScheme::ECDSA_NISTP256_SHA256, SignatureScheme::RSA_PSS_SHA256];
+        let legacy = [SignatureScheme::RSA_PKCS1_SHA256];
+        assert_eq!(certs.select(&modern).unwrap().key_type, KeyType::EcdsaP256);
+        assert_eq!(certs.select(&legacy).unwrap().key_type, KeyType::Rsa);
 
+        // a P-384 only client can't verify either, it gets the ECDSA certificate and fails the handshake
+        assert_eq!(certs.select(&[SignatureScheme::ECDSA_NISTP384_SHA384]).unwrap().key_type, KeyType::EcdsaP256);
+        assert!(certs.get(KeyType::EcdsaP384).is_none());
     }
 }
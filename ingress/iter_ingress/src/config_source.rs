@@ -0,0 +1,30 @@
+//! # Config Sources
+//!
+//! Where the ingress gets its routing configuration from.
+//!
+//! - [`KubernetesConfigSource`](crate::kube_config_tracker::KubernetesConfigSource) watches ingresses in the cluster.
+//! - [`FileConfigSource`](crate::file_config::FileConfigSource) reads a static YAML or TOML file, which lets the
+//!   ingress run on a plain VM or in local integration tests.
+//!
+//! The file source is used when `config_file` is set, otherwise the ingress watches kubernetes.
+
+use std::sync::Arc;
+
+use crate::certificate_state::CertificateState;
+use crate::file_config::FileConfigSource;
+use crate::kube_config_tracker::{KubernetesConfigSource, RoutingTable};
+use crate::settings::Settings;
+
+#[async_trait::async_trait]
+pub trait ConfigSource: Send + Sync {
+    /// Loads the configuration and keeps the routing table and certificate state up to date with it until the
+    /// source ends. Implementations should reload everything when [`RoutingTable::resync_requested`] resolves.
+    async fn watch(self: Arc<Self>, routing_table: Arc<RoutingTable>, cert_state: Arc<CertificateState>) -> Result<(), anyhow::Error>;
+}
+
+pub fn from_settings(settings: &Settings) -> Arc<dyn ConfigSource> {
+    match &settings.config_file {
+        Some(path) => Arc::new(FileConfigSource::new(path.clone())),
+        None => Arc::new(KubernetesConfigSource { cluster_domain: settings.cluster_domain.clone() }),
+    }
+}
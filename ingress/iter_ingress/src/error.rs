@@ -1,26 +1,41 @@
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum IngressLoadBalancerError {
     General(Code, Box<str>),
     Other(Box<str>),
     HyperError(hyper::Error),
+    /// A certificate authority refused to issue because a rate limit was hit, and asked to wait as long
+    RateLimited(Option<Duration>, Box<str>),
 }
 
 #[derive(Debug)]
 pub enum Code {
     NonExistentHost,
-    WebsocketUpgradeError,
     InternalServerError,
     CouldNotGenerateCertificate,
+    InvalidCertificate,
+    CouldNotStoreCertificate,
+    InvalidConfig,
+    BackendUnavailable,
+    ClientCertificateRequired,
+    CouldNotRevokeCertificate,
+    ChallengeFailed,
 }
 
 impl std::fmt::Display for Code {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Code::NonExistentHost => write!(f, "NonExistentHost"),
-            Code::WebsocketUpgradeError => write!(f, "WebsocketUpgradeError"),
             Code::InternalServerError => write!(f, "InternalServerError"),
             Code::CouldNotGenerateCertificate => write!(f, "CouldNotGenerateCertificate"),
+            Code::InvalidCertificate => write!(f, "InvalidCertificate"),
+            Code::CouldNotStoreCertificate => write!(f, "CouldNotStoreCertificate"),
+            Code::InvalidConfig => write!(f, "InvalidConfig"),
+            Code::BackendUnavailable => write!(f, "BackendUnavailable"),
+            Code::ClientCertificateRequired => write!(f, "ClientCertificateRequired"),
+            Code::CouldNotRevokeCertificate => write!(f, "CouldNotRevokeCertificate"),
+            Code::ChallengeFailed => write!(f, "ChallengeFailed"),
         }
     }
 }
@@ -31,6 +46,7 @@ impl std::fmt::Display for IngressLoadBalancerError {
             IngressLoadBalancerError::General(code, msg) => write!(f, "Error: {}: {}", code, msg),
             IngressLoadBalancerError::Other(msg) => write!(f, "Error: {}", msg),
             IngressLoadBalancerError::HyperError(err) => write!(f, "Error: {}", err),
+            IngressLoadBalancerError::RateLimited(_, msg) => write!(f, "Error: RateLimited: {}", msg),
         }
     }
 }
@@ -41,6 +57,7 @@ impl std::error::Error for IngressLoadBalancerError {
             IngressLoadBalancerError::General(_, _) => None,
             IngressLoadBalancerError::Other(_) => None,
             IngressLoadBalancerError::HyperError(err) => Some(err),
+            IngressLoadBalancerError::RateLimited(_, _) => None,
         }
     }
 }
@@ -0,0 +1,98 @@
+//! # Events
+//!
+//! Tells the owners of an ingress what the ingress controller did with it, as Kubernetes Events on the Ingress which
+//! `kubectl describe ingress` lists, e.g. which of its paths aren't routed or why its hosts' certificates can't be
+//! issued.
+//!
+//! Events are informational, an event which can't be published is only logged.
+
+use std::sync::Arc;
+
+use k8s_openapi::api::networking::v1::Ingress;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Api, Client, Resource, ResourceExt};
+use tracing::warn;
+
+use crate::kube_config_tracker::RoutingTable;
+
+/// Shows up as the events' source.
+const CONTROLLER: &str = "iter-ingress";
+/// Event notes are limited to 1kB.
+const MAX_NOTE_LEN: usize = 1024;
+
+pub struct IngressEvents {
+    client: Client,
+    reporter: Reporter,
+    routing_table: Arc<RoutingTable>,
+}
+
+impl IngressEvents {
+    pub fn new(client: Client, pod_name: String, routing_table: Arc<RoutingTable>) -> IngressEvents {
+        IngressEvents {
+            client,
+            reporter: Reporter {
+                controller: CONTROLLER.to_string(),
+                instance: Some(pod_name),
+            },
+            routing_table,
+        }
+    }
+
+    /// Publishes the event on every ingress routing the host.
+    pub async fn publish_for_host(&self, host: &str, type_: EventType, reason: &str, action: &str, note: &str) {
+        for ingress in self.routing_table.sources_of(host).await {
+            self.publish(&ingress, type_, reason, action, note).await;
+        }
+    }
+
+    /// Publishes the event on the ingress named `namespace/name`.
+    pub async fn publish(&self, ingress: &str, type_: EventType, reason: &str, action: &str, note: &str) {
+        let (namespace, name) = match ingress.split_once('/') {
+            Some(key) => key,
+            None => return,
+        };
+
+        // the uid is part of the reference, events without it aren't listed with the ingress
+        let ingresses: Api<Ingress> = Api::namespaced(self.client.clone(), namespace);
+        match ingresses.get(name).await {
+            Ok(ingress) => self.publish_on(&ingress, type_, reason, action, note).await,
+            Err(e) => warn!("events: could not find ingress {}: {}", ingress, e),
+        }
+    }
+
+    pub async fn publish_on(&self, ingress: &Ingress, type_: EventType, reason: &str, action: &str, note: &str) {
+        let recorder = Recorder::new(self.client.clone(), self.reporter.clone(), ingress.object_ref(&()));
+        let event = Event {
+            type_,
+            reason: reason.to_string(),
+            note: Some(truncate(note, MAX_NOTE_LEN).to_string()),
+            action: action.to_string(),
+            secondary: None,
+        };
+
+        if let Err(e) = recorder.publish(event).await {
+            warn!("events: could not publish {} on ingress {}/{}: {}", reason, ingress.namespace().unwrap_or_default(), ingress.name(), e);
+        }
+    }
+}
+
+/// The longest prefix of `value` which fits in `max` bytes.
+fn truncate(value: &str, max: usize) -> &str {
+    let mut end = value.len().min(max);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_notes_on_char_boundaries() {
+        assert_eq!(truncate("short", 1024), "short");
+        assert_eq!(truncate("ééé", 3), "é");
+        assert_eq!(truncate(&"a".repeat(2000), 1024).len(), 1024);
+    }
+}
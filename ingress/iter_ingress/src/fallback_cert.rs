@@ -0,0 +1,135 @@
+//! # Fallback Certificate
+//!
+//! Served when a client sends no SNI, or names a host the ingress has no certificate for. Completing the handshake
+//! lets the proxy answer with an http error page, where failing it would only leave the client with an opaque tls
+//! error.
+//!
+//! By default a self-signed certificate is generated at startup. With `fallback_cert_secret` set, the certificate
+//! from that `kubernetes.io/tls` secret is served instead, and reloaded whenever the secret changes.
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::ListParams;
+use kube::runtime::watcher::Event;
+use kube::{runtime, Api, Client};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use tracing::{info, warn};
+
+use crate::certificate_state::{cert_key_from_pem, CertKey, CertificateState, KeyType};
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::user_certs::SecretRef;
+
+pub const FALLBACK_COMMON_NAME: &str = "iter-ingress-fallback";
+
+/// A new private key of `key_type`, RSA keys are 2048 bits.
+pub fn generate_key(key_type: KeyType) -> Result<PKey<Private>, openssl::error::ErrorStack> {
+    match key_type {
+        KeyType::Rsa => PKey::from_rsa(Rsa::generate(2048)?),
+        KeyType::EcdsaP256 | KeyType::EcdsaP384 => {
+            let curve = match key_type {
+                KeyType::EcdsaP384 => Nid::SECP384R1,
+                _ => Nid::X9_62_PRIME256V1,
+            };
+            let group = EcGroup::from_curve_name(curve)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)
+        }
+    }
+}
+
+/// Generates a self-signed certificate for `common_name` with a new key of `key_type`, valid for `days`.
+pub fn self_signed(common_name: &str, key_type: KeyType, days: u32) -> Result<CertKey, IngressLoadBalancerError> {
+    let failed = |e: openssl::error::ErrorStack| IngressLoadBalancerError::general(Code::CouldNotGenerateCertificate, e.to_string());
+
+    let cert: Result<(X509, PKey<_>), openssl::error::ErrorStack> = try {
+        let key = generate_key(key_type)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", common_name)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(days)?;
+
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_serial_number(&serial)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(&name)?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&not_before)?;
+        cert.set_not_after(&not_after)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+
+        (cert.build(), key)
+    };
+    let (cert, key) = cert.map_err(failed)?;
+
+    cert_key_from_pem(&cert.to_pem().map_err(failed)?, &key.private_key_to_pem_pkcs8().map_err(failed)?)
+}
+
+/// Serves the certificate from the secret as the fallback, for as long as the ingress runs.
+pub async fn watch_secret(client: Client, secret_ref: SecretRef, state: Arc<CertificateState>) {
+    let secrets: Api<Secret> = Api::namespaced(client, &secret_ref.namespace);
+    let params = ListParams::default().fields(&format!("metadata.name={}", secret_ref.name));
+    let mut stream = Box::pin(runtime::watcher(secrets, params));
+
+    while let Some(event) = stream.next().await {
+        let secret = match event {
+            Ok(Event::Applied(secret)) => secret,
+            Ok(Event::Restarted(secrets)) => match secrets.into_iter().next() {
+                Some(secret) => secret,
+                None => {
+                    warn!("fallback_cert: secret {} does not exist, serving the self-signed certificate", secret_ref);
+                    continue;
+                }
+            },
+            // the last certificate loaded keeps being served
+            Ok(Event::Deleted(_)) => continue,
+            Err(e) => {
+                warn!("fallback_cert: error watching secret {}: {}", secret_ref, e);
+                continue;
+            }
+        };
+
+        let data = secret.data.unwrap_or_default();
+        let cert = match (data.get("tls.crt"), data.get("tls.key")) {
+            (Some(cert_pem), Some(key_pem)) => cert_key_from_pem(&cert_pem.0, &key_pem.0),
+            _ => Err(IngressLoadBalancerError::general(Code::InvalidCertificate, "tls.crt or tls.key is missing")),
+        };
+
+        match cert {
+            Ok(cert) => {
+                *state.fallback.write().await = Some(cert);
+                info!("fallback_cert: serving certificate from secret {}", secret_ref);
+            }
+            Err(e) => warn!("fallback_cert: ignoring secret {}: {}", secret_ref, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_self_signed_certificates() {
+        let cert = self_signed(FALLBACK_COMMON_NAME, KeyType::Rsa, 365).unwrap();
+        let leaf = X509::from_der(&cert.certs[0]).unwrap();
+
+        assert_eq!(leaf.subject_name().entries().next().unwrap().data().to_string().unwrap(), FALLBACK_COMMON_NAME);
+        assert_eq!(cert.info().unwrap().valid_days_left, 365);
+    }
+}
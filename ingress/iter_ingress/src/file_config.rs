@@ -0,0 +1,358 @@
+//! # File Config
+//!
+//! A [`ConfigSource`] which reads hosts, paths, upstreams and TLS settings from a YAML or TOML file, chosen by
+//! the file extension. The file, and any certificate files it references, are checked for changes every few
+//! seconds and reloaded. If a reload fails the previous configuration stays in place.
+//!
+//! ```yaml
+//! hosts:
+//!   - host: example.com
+//!     paths:
+//!       - path: /api
+//!         upstream: 10.0.0.5:8080
+//!       - path: /
+//!         upstream: 10.0.0.6:80
+//!     # optional, certificate files are resolved relative to this file.
+//!     # hosts without certificate files are left to letsencrypt
+//!     tls:
+//!       certificate: certs/example.com.crt
+//!       key: certs/example.com.key
+//!     # optional, see crate::tls_policy. clients have to present a certificate issued by client_ca
+//!     tls_policy:
+//!       min_version: "1.3"
+//!       cipher_suites: [TLS13_AES_256_GCM_SHA384]
+//!       kx_groups: [X25519]
+//!       client_ca: certs/partners-ca.crt
+//!   - host: db.example.com
+//!     # forward tls connections untouched, the upstream terminates tls itself
+//!     tls_passthrough: true
+//!     # optional, send a PROXY protocol v1 or v2 header to the upstream on every connection
+//!     upstream_proxy_protocol: v2
+//!     paths:
+//!       - upstream: 10.0.0.7:5432
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::certificate_state::{cert_key_from_pem, CertificateState, Host, HostCerts};
+use crate::config_source::ConfigSource;
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::kube_config_tracker::{Backend, RoutingTable};
+use crate::tls_policy::{ClientCa, TlsPolicy};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Deserialize)]
+pub struct FileConfig {
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HostConfig {
+    pub host: String,
+    #[serde(default)]
+    pub paths: Vec<PathConfig>,
+    pub tls: Option<TlsConfig>,
+    pub tls_policy: Option<TlsPolicyConfig>,
+    #[serde(default)]
+    pub tls_passthrough: bool,
+    /// `v1` or `v2`
+    pub upstream_proxy_protocol: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathConfig {
+    #[serde(default = "root_path")]
+    pub path: String,
+    /// `address:port` of the upstream
+    pub upstream: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsPolicyConfig {
+    /// `1.2` or `1.3`
+    pub min_version: Option<String>,
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    #[serde(default)]
+    pub kx_groups: Vec<String>,
+    /// PEM CA bundle, resolved relative to the config file
+    pub client_ca: Option<PathBuf>,
+}
+
+fn root_path() -> String {
+    "/".to_string()
+}
+
+impl FileConfig {
+    pub fn parse(path: &Path, contents: &str) -> Result<FileConfig, IngressLoadBalancerError> {
+        let invalid = |e: String| IngressLoadBalancerError::general(Code::InvalidConfig, format!("{}: {}", path.display(), e));
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(contents).map_err(|e| invalid(e.to_string())),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(contents).map_err(|e| invalid(e.to_string())),
+            _ => Err(invalid("expected a .yaml, .yml or .toml file".to_string())),
+        }
+    }
+
+    pub fn backends(&self) -> Result<HashMap<String, Vec<Backend>>, IngressLoadBalancerError> {
+        let mut backends_by_host = HashMap::new();
+
+        for host in &self.hosts {
+            // a second entry would silently replace the first one's paths
+            if backends_by_host.contains_key(&host.host) {
+                return Err(IngressLoadBalancerError::general(Code::InvalidConfig, format!("{} is listed more than once", host.host)));
+            }
+
+            let mut backends = Vec::new();
+
+            let proxy_protocol = host.upstream_proxy_protocol
+                .as_deref()
+                .map(|version| version.parse())
+                .transpose()
+                .map_err(|e| IngressLoadBalancerError::general(Code::InvalidConfig, format!("{}: {}", host.host, e)))?;
+
+            let tls_policy = host.tls_policy
+                .as_ref()
+                .map(|policy| TlsPolicy::new(
+                    policy.min_version.as_deref(),
+                    policy.cipher_suites.clone(),
+                    policy.kx_groups.clone(),
+                    policy.client_ca.clone().map(ClientCa::File),
+                ))
+                .transpose()
+                .map_err(|e| IngressLoadBalancerError::general(Code::InvalidConfig, format!("tls_policy for {}: {}", host.host, e)))?;
+
+            for path in &host.paths {
+                let (address, port) = path.upstream
+                    .rsplit_once(':')
+                    .and_then(|(address, port)| Some((address, port.parse::<u16>().ok()?)))
+                    .ok_or_else(|| IngressLoadBalancerError::general(
+                        Code::InvalidConfig,
+                        format!("upstream for {}{} must be address:port, got {}", host.host, path.path, path.upstream)))?;
+
+                backends.push(Backend::with_prefix(host.host.clone(), path.path.clone(), address.to_string(), port)
+                    .with_tls_passthrough(host.tls_passthrough)
+                    .with_proxy_protocol(proxy_protocol)
+                    .with_static_certificate(host.tls.is_some())
+                    .with_tls_policy(tls_policy.clone()));
+            }
+
+            backends_by_host.insert(host.host.clone(), backends);
+        }
+
+        Ok(backends_by_host)
+    }
+}
+
+pub struct FileConfigSource {
+    path: PathBuf,
+    /// hosts whose certificate was loaded from a file, so they can be dropped when removed from the config
+    static_cert_hosts: Mutex<HashSet<Host>>,
+}
+
+impl FileConfigSource {
+    pub fn new(path: PathBuf) -> FileConfigSource {
+        FileConfigSource {
+            path,
+            static_cert_hosts: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn resolve(&self, file: &Path) -> PathBuf {
+        match self.path.parent() {
+            Some(dir) if file.is_relative() => dir.join(file),
+            _ => file.to_path_buf(),
+        }
+    }
+
+    /// Modification times of the config file and every certificate file it references, used to detect changes.
+    async fn fingerprint(&self, config: Option<&FileConfig>) -> Vec<Option<SystemTime>> {
+        let mut files = vec![self.path.clone()];
+
+        if let Some(config) = config {
+            for tls in config.hosts.iter().filter_map(|host| host.tls.as_ref()) {
+                files.push(self.resolve(&tls.certificate));
+                files.push(self.resolve(&tls.key));
+            }
+        }
+
+        let mut fingerprint = Vec::new();
+        for file in files {
+            fingerprint.push(tokio::fs::metadata(&file).await.and_then(|m| m.modified()).ok());
+        }
+        fingerprint
+    }
+
+    async fn load(&self, routing_table: &RoutingTable, cert_state: &CertificateState) -> Result<FileConfig, IngressLoadBalancerError> {
+        let contents = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| IngressLoadBalancerError::general(Code::InvalidConfig, format!("could not read {}: {}", self.path.display(), e)))?;
+
+        let config = FileConfig::parse(&self.path, &contents)?;
+        let mut backends = config.backends()?;
+        for backend in backends.values_mut().flatten() {
+            if let Some(ClientCa::File(path)) = backend.tls_policy.as_mut().and_then(|policy| policy.client_ca.as_mut()) {
+                *path = self.resolve(path);
+            }
+        }
+
+        let mut certs = HashMap::new();
+        for host in &config.hosts {
+            if let Some(tls) = &host.tls {
+                let read = |file: PathBuf| async move {
+                    tokio::fs::read(&file)
+                        .await
+                        .map_err(|e| IngressLoadBalancerError::general(Code::InvalidCertificate, format!("could not read {}: {}", file.display(), e)))
+                };
+
+                let cert_pem = read(self.resolve(&tls.certificate)).await?;
+                let key_pem = read(self.resolve(&tls.key)).await?;
+                certs.insert(host.host.clone(), cert_key_from_pem(&cert_pem, &key_pem)?);
+            }
+        }
+
+        // everything is valid, so apply it
+        routing_table.replace(backends).await;
+
+        let mut static_cert_hosts = self.static_cert_hosts.lock().await;
+        let mut state_certs = cert_state.certs.write().await;
+        for host in static_cert_hosts.drain() {
+            if !certs.contains_key(&host) {
+                state_certs.remove(&host);
+            }
+        }
+        for (host, cert) in certs {
+            static_cert_hosts.insert(host.clone());
+            state_certs.insert(host, HostCerts::from(cert));
+        }
+
+        info!("file_config: loaded {} hosts from {}", config.hosts.len(), self.path.display());
+        Ok(config)
+    }
+}
+
+#[async_trait::async_trait]
+impl ConfigSource for FileConfigSource {
+    async fn watch(self: Arc<Self>, routing_table: Arc<RoutingTable>, cert_state: Arc<CertificateState>) -> Result<(), anyhow::Error> {
+        let mut config = None;
+        let mut fingerprint = Vec::new();
+        let mut force_reload = true;
+
+        loop {
+            let current = self.fingerprint(config.as_ref()).await;
+
+            if force_reload || current != fingerprint {
+                match self.load(&routing_table, &cert_state).await {
+                    Ok(loaded) => {
+                        config = Some(loaded);
+                        fingerprint = self.fingerprint(config.as_ref()).await;
+                    }
+                    Err(e) => {
+                        warn!("file_config: keeping previous configuration, {}", e);
+                        fingerprint = current;
+                    }
+                }
+            }
+
+            force_reload = tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => false,
+                _ = routing_table.resync_requested() => true,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_yaml_and_toml() {
+        let yaml = r#"
+hosts:
+  - host: example.com
+    paths:
+      - path: /api
+        upstream: 10.0.0.5:8080
+      - upstream: "[::1]:80"
+"#;
+        let config = FileConfig::parse(Path::new("ingress.yaml"), yaml).unwrap();
+        let backends = config.backends().unwrap();
+        assert_eq!(backends["example.com"].len(), 2);
+        assert_eq!(backends["example.com"][1].service_name, "[::1]");
+        assert_eq!(backends["example.com"][1].port, 80);
+
+        let toml = r#"
+[[hosts]]
+host = "example.com"
+
+[[hosts.paths]]
+path = "/"
+upstream = "localhost:3000"
+
+[hosts.tls]
+certificate = "example.crt"
+key = "example.key"
+"#;
+        let config = FileConfig::parse(Path::new("ingress.toml"), toml).unwrap();
+        assert_eq!(config.hosts[0].tls.as_ref().unwrap().key, PathBuf::from("example.key"));
+
+        assert!(FileConfig::parse(Path::new("ingress.json"), "{}").is_err());
+    }
+
+    #[test]
+    fn rejects_upstreams_without_port() {
+        let config = FileConfig::parse(Path::new("ingress.yaml"), "hosts: [{ host: a.com, paths: [{ upstream: backend }] }]").unwrap();
+        assert!(config.backends().is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_hosts() {
+        let config = FileConfig::parse(
+            Path::new("ingress.yaml"),
+            "hosts: [{ host: a.com, paths: [{ upstream: 'a:80' }] }, { host: a.com, paths: [{ path: /api, upstream: 'b:80' }] }]",
+        ).unwrap();
+        assert!(config.backends().is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_routes_when_the_file_changes() {
+        let dir = std::env::temp_dir().join(format!("iter-file-config-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("ingress.yaml");
+        tokio::fs::write(&path, "hosts: [{ host: a.com, paths: [{ upstream: 'a:80' }] }]").await.unwrap();
+
+        let routing_table = Arc::new(RoutingTable::new());
+        let source = Arc::new(FileConfigSource::new(path.clone()));
+        tokio::spawn(source.watch(routing_table.clone(), Arc::new(CertificateState::new())));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(routing_table.find_backend("a.com", "/").await.unwrap().address(), "a:80");
+
+        // the change alone has to be noticed, on file systems with coarse timestamps it may not move the mtime
+        tokio::fs::write(&path, "hosts: [{ host: b.com, paths: [{ upstream: 'b:80' }] }]").await.unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        tokio::time::sleep(POLL_INTERVAL + Duration::from_millis(500)).await;
+
+        assert!(routing_table.find_backend("a.com", "/").await.is_err());
+        assert_eq!(routing_table.find_backend("b.com", "/").await.unwrap().address(), "b:80");
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
@@ -0,0 +1,225 @@
+//! # Forwarding
+//!
+//! Who a request really came from, and how that is passed on to upstreams.
+//!
+//! Behind a cloud L4 load balancer the ingress only sees the balancer's address. Setting
+//! `proxy_protocol_trusted_cidrs` to the balancers' ranges lets them send a PROXY protocol v1 or v2 header on the
+//! http and https listeners, and the client address from the header is used from then on. Connections from
+//! other addresses are never checked for a header.
+//!
+//! Upstreams receive the client address in `X-Forwarded-For` and `X-Real-IP`, along with `X-Forwarded-Proto` and
+//! `X-Forwarded-Host`, and the subject of a client certificate verified under the host's
+//! [`TlsPolicy`](crate::tls_policy::TlsPolicy) in `X-Client-Cert-Subject`. Upstreams which want a PROXY protocol header themselves ask for one with the
+//! [`UPSTREAM_PROXY_PROTOCOL_ANNOTATION`](crate::kube_config_tracker::UPSTREAM_PROXY_PROTOCOL_ANNOTATION).
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::Service;
+use hyper::{HeaderMap, Uri};
+use iter_tls_acceptor::proxy_protocol::{self, ClientStream};
+use iter_tls_acceptor::tls_acceptor::TlsConn;
+use openssl::x509::X509;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::kube_config_tracker::Backend;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+const X_CLIENT_CERT_SUBJECT: HeaderName = HeaderName::from_static("x-client-cert-subject");
+
+/// The connection a request arrived on.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// the real client, as announced by a trusted load balancer or otherwise the tcp peer
+    pub addr: SocketAddr,
+    /// the address the client connected to
+    pub server_addr: SocketAddr,
+    pub https: bool,
+    /// the host the client asked for in the tls handshake
+    pub server_name: Option<String>,
+    /// subject of the certificate the client presented, only ever set once it has been verified
+    pub client_cert_subject: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_stream(stream: &ClientStream, https: bool) -> ClientInfo {
+        ClientInfo {
+            addr: stream.client_addr(),
+            server_addr: stream.server_addr(),
+            https,
+            server_name: None,
+            client_cert_subject: None,
+        }
+    }
+
+    pub fn from_tls(conn: &TlsConn) -> ClientInfo {
+        let (stream, connection) = conn.get_ref();
+
+        ClientInfo {
+            server_name: connection.sni_hostname().map(|name| name.to_string()),
+            client_cert_subject: connection.peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| certificate_subject(&cert.0)),
+            ..ClientInfo::from_stream(stream, true)
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self.https {
+            true => "https",
+            false => "http",
+        }
+    }
+}
+
+/// Tells the upstream who the client is. An existing `X-Forwarded-For` is appended to, as there may be more
+/// proxies in front of the ingress. The client certificate's subject is only passed on if the route verifies
+/// client certificates, `client_ca`, and the handshake was for `host`.
+pub fn set_forwarding_headers(headers: &mut HeaderMap, client: &ClientInfo, host: &str, client_ca: bool) {
+    let client_ip = client.addr.ip().to_string();
+
+    let forwarded_for = match headers.get(&X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, client_ip),
+        None => client_ip.clone(),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&client_ip) {
+        headers.insert(X_REAL_IP, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(host) {
+        headers.insert(X_FORWARDED_HOST, value);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(client.scheme()));
+
+    // only ever what the ingress verified for this host, never what the client sent or a certificate verified
+    // against another host's CA on the same connection
+    headers.remove(&X_CLIENT_CERT_SUBJECT);
+    if !client_ca || client.server_name.as_deref() != Some(host) {
+        return;
+    }
+    if let Some(value) = client.client_cert_subject.as_ref().and_then(|subject| HeaderValue::from_str(subject).ok()) {
+        headers.insert(X_CLIENT_CERT_SUBJECT, value);
+    }
+}
+
+/// The certificate's subject as e.g. `CN=client,O=Partner`.
+fn certificate_subject(der: &[u8]) -> Option<String> {
+    let cert = X509::from_der(der).ok()?;
+
+    let entries: Option<Vec<String>> = cert.subject_name().entries().map(|entry| {
+        let name = entry.object().nid().short_name().ok()?;
+        let value = entry.data().to_string().ok()?;
+        Some(format!("{}={}", name, value))
+    }).collect();
+
+    entries.map(|entries| entries.join(","))
+}
+
+/// The PROXY protocol header the backend wants on each connection, if any.
+pub fn upstream_proxy_header(backend: &Backend, client: &ClientInfo) -> Option<Vec<u8>> {
+    backend.proxy_protocol.map(|version| proxy_protocol::encode(version, client.addr, client.server_addr))
+}
+
+/// Connects to upstreams for hyper, sending a PROXY protocol header first when the backend wants one.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    proxy_header: Option<Arc<Vec<u8>>>,
+}
+
+impl UpstreamConnector {
+    pub fn new(backend: &Backend, client: &ClientInfo) -> UpstreamConnector {
+        UpstreamConnector {
+            http: HttpConnector::new(),
+            proxy_header: upstream_proxy_header(backend, client).map(Arc::new),
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let proxy_header = self.proxy_header.clone();
+
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(proxy_header) = proxy_header {
+                stream.write_all(&proxy_header).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_to_existing_forwarding_headers() {
+        let client = ClientInfo {
+            addr: "203.0.113.7:51234".parse().unwrap(),
+            server_addr: "10.0.0.1:443".parse().unwrap(),
+            https: true,
+            server_name: Some("example.com".to_string()),
+            client_cert_subject: None,
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("198.51.100.1"));
+        headers.insert(X_CLIENT_CERT_SUBJECT, HeaderValue::from_static("CN=spoofed"));
+        set_forwarding_headers(&mut headers, &client, "example.com", false);
+
+        assert_eq!(headers[&X_FORWARDED_FOR], "198.51.100.1, 203.0.113.7");
+        assert_eq!(headers[&X_REAL_IP], "203.0.113.7");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[&X_FORWARDED_HOST], "example.com");
+        assert!(!headers.contains_key(&X_CLIENT_CERT_SUBJECT));
+
+        let cert = crate::fallback_cert::self_signed("partner.example.com", crate::certificate_state::KeyType::Rsa, 30).unwrap();
+        let client = ClientInfo { client_cert_subject: certificate_subject(&cert.certs[0]), ..client };
+        set_forwarding_headers(&mut headers, &client, "example.com", true);
+        assert_eq!(headers[&X_CLIENT_CERT_SUBJECT], "CN=partner.example.com");
+
+        // hosts without a client CA never verified it
+        set_forwarding_headers(&mut headers, &client, "example.com", false);
+        assert!(!headers.contains_key(&X_CLIENT_CERT_SUBJECT));
+    }
+
+    #[test]
+    fn drops_client_certificates_verified_for_another_host() {
+        let cert = crate::fallback_cert::self_signed("partner.example.com", crate::certificate_state::KeyType::Rsa, 30).unwrap();
+        let client = ClientInfo {
+            addr: "203.0.113.7:51234".parse().unwrap(),
+            server_addr: "10.0.0.1:443".parse().unwrap(),
+            https: true,
+            server_name: Some("a.example.com".to_string()),
+            client_cert_subject: certificate_subject(&cert.certs[0]),
+        };
+
+        // a later request on the same connection for a host with a client CA of its own
+        let mut headers = HeaderMap::new();
+        set_forwarding_headers(&mut headers, &client, "b.example.com", true);
+        assert!(!headers.contains_key(&X_CLIENT_CERT_SUBJECT));
+    }
+}
@@ -0,0 +1,245 @@
+//! # Ingress Status
+//!
+//! Shows with `kubectl` whether iter picked up an ingress. The leader writes the addresses the ingress is reachable
+//! at to `status.loadBalancer.ingress` of every ingress it routes: the addresses from [`IngressStatusSettings`], or
+//! those of the `LoadBalancer` Service in front of the ingress pods. Without either the status is left alone.
+//!
+//! Whenever an ingress changes, the leader also publishes [events](crate::events) on it: that its routes were
+//! accepted, why any of its paths were rejected, e.g. an unsupported pathType or a missing port, and which of its
+//! backend Services don't exist.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{LoadBalancerIngress, Service};
+use k8s_openapi::api::networking::v1::Ingress;
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::runtime::events::EventType;
+use kube::runtime::{self, watcher::Event};
+use kube::{Api, Client, ResourceExt};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::events::IngressEvents;
+use crate::kube_config_tracker::{ingress_key, routes_from_ingress, IngressRoutes};
+
+/// How often the addresses of the published Service are checked for changes.
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct IngressStatusSettings {
+    /// ips or hostnames published as they are
+    pub addresses: Vec<String>,
+    /// `(namespace, name)` of the Service whose addresses are published when `addresses` is empty
+    pub publish_service: Option<(String, String)>,
+}
+
+pub struct IngressStatus {
+    client: Client,
+    settings: IngressStatusSettings,
+    cluster_domain: String,
+    events: Arc<IngressEvents>,
+}
+
+impl IngressStatus {
+    pub fn new(client: Client, settings: IngressStatusSettings, cluster_domain: String, events: Arc<IngressEvents>) -> IngressStatus {
+        IngressStatus { client, settings, cluster_domain, events }
+    }
+
+    /// Keeps the ingresses' status up to date while `leader` is true, so replicas don't report the same change twice.
+    pub async fn run(self: Arc<Self>, mut leader: watch::Receiver<bool>) {
+        loop {
+            if !*leader.borrow_and_update() {
+                if leader.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            // a new leader reports every ingress once more
+            tokio::select! {
+                _ = self.sync() => {}
+                changed = leader.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+
+    async fn sync(&self) {
+        let ingresses: Api<Ingress> = Api::all(self.client.clone());
+        // generation of every ingress, by `namespace/name`, as last reported in events
+        let mut reported: HashMap<String, Option<i64>> = HashMap::new();
+        let mut check = tokio::time::interval(ADDRESS_CHECK_INTERVAL);
+
+        loop {
+            let addresses = self.addresses().await;
+            let mut stream = Box::pin(runtime::watcher(ingresses.clone(), ListParams::default()));
+            check.reset();
+
+            loop {
+                let event = tokio::select! {
+                    event = stream.next() => event,
+                    _ = check.tick() => {
+                        // watching again lists every ingress, whose status is then written with the new addresses
+                        if self.addresses().await != addresses {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                match event {
+                    Some(Ok(Event::Applied(ingress))) => self.report(&ingress, addresses.as_deref(), &mut reported).await,
+                    Some(Ok(Event::Deleted(ingress))) => {
+                        reported.remove(&ingress_key(&ingress));
+                    }
+                    Some(Ok(Event::Restarted(list))) => {
+                        for ingress in list {
+                            self.report(&ingress, addresses.as_deref(), &mut reported).await;
+                        }
+                    }
+                    Some(Err(e)) => warn!("ingress_status: error watching ingresses: {}", e),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Publishes events about the ingress if it changed since it was last reported, and writes its status.
+    async fn report(&self, ingress: &Ingress, addresses: Option<&[LoadBalancerIngress]>, reported: &mut HashMap<String, Option<i64>>) {
+        let routes = routes_from_ingress(ingress, &self.cluster_domain);
+
+        // writing the status changes the ingress too, but not its generation
+        let generation = ingress.metadata.generation;
+        if reported.insert(ingress_key(ingress), generation) != Some(generation) {
+            self.publish_events(ingress, &routes).await;
+        }
+
+        if let (false, Some(addresses)) = (routes.backends.is_empty(), addresses) {
+            self.write_status(ingress, addresses).await;
+        }
+    }
+
+    async fn publish_events(&self, ingress: &Ingress, routes: &IngressRoutes) {
+        if !routes.backends.is_empty() {
+            let hosts: BTreeSet<&str> = routes.backends.iter().map(|backend| backend.host.as_str()).collect();
+            let paths = match routes.backends.len() {
+                1 => "1 path".to_string(),
+                paths => format!("{} paths", paths),
+            };
+            let note = format!("routing {} of {}", paths, hosts.into_iter().collect::<Vec<_>>().join(", "));
+            self.events.publish_on(ingress, EventType::Normal, "Accepted", "Route", &note).await;
+        }
+
+        if !routes.rejected.is_empty() {
+            let note = format!("not routing {}", routes.rejected.join("; "));
+            self.events.publish_on(ingress, EventType::Warning, "Rejected", "Route", &note).await;
+        }
+
+        let namespace = ingress.namespace().unwrap_or_default();
+        let services: Api<Service> = Api::namespaced(self.client.clone(), &namespace);
+        for service in service_names(ingress) {
+            match services.get(&service).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(e)) if e.code == 404 => {
+                    let note = format!("backend service {}/{} doesn't exist", namespace, service);
+                    self.events.publish_on(ingress, EventType::Warning, "ServiceNotFound", "Route", &note).await;
+                }
+                Err(e) => warn!("ingress_status: could not look up service {}/{}: {}", namespace, service, e),
+            }
+        }
+    }
+
+    async fn write_status(&self, ingress: &Ingress, addresses: &[LoadBalancerIngress]) {
+        let current = ingress.status.as_ref().and_then(|status| status.load_balancer.as_ref()).and_then(|lb| lb.ingress.as_deref());
+        if current == Some(addresses) {
+            return;
+        }
+
+        let ingresses: Api<Ingress> = Api::namespaced(self.client.clone(), &ingress.namespace().unwrap_or_default());
+        let patch = serde_json::json!({ "status": { "loadBalancer": { "ingress": addresses } } });
+        match ingresses.patch_status(&ingress.name(), &PatchParams::default(), &Patch::Merge(&patch)).await {
+            Ok(_) => info!("ingress_status: published the addresses of ingress {}", ingress_key(ingress)),
+            Err(e) => warn!("ingress_status: could not write the status of ingress {}: {}", ingress_key(ingress), e),
+        }
+    }
+
+    /// The addresses to publish, `None` when there are none to publish or they can't be found out right now.
+    async fn addresses(&self) -> Option<Vec<LoadBalancerIngress>> {
+        if !self.settings.addresses.is_empty() {
+            return Some(self.settings.addresses.iter().map(|address| load_balancer_ingress(address)).collect());
+        }
+
+        let (namespace, name) = self.settings.publish_service.as_ref()?;
+        let service = match Api::<Service>::namespaced(self.client.clone(), namespace).get(name).await {
+            Ok(service) => service,
+            Err(e) => {
+                warn!("ingress_status: could not look up service {}/{}: {}", namespace, name, e);
+                return None;
+            }
+        };
+
+        let load_balancer = service.status.and_then(|status| status.load_balancer).and_then(|lb| lb.ingress).unwrap_or_default();
+        if !load_balancer.is_empty() {
+            return Some(load_balancer
+                .into_iter()
+                .map(|lb| LoadBalancerIngress { hostname: lb.hostname, ip: lb.ip, ..LoadBalancerIngress::default() })
+                .collect());
+        }
+
+        // services without a load balancer are reached on their external ips
+        let external_ips = service.spec.and_then(|spec| spec.external_ips).unwrap_or_default();
+        Some(external_ips.iter().map(|address| load_balancer_ingress(address)).collect())
+    }
+}
+
+fn load_balancer_ingress(address: &str) -> LoadBalancerIngress {
+    match address.parse::<IpAddr>() {
+        Ok(_) => LoadBalancerIngress { ip: Some(address.to_string()), ..LoadBalancerIngress::default() },
+        Err(_) => LoadBalancerIngress { hostname: Some(address.to_string()), ..LoadBalancerIngress::default() },
+    }
+}
+
+/// The Services the ingress' paths lead to.
+fn service_names(ingress: &Ingress) -> BTreeSet<String> {
+    ingress.spec.iter()
+        .flat_map(|spec| spec.rules.iter().flatten())
+        .flat_map(|rule| rule.http.iter().flat_map(|http| http.paths.iter()))
+        .filter_map(|path| path.backend.service.as_ref())
+        .map(|service| service.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_ips_and_hostnames() {
+        assert_eq!(load_balancer_ingress("203.0.113.7").ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(load_balancer_ingress("2001:db8::7").ip.as_deref(), Some("2001:db8::7"));
+        assert_eq!(load_balancer_ingress("lb.example.com").hostname.as_deref(), Some("lb.example.com"));
+    }
+
+    #[test]
+    fn finds_the_backend_services() {
+        let ingress: Ingress = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "web", "namespace": "default" },
+            "spec": { "rules": [
+                { "host": "a.example.com", "http": { "paths": [
+                    { "path": "/", "pathType": "Prefix", "backend": { "service": { "name": "web", "port": { "number": 80 } } } },
+                    { "path": "/api", "pathType": "Prefix", "backend": { "service": { "name": "api", "port": { "number": 80 } } } },
+                ] } },
+                { "host": "b.example.com", "http": { "paths": [
+                    { "path": "/", "pathType": "Prefix", "backend": { "service": { "name": "web", "port": { "number": 80 } } } },
+                ] } },
+            ] },
+        })).unwrap();
+
+        assert_eq!(service_names(&ingress).into_iter().collect::<Vec<_>>(), vec!["api", "web"]);
+    }
+}
@@ -3,21 +3,41 @@
 //! This module tracks the configuration of services, ingresses required for routing traffic to kubernetes services.
 //!
 //! It sets up a watcher which listens for changes to the kubernetes api and updates the routing table accordingly.
+//! The routing table itself doesn't know about kubernetes, it is filled by a [`ConfigSource`].
 
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use k8s_openapi::api::core::v1::{Pod};
+use std::time::Duration;
 use k8s_openapi::api::networking::v1::Ingress;
-use kube::core::WatchEvent;
-use kube::{Api, Client, api::ListParams, runtime};
-use futures::{StreamExt, TryStreamExt};
+use kube::runtime::watcher::Event;
+use kube::{Api, Client, ResourceExt, api::ListParams, runtime};
+use futures::StreamExt;
+use iter_tls_acceptor::proxy_protocol::Version as ProxyProtocolVersion;
 use regex::Regex;
-use tokio::sync::RwLock;
-use tokio_retry::strategy::FibonacciBackoff;
-
-use crate::lets_encrypt::NAMESPACE;
+use tokio::sync::{Notify, RwLock};
+use tracing::{info, warn};
+
+use crate::certificate_state::CertificateState;
+use crate::config_source::ConfigSource;
+use crate::serverless::ServerlessTarget;
+use crate::settings::service_host;
+use crate::tls_policy::TlsPolicy;
+use crate::user_certs::SecretRef;
 use crate::{IngressLoadBalancerError, Code};
 
+/// Set to `"true"` on an ingress to forward its hosts' tls connections untouched, for services which terminate tls
+/// themselves. The ingress picks the backend by sni, so paths other than the first one are ignored.
+pub const TLS_PASSTHROUGH_ANNOTATION: &str = "iter.earth/ssl-passthrough";
+
+/// Set to `v1` or `v2` on an ingress whose services expect a PROXY protocol header on every connection.
+pub const UPSTREAM_PROXY_PROTOCOL_ANNOTATION: &str = "iter.earth/upstream-proxy-protocol";
+
+/// Names the Deployment, in the ingress' namespace, behind a scale-to-zero service. See [`crate::serverless`].
+pub const SERVERLESS_ANNOTATION: &str = "iter.earth/serverless-deployment";
+
+/// Seconds without requests before the deployment named by [`SERVERLESS_ANNOTATION`] is scaled to zero.
+pub const SERVERLESS_IDLE_TIMEOUT_ANNOTATION: &str = "iter.earth/serverless-idle-timeout-seconds";
+
 #[derive(Debug, Clone)]
 pub enum ChangeType {
     BackendChanged,
@@ -26,151 +46,377 @@ pub enum ChangeType {
 pub struct RoutingTable {
     subscribers: RwLock<Vec<Box<dyn Fn(ChangeType) + Sync + Send>>>,
     pub backends_by_host: RwLock<HashMap<String, HashSet<Backend>>>, // there may be multiple backends for a host, so we need to store them in a map later
+    /// backends grouped by the piece of configuration that declared them, e.g. an ingress
+    backends_by_source: RwLock<HashMap<String, Vec<Backend>>>,
+    resync: Notify,
 }
 
 impl RoutingTable {
     pub fn new() -> Self {
         Self {
             subscribers: RwLock::new(Vec::new()),
-            backends_by_host: RwLock::new(HashMap::new())
+            backends_by_host: RwLock::new(HashMap::new()),
+            backends_by_source: RwLock::new(HashMap::new()),
+            resync: Notify::new(),
         }
     }
 
-    pub async fn start_watching(self: Arc<Self>) -> Result<(), anyhow::Error> {
-        let client = Client::try_default().await.expect("Expected a valid KUBECONFIG environment variable");
-        let ingress_api: Api<Ingress> = Api::all(client.clone());
+    /// Sets the backends declared by a single piece of configuration, replacing what it declared before.
+    pub async fn apply(&self, key: String, backends: Vec<Backend>) {
+        self.backends_by_source.write().await.insert(key, backends);
+        self.rebuild_backends_by_host().await;
+    }
 
-        let rt = self.clone();
+    pub async fn remove(&self, key: &str) {
+        self.backends_by_source.write().await.remove(key);
+        self.rebuild_backends_by_host().await;
+    }
 
-        let watcher = runtime::watcher(ingress_api, ListParams::default());
-        let mut stream = Box::pin(runtime::utils::try_flatten_applied(watcher));
+    /// Replaces every backend in the table, used when a config source has reloaded all of its configuration.
+    pub async fn replace(&self, backends_by_source: HashMap<String, Vec<Backend>>) {
+        *self.backends_by_source.write().await = backends_by_source;
+        self.rebuild_backends_by_host().await;
+    }
 
-        while let Some(resource) = stream.next().await {
-            let rt = rt.clone();
-            let resource = resource?;
-            // Ingress name
-            let name = resource.metadata.name.clone();
-            println!("Ingress Name: {:?} changed", name);
+    /// Asks the config source to load its configuration again from scratch, replacing the routing table with the result.
+    pub fn request_resync(&self) {
+        self.resync.notify_one();
+    }
 
-            if let None = resource.spec {
-                break
-            }
+    /// Resolves when a resync has been requested.
+    pub async fn resync_requested(&self) {
+        self.resync.notified().await
+    }
 
-            if let None = resource.spec.as_ref().unwrap().rules {
-                break
-            }
+    async fn rebuild_backends_by_host(&self) {
+        let backends_by_source = self.backends_by_source.read().await;
+        let mut backends_by_host: HashMap<String, HashSet<Backend>> = HashMap::new();
 
-            let rules = &resource.spec.unwrap().rules.unwrap();
+        for backend in backends_by_source.values().flatten() {
+            backends_by_host
+                .entry(backend.host.clone())
+                .or_default()
+                .insert(backend.clone());
+        }
 
-            for rule in rules {
-                if let None = rule.host {
-                    continue; // we don't support rules without a host
-                }
+        *self.backends_by_host.write().await = backends_by_host;
+        drop(backends_by_source);
 
-                if let None = rule.http.as_ref() {
-                    continue; // we don't support rules without an http spec
-                }
+        self.notify_subscribers(ChangeType::BackendChanged).await;
+    }
 
-                let paths = &rule.http.as_ref().unwrap().paths;
+    pub async fn subscribe(&self, subscriber: Box<dyn Fn(ChangeType) + Sync + Send>) {
+        self.subscribers.write().await.push(subscriber);
+    }
 
-                for path in paths {
-                    if let None = path.path {
-                        continue; // we don't support rules without a path
-                    }
+    pub async fn notify_subscribers(&self, change_type: ChangeType) {
+        let mut subscribers = self.subscribers.write().await;
 
-                    let backend = &path.backend;
+        for subscriber in subscribers.iter_mut() {
+            subscriber(change_type.clone());
+        }
+    }
 
-                    if let None = backend.service {
-                        continue; // we don't support rules without a service
-                    }
+    /// Returns the backend with the longest path prefix matching the request, so a more specific path wins whatever
+    /// order the paths were declared in. Its [`Backend::address`] is `service:port`.
+    pub async fn find_backend(&self, host: &str, path: &str) -> Result<Backend, IngressLoadBalancerError> {
+        let backends_for_host = self.backends_by_host.read().await;
 
-                    if let None = backend.service.as_ref().unwrap().port {
-                        continue; // we don't support rules without a service port
-                    }
+        let backend = backends_for_host
+            .get(host)
+            .and_then(|backends| backends
+                .iter()
+                .filter(|backend| backend.matches(path))
+                .max_by_key(|backend| backend.path_pattern().len()));
 
-                    if let None = backend.service.as_ref().unwrap().port.as_ref().unwrap().number {
-                        continue; // we don't support rules without a service port
-                    }
+        match backend {
+            Some(backend) => Ok(backend.clone()),
+            None => Err(IngressLoadBalancerError::general(Code::NonExistentHost, format!("No backend found for host: {}", host))),
+        }
+    }
 
-                    if let None = path.path_type {
-                        continue; // we don't support rules without a path type
-                    }
+    /// Returns the backend to forward tls connections for the host to without terminating them, if the host is
+    /// configured for tls passthrough.
+    pub async fn get_passthrough_backend(&self, host: &str) -> Option<Backend> {
+        let backends_for_host = self.backends_by_host.read().await;
 
-                    let path_type = path.path_type.as_ref().unwrap();
+        backends_for_host
+            .get(host)?
+            .iter()
+            .filter(|backend| backend.tls_passthrough)
+            .min_by_key(|backend| backend.path_pattern().len())
+            .cloned()
+    }
 
-                    if path_type != "Prefix" {
-                        continue; // we only support prefix paths
-                    }
+    /// Every host the ingress needs a certificate for, that is every routed host which isn't only passed through and
+    /// doesn't bring its own certificate, from a secret or the config file.
+    pub async fn tls_hosts(&self) -> HashSet<String> {
+        self.backends_by_host
+            .read()
+            .await
+            .iter()
+            .filter(|(_, backends)| backends.iter().any(|backend| !backend.tls_passthrough))
+            .filter(|(_, backends)| backends.iter().all(|backend| backend.tls_secrets.is_empty() && !backend.static_certificate))
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+
+    /// The secrets holding each host's certificates, for hosts which bring their own. A host may name one secret
+    /// with an RSA and one with an ECDSA certificate.
+    pub async fn user_certificates(&self) -> HashMap<String, Vec<SecretRef>> {
+        self.backends_by_host
+            .read()
+            .await
+            .iter()
+            .filter_map(|(host, backends)| {
+                let secret_refs = backends.iter().find(|backend| !backend.tls_secrets.is_empty())?.tls_secrets.clone();
+                Some((host.clone(), secret_refs))
+            })
+            .collect()
+    }
 
-                    let host = rule.host.as_ref().unwrap();
-                    let service_name = &backend.service.as_ref().unwrap().name;
-                    let service_port = backend.service.as_ref().unwrap().port.as_ref().unwrap();
-                    let path_prefix = path.path.as_ref().unwrap();
-                    let namespace = resource.metadata.namespace.clone().unwrap();
+    /// The pieces of configuration declaring routes for the host, e.g. `namespace/name` of ingresses.
+    pub async fn sources_of(&self, host: &str) -> Vec<String> {
+        self.backends_by_source
+            .read()
+            .await
+            .iter()
+            .filter(|(_, backends)| backends.iter().any(|backend| backend.host == host))
+            .map(|(source, _)| source.clone())
+            .collect()
+    }
 
-                    let backend = Backend::with_prefix(
-                        host.to_string(),
-                        path_prefix.to_string(),
-                        format!("{}.{}", service_name.to_string(), namespace),
-                        service_port.number.unwrap() as u16);
+    /// The tls policy of each host which has one.
+    pub async fn tls_policies(&self) -> HashMap<String, TlsPolicy> {
+        self.backends_by_host
+            .read()
+            .await
+            .iter()
+            .filter_map(|(host, backends)| {
+                let policy = backends.iter().find_map(|backend| backend.tls_policy.clone())?;
+                Some((host.clone(), policy))
+            })
+            .collect()
+    }
 
-                    let mut backends = rt.backends_by_host.write().await;
+    /// Every scale-to-zero deployment a route points at.
+    pub async fn serverless_targets(&self) -> HashSet<ServerlessTarget> {
+        self.backends_by_host
+            .read()
+            .await
+            .values()
+            .flatten()
+            .filter_map(|backend| backend.serverless.clone())
+            .collect()
+    }
+}
 
-                    // check if the host already has backends
-                    let backends_for_host = if let Some(backends_for_host) = backends.get_mut(host) {
-                        backends_for_host
-                    } else {
-                        backends.insert(host.to_string(), HashSet::new());
-                        backends.get_mut(host).unwrap()
-                    };
+/// Watches every ingress in the cluster and keeps the routing table up to date.
+pub struct KubernetesConfigSource {
+    pub cluster_domain: String,
+}
 
-                    // add the backend to the host
-                    backends_for_host.insert(backend);
+#[async_trait::async_trait]
+impl ConfigSource for KubernetesConfigSource {
+    async fn watch(self: Arc<Self>, routing_table: Arc<RoutingTable>, _cert_state: Arc<CertificateState>) -> Result<(), anyhow::Error> {
+        let client = Client::try_default().await?;
+        let ingress_api: Api<Ingress> = Api::all(client.clone());
 
-                    rt.notify_subscribers(ChangeType::BackendChanged).await;
+        loop {
+            let watcher = runtime::watcher(ingress_api.clone(), ListParams::default());
+            let mut stream = Box::pin(watcher);
+
+            loop {
+                let event = tokio::select! {
+                    event = stream.next() => event,
+                    _ = routing_table.resync_requested() => {
+                        // dropping the stream and watching again starts with a fresh list of every ingress
+                        info!("Resyncing routing table");
+                        break;
+                    }
+                };
+
+                match event {
+                    Some(Ok(Event::Applied(ingress))) => {
+                        info!("Ingress Name: {:?} changed", ingress.metadata.name);
+                        routing_table.apply(ingress_key(&ingress), routes_from_ingress(&ingress, &self.cluster_domain).backends).await;
+                    }
+                    Some(Ok(Event::Deleted(ingress))) => {
+                        info!("Ingress Name: {:?} deleted", ingress.metadata.name);
+                        routing_table.remove(&ingress_key(&ingress)).await;
+                    }
+                    Some(Ok(Event::Restarted(ingresses))) => {
+                        routing_table.replace(ingresses
+                            .iter()
+                            .map(|ingress| (ingress_key(ingress), routes_from_ingress(ingress, &self.cluster_domain).backends))
+                            .collect()).await;
+                    }
+                    Some(Err(e)) => warn!("Error watching ingresses: {}", e),
+                    None => return Ok(()),
                 }
             }
         }
-
-        Ok(())
     }
+}
 
-    pub async fn subscribe(&self, subscriber: Box<dyn Fn(ChangeType) + Sync + Send>) {
-        self.subscribers.write().await.push(subscriber);
-    }
+pub fn ingress_key(ingress: &Ingress) -> String {
+    format!("{}/{}", ingress.namespace().unwrap_or_default(), ingress.name())
+}
 
-    pub async fn notify_subscribers(&self, change_type: ChangeType) {
-        let mut subscribers = self.subscribers.write().await;
+/// What iter makes of an ingress: the backends it routes, and why it doesn't route the rest.
+#[derive(Debug, Default)]
+pub struct IngressRoutes {
+    pub backends: Vec<Backend>,
+    /// a reason for every rule or path which isn't routed, e.g. `web.example.com/api: unsupported pathType Exact`
+    pub rejected: Vec<String>,
+}
 
-        for subscriber in subscribers.iter_mut() {
-            subscriber(change_type.clone());
+pub fn routes_from_ingress(ingress: &Ingress, cluster_domain: &str) -> IngressRoutes {
+    let mut routes = IngressRoutes::default();
+
+    let rules = match ingress.spec.as_ref().and_then(|spec| spec.rules.as_ref()) {
+        Some(rules) => rules,
+        None => return routes,
+    };
+
+    let namespace = ingress.namespace().unwrap_or_default();
+    let tls_passthrough = ingress.annotations().get(TLS_PASSTHROUGH_ANNOTATION).map(|value| value == "true").unwrap_or(false);
+    let proxy_protocol = ingress.annotations().get(UPSTREAM_PROXY_PROTOCOL_ANNOTATION).and_then(|value| match value.parse() {
+        Ok(version) => Some(version),
+        Err(e) => {
+            warn!("Ignoring {} on ingress {}: {}", UPSTREAM_PROXY_PROTOCOL_ANNOTATION, ingress_key(ingress), e);
+            None
+        }
+    });
+
+    let serverless = ingress.annotations().get(SERVERLESS_ANNOTATION).map(|deployment| ServerlessTarget {
+        namespace: namespace.clone(),
+        deployment: deployment.clone(),
+        idle_timeout: ingress.annotations()
+            .get(SERVERLESS_IDLE_TIMEOUT_ANNOTATION)
+            .and_then(|seconds| match seconds.parse() {
+                Ok(seconds) => Some(Duration::from_secs(seconds)),
+                Err(e) => {
+                    warn!("Ignoring {} on ingress {}: {}", SERVERLESS_IDLE_TIMEOUT_ANNOTATION, ingress_key(ingress), e);
+                    None
+                }
+            }),
+    });
+
+    // routing an ingress without the tls policy it asks for could skip client authentication, so it isn't routed
+    let tls_policy = match TlsPolicy::from_annotations(ingress.annotations(), &namespace) {
+        Ok(tls_policy) => tls_policy,
+        Err(e) => {
+            warn!("Not routing ingress {}, invalid tls policy: {}", ingress_key(ingress), e);
+            routes.rejected.push(format!("invalid tls policy: {}", e));
+            return routes;
+        }
+    };
+
+    // hosts listed in spec.tls are served with the certificates from the named secrets
+    let mut tls_secrets: HashMap<&String, Vec<SecretRef>> = HashMap::new();
+    for tls in ingress.spec.as_ref().and_then(|spec| spec.tls.as_ref()).into_iter().flatten() {
+        if let (Some(hosts), Some(secret_name)) = (&tls.hosts, &tls.secret_name) {
+            for host in hosts {
+                tls_secrets.entry(host).or_default().push(SecretRef { namespace: namespace.clone(), name: secret_name.clone() });
+            }
         }
     }
 
-    pub async fn get_backend(&self, host: &str, path: &str) -> Result<String, IngressLoadBalancerError> {
-        let backends_for_host = self.backends_by_host.read().await;
+    for rule in rules {
+        let host = match &rule.host {
+            Some(host) => host,
+            None => {
+                routes.rejected.push("rules without a host are not supported".to_string());
+                continue;
+            }
+        };
+
+        let paths = match &rule.http {
+            Some(http) => &http.paths,
+            None => {
+                routes.rejected.push(format!("{}: rules without http paths are not supported", host));
+                continue;
+            }
+        };
+
+        for path in paths {
+            let path_prefix = path.path.as_deref().unwrap_or_default();
+            let mut reject = |reason: String| routes.rejected.push(format!("{}{}: {}", host, path_prefix, reason));
+
+            let service = match &path.backend.service {
+                Some(service) => service,
+                None => {
+                    reject("only service backends are supported".to_string());
+                    continue;
+                }
+            };
 
-        if let Some(backends_for_host) = backends_for_host.get(host) {
-            for backend in backends_for_host {
-                if backend.matches(path) {
-                    return Ok(backend.service_name.clone());
+            let port = match service.port.as_ref().map(|port| (port.number, &port.name)) {
+                Some((Some(number), _)) => number,
+                Some((None, Some(name))) => {
+                    reject(format!("port {:?} of service {} is named, only port numbers are supported", name, service.name));
+                    continue;
+                }
+                _ => {
+                    reject(format!("missing port of service {}", service.name));
+                    continue;
+                }
+            };
+
+            match path.path_type.as_deref() {
+                Some("Prefix") if !path_prefix.is_empty() => {}
+                Some("Prefix") => {
+                    reject("missing path".to_string());
+                    continue;
+                }
+                Some(other) => {
+                    reject(format!("unsupported pathType {}, only Prefix is supported", other));
+                    continue;
+                }
+                None => {
+                    reject("missing pathType".to_string());
+                    continue;
                 }
             }
-        }
 
-        Err(IngressLoadBalancerError::general(Code::NonExistentHost, format!("No backend found for host: {}", host)))
+            routes.backends.push(Backend::with_prefix(
+                host.to_string(),
+                path_prefix.to_string(),
+                service_host(&service.name, &namespace, cluster_domain),
+                port as u16)
+                .with_tls_passthrough(tls_passthrough)
+                .with_proxy_protocol(proxy_protocol)
+                .with_serverless(serverless.clone())
+                .with_tls_secrets(tls_secrets.get(host).cloned().unwrap_or_default())
+                .with_tls_policy(tls_policy.clone()));
+        }
     }
+
+    routes
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Backend {
-    host: String,
+    pub host: String,
     path_regex: RegexWrapper,
-    service_name: String,
-    port: u16
+    pub service_name: String,
+    pub port: u16,
+    /// tls connections are forwarded without being terminated by the ingress
+    pub tls_passthrough: bool,
+    /// PROXY protocol header to send on every connection to the backend
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// deployment to scale up before forwarding, for backends which scale to zero
+    pub serverless: Option<ServerlessTarget>,
+    /// secrets holding the certificates for the host, when the ingress brings its own in `spec.tls`
+    pub tls_secrets: Vec<SecretRef>,
+    /// the host's certificate is read from files named in the config file
+    pub static_certificate: bool,
+    /// handshake settings for the host, `None` for the defaults
+    pub tls_policy: Option<TlsPolicy>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RegexWrapper(Regex);
 
 impl Eq for RegexWrapper {}
@@ -188,18 +434,114 @@ impl core::hash::Hash for RegexWrapper {
 }
 
 impl Backend {
-    fn with_prefix(host: String, path_prefix: String, service_name: String, port: u16) -> Backend {
+    pub fn with_prefix(host: String, path_prefix: String, service_name: String, port: u16) -> Backend {
         let path_regex = Regex::new(&format!("^{}", regex::escape(&path_prefix))).expect("Expected a valid regex");
 
         Backend {
             host,
             path_regex: RegexWrapper(path_regex),
             service_name,
-            port
+            port,
+            tls_passthrough: false,
+            proxy_protocol: None,
+            serverless: None,
+            tls_secrets: Vec::new(),
+            static_certificate: false,
+            tls_policy: None,
         }
     }
 
+    pub fn with_proxy_protocol(mut self, proxy_protocol: Option<ProxyProtocolVersion>) -> Backend {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// `address:port` to connect to
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.service_name, self.port)
+    }
+
+    pub fn with_tls_passthrough(mut self, tls_passthrough: bool) -> Backend {
+        self.tls_passthrough = tls_passthrough;
+        self
+    }
+
+    pub fn with_serverless(mut self, serverless: Option<ServerlessTarget>) -> Backend {
+        self.serverless = serverless;
+        self
+    }
+
+    pub fn with_tls_secrets(mut self, tls_secrets: Vec<SecretRef>) -> Backend {
+        self.tls_secrets = tls_secrets;
+        self
+    }
+
+    pub fn with_static_certificate(mut self, static_certificate: bool) -> Backend {
+        self.static_certificate = static_certificate;
+        self
+    }
+
+    pub fn with_tls_policy(mut self, tls_policy: Option<TlsPolicy>) -> Backend {
+        self.tls_policy = tls_policy;
+        self
+    }
+
+    /// Whether clients of the host have to present a certificate.
+    pub fn requires_client_certificate(&self) -> bool {
+        self.tls_policy.as_ref().is_some_and(|policy| policy.client_ca.is_some())
+    }
+
     fn matches(&self, path: &str) -> bool {
         self.path_regex.0.is_match(path)
     }
-}
\ No newline at end of file
+
+    pub fn path_pattern(&self) -> &str {
+        self.path_regex.0.as_str()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_to_the_longest_matching_prefix() {
+        let routing_table = RoutingTable::new();
+        routing_table.apply("default/web".to_string(), vec![
+            Backend::with_prefix("a.example.com".to_string(), "/".to_string(), "web.default.svc.cluster.local".to_string(), 80),
+            Backend::with_prefix("a.example.com".to_string(), "/api".to_string(), "api.default.svc.cluster.local".to_string(), 8080),
+        ]).await;
+
+        let backend = routing_table.find_backend("a.example.com", "/api/users").await.unwrap();
+        assert_eq!(backend.address(), "api.default.svc.cluster.local:8080");
+        let backend = routing_table.find_backend("a.example.com", "/index.html").await.unwrap();
+        assert_eq!(backend.address(), "web.default.svc.cluster.local:80");
+        assert!(routing_table.find_backend("b.example.com", "/").await.is_err());
+    }
+
+    #[test]
+    fn explains_why_paths_are_not_routed() {
+        let ingress: Ingress = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "web", "namespace": "default" },
+            "spec": { "rules": [
+                { "host": "a.example.com", "http": { "paths": [
+                    { "path": "/", "pathType": "Prefix", "backend": { "service": { "name": "web", "port": { "number": 80 } } } },
+                    { "path": "/exact", "pathType": "Exact", "backend": { "service": { "name": "web", "port": { "number": 80 } } } },
+                    { "path": "/named", "pathType": "Prefix", "backend": { "service": { "name": "web", "port": { "name": "http" } } } },
+                    { "path": "/portless", "pathType": "Prefix", "backend": { "service": { "name": "web" } } },
+                ] } },
+                { "http": { "paths": [] } },
+            ] },
+        })).unwrap();
+
+        let routes = routes_from_ingress(&ingress, "cluster.local");
+
+        assert_eq!(routes.backends.len(), 1);
+        assert_eq!(routes.backends[0].address(), "web.default.svc.cluster.local:80");
+        assert_eq!(routes.rejected, vec![
+            "a.example.com/exact: unsupported pathType Exact, only Prefix is supported",
+            "a.example.com/named: port \"http\" of service web is named, only port numbers are supported",
+            "a.example.com/portless: missing port of service web",
+            "rules without a host are not supported",
+        ]);
+    }
+}
@@ -0,0 +1,203 @@
+//! # Leader Election
+//!
+//! Only one ingress replica talks to the CA. Replicas compete for a `coordination.k8s.io` Lease in the ingress'
+//! namespace: the holder renews it every third of the lease duration and issues certificates, everyone else
+//! follows by loading certificates from storage. When the leader dies its lease runs out and another replica takes
+//! over, a leader which shuts down cleanly releases the lease straight away.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::api::{ObjectMeta, PostParams};
+use kube::{Api, Client};
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+pub const LEASE_NAME: &str = "iter-ingress-leader";
+
+pub struct LeaderElection {
+    leases: Api<Lease>,
+    identity: String,
+    lease_duration: Duration,
+    leader: watch::Sender<bool>,
+    /// set once the lease is released, held while the lease is updated so a renewal can't race the release
+    released: Mutex<bool>,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, namespace: &str, identity: String, lease_duration: Duration) -> LeaderElection {
+        LeaderElection {
+            leases: Api::namespaced(client, namespace),
+            identity,
+            lease_duration,
+            leader: watch::channel(false).0,
+            released: Mutex::new(false),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Changes whenever this replica becomes or stops being the leader.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leader.subscribe()
+    }
+
+    /// Acquires and renews the lease until it is [released](LeaderElection::release).
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let released = self.released.lock().await;
+            if *released {
+                return;
+            }
+
+            let leader = match self.try_acquire_or_renew().await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    warn!("leader_election: could not update lease {}: {}", LEASE_NAME, e);
+                    // without a renewal the lease may already belong to someone else
+                    false
+                }
+            };
+
+            if leader != self.is_leader() {
+                match leader {
+                    true => info!("leader_election: {} is now the leader", self.identity),
+                    false => info!("leader_election: {} is no longer the leader", self.identity),
+                }
+                self.leader.send_replace(leader);
+            }
+            drop(released);
+
+            tokio::time::sleep(self.lease_duration / 3).await;
+        }
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool, kube::Error> {
+        let now = Utc::now();
+
+        let existing = match self.leases.get(LEASE_NAME).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta { name: Some(LEASE_NAME.to_string()), ..Default::default() },
+                    spec: Some(self.lease_spec(None, now)),
+                };
+
+                return match self.leases.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    // another replica created it first
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+
+        let spec = existing.spec.clone().unwrap_or_default();
+        if !can_acquire(&spec, &self.identity, now) {
+            return Ok(false);
+        }
+
+        let lease = Lease {
+            // the resource version makes the replace fail if another replica got there first
+            metadata: existing.metadata,
+            spec: Some(self.lease_spec(Some(&spec), now)),
+        };
+
+        match self.leases.replace(LEASE_NAME, &PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn lease_spec(&self, previous: Option<&LeaseSpec>, now: DateTime<Utc>) -> LeaseSpec {
+        let renewing = previous.and_then(|spec| spec.holder_identity.as_ref()) == Some(&self.identity);
+        let transitions = previous.and_then(|spec| spec.lease_transitions).unwrap_or(0);
+
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+            acquire_time: match renewing {
+                true => previous.and_then(|spec| spec.acquire_time.clone()),
+                false => Some(MicroTime(now)),
+            },
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(match renewing {
+                true => transitions,
+                false => transitions + 1,
+            }),
+        }
+    }
+
+    /// Stops renewing the lease and gives it up if this replica holds it, so another can take over without waiting
+    /// for it to expire.
+    pub async fn release(&self) {
+        let mut released = self.released.lock().await;
+        *released = true;
+        if !self.is_leader() {
+            return;
+        }
+        self.leader.send_replace(false);
+
+        let result: Result<(), kube::Error> = try {
+            let mut lease = self.leases.get(LEASE_NAME).await?;
+            if let Some(spec) = lease.spec.as_mut().filter(|spec| spec.holder_identity.as_ref() == Some(&self.identity)) {
+                spec.holder_identity = None;
+                spec.renew_time = None;
+                self.leases.replace(LEASE_NAME, &PostParams::default(), &lease).await?;
+                info!("leader_election: released lease {}", LEASE_NAME);
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("leader_election: could not release lease {}: {}", LEASE_NAME, e);
+        }
+    }
+}
+
+/// Whether `identity` may hold the lease: it already does, nobody does, or the holder stopped renewing it.
+fn can_acquire(spec: &LeaseSpec, identity: &str, now: DateTime<Utc>) -> bool {
+    let holder = match spec.holder_identity.as_deref() {
+        Some(holder) if !holder.is_empty() => holder,
+        _ => return true,
+    };
+
+    if holder == identity {
+        return true;
+    }
+
+    match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(MicroTime(renewed)), Some(duration)) => *renewed + chrono::Duration::seconds(duration as i64) < now,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held_by(holder: &str, renewed_seconds_ago: i64) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(holder.to_string()),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(Utc::now() - chrono::Duration::seconds(renewed_seconds_ago))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn takes_over_only_expired_leases() {
+        let now = Utc::now();
+
+        assert!(can_acquire(&LeaseSpec::default(), "pod-a", now));
+        assert!(can_acquire(&held_by("pod-a", 5), "pod-a", now));
+        assert!(!can_acquire(&held_by("pod-b", 5), "pod-a", now));
+        assert!(can_acquire(&held_by("pod-b", 20), "pod-a", now));
+    }
+}
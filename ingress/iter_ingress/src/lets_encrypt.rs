@@ -1,290 +1,393 @@
-use crate::certificate_state::{CertificateState, CertKey, Host, CertData, cert_key_from};
+use crate::cert_manager::{CertIssuer, RateLimits, RevocationReason};
+use crate::cert_storage::{CertStorage, StorageData, TlsEntry};
+use crate::certificate_state::{CertKey, Host, KeyType, CertData, cert_key_from, cert_key_to_pem};
 use crate::error::{IngressLoadBalancerError, Code};
-use crate::kube_config_tracker::RoutingTable;
-use k8s_openapi::api::core::v1::Secret;
-use kube::ResourceExt;
-use kube::{api::PostParams, Api, Client};
+use crate::settings::AcmeSettings;
 use iter_letsencrypt::account::{Account, ServesChallenge};
+use iter_letsencrypt::error::LetsEncryptError;
+use iter_letsencrypt::cert::{create_ec_key, create_rsa_key};
 use iter_letsencrypt::directory::{Directory, PRODUCTON, STAGING};
-use serde_json::json;
+use openssl::nid::Nid;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::HashMap};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
 
-const ENV: Environment = Environment::Production;
 pub type SecretCerts = Vec<(Host, CertData)>;
 
-#[allow(dead_code)]
-enum Environment {
+/// The ACME server certificates are issued by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcmeDirectory {
     Production,
     Staging,
+    Custom(String),
 }
 
-impl Environment {
+impl AcmeDirectory {
     fn to_url(&self) -> &str {
         match self {
-            Environment::Production => PRODUCTON,
-            Environment::Staging => STAGING,
+            AcmeDirectory::Production => PRODUCTON,
+            AcmeDirectory::Staging => STAGING,
+            AcmeDirectory::Custom(url) => url,
         }
     }
 
+    /// Let's Encrypt's published limits, other directories only ever answer with `Retry-After`.
+    fn limits(&self) -> RateLimits {
+        let (certificates, failures) = match self {
+            AcmeDirectory::Production => (50, 5),
+            AcmeDirectory::Staging => (30_000, 60),
+            AcmeDirectory::Custom(_) => return RateLimits::default(),
+        };
+        RateLimits {
+            certificates_per_domain: Some((certificates, Duration::from_secs(7 * 24 * 60 * 60))),
+            failures_per_host: Some((failures, Duration::from_secs(60 * 60))),
+        }
+    }
+
+    /// Names the stored account and certificates, so switching directories never mixes them up.
     fn to_name(&self) -> &str {
         match self {
-            Environment::Production => "production",
-            Environment::Staging => "staging",
+            AcmeDirectory::Production => "production",
+            AcmeDirectory::Staging => "staging",
+            AcmeDirectory::Custom(_) => "custom",
         }
     }
 }
 
-pub struct CertGenerator {
-    pub account: Account,
-    pub routing_table: Arc<RoutingTable>,
-    pub kube_api: Client,
-    pub state: Arc<CertificateState>,
+impl FromStr for AcmeDirectory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "production" => Ok(AcmeDirectory::Production),
+            "staging" => Ok(AcmeDirectory::Staging),
+            url if url.starts_with("https://") || url.starts_with("http://") => Ok(AcmeDirectory::Custom(url.to_string())),
+            other => Err(format!("expected production, staging or a directory url, got {:?}", other)),
+        }
+    }
 }
 
+/// Issues certificates with an ACME account, which is only loaded or registered once the first one is ordered.
+pub struct AcmeIssuer<S> {
+    acme: AcmeSettings,
+    storage: CertStorage,
+    /// serves the HTTP-01 challenges of orders
+    server: Arc<S>,
+    account: OnceCell<Account>,
+}
 
-impl CertGenerator {
-    pub async fn create(rt: Arc<RoutingTable>, state: Arc<CertificateState>) -> Arc<Self> {
-        let mut kube_api = Client::try_default()
-            .await
-            .expect("Expected a valid KUBECONFIG environment variable");
-        let account = Self::get_account(&mut kube_api).await;
-        let certs = Self::get_certs(&kube_api).await;
-        *state.certs.write().await = certs;
-
-        Arc::new(Self {
-            state,
-            account,
-            routing_table: rt,
-            kube_api,
-        })
+impl<S> AcmeIssuer<S> {
+    pub fn new(acme: AcmeSettings, storage: CertStorage, server: Arc<S>) -> AcmeIssuer<S> {
+        AcmeIssuer {
+            acme,
+            storage,
+            server,
+            account: OnceCell::new(),
+        }
     }
 
-    /// we want to check if there is an existing account in the kubernetes secrets
+    /// we want to check if there is an existing account in storage
     /// if there is, we want to use that account, otherwise we want to create a new one
-    /// and store it in the kubernetes secrets
-    async fn get_account(kube_api: &Client) -> Account {
-        let secrets: Api<Secret> = Api::namespaced(kube_api.clone(), NAMESPACE);
+    /// and store it
+    async fn get_account(storage: &CertStorage, acme: &AcmeSettings) -> Result<Account, IngressLoadBalancerError> {
+        let invalid = |msg: &str| IngressLoadBalancerError::general(Code::CouldNotGenerateCertificate, format!("stored account is invalid: {}", msg));
 
-        let account_secret = secrets
-            .get(&format!("letsencrypt-account-{}", ENV.to_name()))
-            .await
-            .ok();
+        let account_data = storage
+            .load(&format!("letsencrypt-account-{}", acme.directory.to_name()))
+            .await?;
 
-        let directory = Directory::from_url(ENV.to_url())
+        let directory = Directory::from_url(acme.directory.to_url())
             .await
-            .expect("Could not get letsencrypt directory");
+            .map_err(acme_error)?;
+
+        match account_data {
+            Some(data) => {
+                let email = data.get("email").ok_or_else(|| invalid("no email"))?;
+                let private_key = data.get("private_key").ok_or_else(|| invalid("no private_key"))?;
+                let es_key = data.get("es_key").ok_or_else(|| invalid("no es_key"))?;
 
-        match account_secret {
-            Some(Secret {
-                data: Some(data), ..
-            }) => {
-                let email = &data.get("email").unwrap().0;
-                let private_key = &data.get("private_key").unwrap().0;
-                let es_key = &data.get("es_key").unwrap().0;
-
-                let account = Account::account_from(
-                    directory,
-                    &String::from_utf8_lossy(&email),
-                    &es_key,
-                    &private_key,
-                )
-                .await
-                .unwrap();
-
-                return account;
+                Account::account_from(directory, &String::from_utf8_lossy(email), es_key, private_key)
+                    .await
+                    .map_err(acme_error)
             }
-            _ => {
+            None => {
                 let account = directory
-                    .new_account(" @framework.tools")
-                    .await
-                    .unwrap();
-
-                let private_key = account.private_key.private_key_to_pem_pkcs8().unwrap();
-
-                let data: Secret = serde_json::from_value(json!({
-                    "apiVersion": "v1",
-                    "kind": "Secret",
-                    "metadata": {
-                        "name": format!("letsencrypt-account-{}", ENV.to_name()),
-                        "namespace": NAMESPACE
-                    },
-                    "data": {
-                        "private_key": base64::encode(&private_key),
-                        "email": base64::encode("albert@framework.tools"),
-                        "es_key": base64::encode(&account.es_key)
-                    }
-                }))
-                .unwrap();
-
-                secrets
-                    .create(&PostParams::default(), &data)
+                    .new_account(&acme.email)
                     .await
-                    .expect("Failed to create secret");
+                    .map_err(acme_error)?;
+
+                let private_key = account.private_key.private_key_to_pem_pkcs8()
+                    .map_err(|e| IngressLoadBalancerError::general(Code::CouldNotGenerateCertificate, e.to_string()))?;
+
+                let data: StorageData = [
+                    ("private_key".to_string(), private_key),
+                    ("email".to_string(), acme.email.as_bytes().to_vec()),
+                    ("es_key".to_string(), account.es_key.clone()),
+                ].into_iter().collect();
 
-                return account;
+                storage
+                    .save(&format!("letsencrypt-account-{}", acme.directory.to_name()), data)
+                    .await?;
+
+                Ok(account)
             }
         }
     }
 
-    pub async fn get_certs(kube_api: &Client) -> HashMap<String, CertKey> {
-        let mut map = HashMap::new();
+    /// The account, loaded or registered by the first caller. A failure is returned to every caller waiting for it,
+    /// and the next call tries again.
+    async fn account(&self) -> Result<&Account, IngressLoadBalancerError> {
+        self.account.get_or_try_init(|| Self::get_account(&self.storage, &self.acme)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: ServesChallenge + Send + Sync + 'static> CertIssuer for AcmeIssuer<S> {
+    fn name(&self) -> String {
+        issuer(&self.acme.directory)
+    }
+
+    fn limits(&self) -> RateLimits {
+        self.acme.directory.limits()
+    }
 
-        let secrets: Api<Secret> = Api::namespaced(kube_api.clone(), NAMESPACE);
+    /// Orders a certificate for the host from the CA, for a new key of `key_type`.
+    async fn issue(&self, host: &str, key_type: KeyType) -> Result<CertKey, IngressLoadBalancerError> {
+        let private_key = match key_type {
+            KeyType::Rsa => Ok(create_rsa_key(2048)),
+            KeyType::EcdsaP256 => create_ec_key(Nid::X9_62_PRIME256V1),
+            KeyType::EcdsaP384 => create_ec_key(Nid::SECP384R1),
+        }.map_err(|e| IngressLoadBalancerError::General(Code::CouldNotGenerateCertificate, format!("{:#?}", e).into()))?;
 
-        let certs = secrets
-            .get(&format!("letsencrypt-certs-{}", ENV.to_name()))
+        let cert = self.account().await?
+            .generate_certificate_for_key(&[host.to_string()], &private_key, self.server.clone())
             .await
-            .ok();
+            .map_err(acme_error)?;
 
-        match certs {
-            Some(Secret {
-                data: Some(data), ..
-            }) => {
-                let certs = &data.get("certs").unwrap().0;
+        let certs_vec =
+            rustls_pemfile::certs(&mut Box::new(&cert.certificate_to_pem()[..]))
+            .map_err(|e| IngressLoadBalancerError::General(Code::CouldNotGenerateCertificate, format!("{:#?}", e).into()))?;
 
-                let certs: SecretCerts = serde_json::from_slice(certs).unwrap();
+        cert_key_from(certs_vec, cert.private_key_to_der())
+    }
 
-                for (host, cert_data) in certs {
-                    map.insert(host, cert_key_from(cert_data.certs, cert_data.private_key));
-                }
-            }
-            _ => {}
-        }
+    async fn revoke(&self, cert: &CertKey, reason: RevocationReason) -> Result<(), IngressLoadBalancerError> {
+        let leaf = cert.certs.first().ok_or_else(|| IngressLoadBalancerError::general(Code::InvalidCertificate, "the certificate chain is empty"))?;
 
-        map
+        self.account().await?
+            .revoke_certificate(leaf, reason as u8)
+            .await
+            .map_err(|e| IngressLoadBalancerError::general(Code::CouldNotRevokeCertificate, format!("{:?}", e)))
     }
+}
 
-    pub async fn check_for_new_certificates<S: ServesChallenge> (&self, server: Arc<S>) -> Result<(), IngressLoadBalancerError> {
-        let backends = self.routing_table.backends_by_host.read().await;
-        let mut certs = self.state.certs.write().await;
+/// Rate limits are passed on so the order is retried once the CA allows it, the rest are retried with backoff.
+fn acme_error(e: LetsEncryptError) -> IngressLoadBalancerError {
+    match e {
+        LetsEncryptError::RateLimited { retry_after, detail } => IngressLoadBalancerError::RateLimited(retry_after, detail.into()),
+        LetsEncryptError::CouldNotValidateChallenge(detail) => IngressLoadBalancerError::general(Code::ChallengeFailed, detail),
+        e => IngressLoadBalancerError::General(Code::CouldNotGenerateCertificate, format!("{:#?}", e).into()),
+    }
+}
 
-        for (host, _backend) in backends.iter() {
-            let cert = certs.get(host);
-            if cert.is_none() {
-                let cert = self
-                    .account
-                    .generate_certificate( &[host.to_string()], server.clone())
-                    .await
-                    .map_err(|e| IngressLoadBalancerError::General(Code::CouldNotGenerateCertificate, format!("{:#?}", e).into()))?;
-
-                let certs_vec =
-                    rustls_pemfile::certs(&mut Box::new(&cert.certificate_to_pem()[..]))
-                    .map_err(|e| IngressLoadBalancerError::General(Code::CouldNotGenerateCertificate, format!("{:#?}", e).into()))?;
-                certs.insert(
-                    host.to_string(),
-                    cert_key_from(certs_vec, cert.private_key_to_der()),
-                );
+/// Labels the certificates issued by the directory.
+fn issuer(directory: &AcmeDirectory) -> String {
+    format!("letsencrypt-{}", directory.to_name())
+}
+
+/// Certificates used to be stored together in a single entry, which outgrows the size limit of a secret.
+fn certs_entry(directory: &AcmeDirectory) -> String {
+    format!("letsencrypt-certs-{}", directory.to_name())
+}
+
+/// The readable certificates in the entry, and how many it holds which can't be read.
+fn certs_from(data: &StorageData) -> (HashMap<String, CertKey>, usize) {
+    let certs: SecretCerts = match data.get("certs").map(|certs| serde_json::from_slice(certs)) {
+        Some(Ok(certs)) => certs,
+        Some(Err(e)) => {
+            warn!("lets_encrypt: could not read stored certificates: {}", e);
+            return (HashMap::new(), 1);
+        }
+        None => return (HashMap::new(), 0),
+    };
+
+    let mut unreadable = 0;
+    let certs = certs
+        .into_iter()
+        .filter_map(|(host, cert_data)| match cert_key_from(cert_data.certs, cert_data.private_key) {
+            Ok(cert) => Some((host, cert)),
+            Err(e) => {
+                warn!("lets_encrypt: could not read stored certificate for {}: {}", host, e);
+                unreadable += 1;
+                None
             }
+        })
+        .collect();
+
+    (certs, unreadable)
+}
+
+/// Moves certificates out of the single entry they used to be stored in, into one entry each. The old entry is
+/// only deleted once every certificate in it has been stored, and kept if any of them can't be read.
+pub async fn migrate_legacy_certificates(storage: &CertStorage, directory: &AcmeDirectory) {
+    let data = match storage.load(&certs_entry(directory)).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return,
+        Err(e) => return warn!("lets_encrypt: could not load certificates to migrate: {}", e),
+    };
+
+    // without knowing what's stored, an old certificate could replace a newer one
+    let stored = match storage.list_tls(&issuer(directory)).await {
+        Ok(stored) => stored,
+        Err(e) => return warn!("lets_encrypt: could not list certificates, not migrating {}: {}", certs_entry(directory), e),
+    };
+    let (certs, mut failed) = certs_from(&data);
+    let mut migrated = 0;
+
+    for (host, cert) in certs {
+        // a certificate stored since is newer than the one in the old entry
+        if stored.iter().any(|entry| entry.host == host && entry.key_type == cert.key_type) {
+            continue;
         }
 
-        let secrets: Api<Secret> = Api::namespaced(self.kube_api.clone(), NAMESPACE);
+        let result = match cert_key_to_pem(&cert) {
+            Ok((cert_pem, key_pem)) => storage.save_tls(&TlsEntry {
+                host: host.clone(),
+                issuer: issuer(directory),
+                key_type: cert.key_type,
+                cert_pem,
+                key_pem,
+            }).await,
+            Err(e) => Err(e),
+        };
 
-        let certs_secret = secrets
-            .get(&format!("letsencrypt-certs-{}", ENV.to_name()))
-            .await
-            .ok();
-
-        let entries = {
-            let mut entries = Vec::new();
-            for (host, host_cert_key) in certs.iter() {
-                entries.push((
-                    host.clone(),
-                    CertData {
-                        certs: host_cert_key.certs.clone(),
-                        private_key: host_cert_key.private_key.clone(),
-                    },
-                ));
+        match result {
+            Ok(()) => migrated += 1,
+            Err(e) => {
+                warn!("lets_encrypt: could not migrate certificate for {}: {}", host, e);
+                failed += 1;
             }
-            entries
+        }
+    }
+
+    if failed > 0 {
+        return warn!("lets_encrypt: keeping {} until all its certificates are migrated", certs_entry(directory));
+    }
+
+    match storage.delete(&certs_entry(directory)).await {
+        Ok(()) => info!("lets_encrypt: migrated {} certificates out of {}", migrated, certs_entry(directory)),
+        Err(e) => warn!("lets_encrypt: could not delete {}: {}", certs_entry(directory), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_storage::MemoryStore;
+    use crate::local_ca::{LocalCa, LocalCaSettings};
+    use k8s_openapi::api::core::v1::Secret;
+    use kube::{api::PostParams, Api, Client};
+
+    #[tokio::test]
+    async fn migrates_legacy_certificates_and_keeps_unreadable_ones() {
+        let storage: CertStorage = Arc::new(MemoryStore::new());
+        let ca = LocalCa::new(LocalCaSettings { secret: "iter-local-ca".to_string(), cert_days: 7 }, storage.clone());
+        let directory = AcmeDirectory::Staging;
+        let legacy = |certs: SecretCerts| -> StorageData {
+            [("certs".to_string(), serde_json::to_vec(&certs).unwrap())].into_iter().collect()
         };
+        let cert_data = |cert: &CertKey| CertData { private_key: cert.private_key.clone(), certs: cert.certs.clone() };
+
+        let a = ca.issue("a.example.com", KeyType::Rsa).await.unwrap();
+        let b = ca.issue("b.example.com", KeyType::Rsa).await.unwrap();
+        let corrupt = CertData { private_key: b"not a key".to_vec(), certs: Vec::new() };
+
+        // an entry which can't be read in full is kept, the readable certificates are migrated all the same
+        storage.save(&certs_entry(&directory), legacy(vec![
+            ("a.example.com".to_string(), cert_data(&a)),
+            ("c.example.com".to_string(), corrupt),
+        ])).await.unwrap();
+        migrate_legacy_certificates(&storage, &directory).await;
+        assert!(storage.load(&certs_entry(&directory)).await.unwrap().is_some());
+        let stored = storage.list_tls(&issuer(&directory)).await.unwrap();
+        assert_eq!(stored.iter().map(|entry| entry.host.as_str()).collect::<Vec<_>>(), vec!["a.example.com"]);
+
+        storage.save(&certs_entry(&directory), [("certs".to_string(), b"not json".to_vec())].into_iter().collect()).await.unwrap();
+        migrate_legacy_certificates(&storage, &directory).await;
+        assert!(storage.load(&certs_entry(&directory)).await.unwrap().is_some());
+
+        // certificates stored since are newer than the ones in the entry
+        let newer = ca.issue("a.example.com", KeyType::Rsa).await.unwrap();
+        let (cert_pem, key_pem) = cert_key_to_pem(&newer).unwrap();
+        storage.save_tls(&TlsEntry { host: "a.example.com".to_string(), issuer: issuer(&directory), key_type: KeyType::Rsa, cert_pem: cert_pem.clone(), key_pem }).await.unwrap();
+        storage.save(&certs_entry(&directory), legacy(vec![
+            ("a.example.com".to_string(), cert_data(&a)),
+            ("b.example.com".to_string(), cert_data(&b)),
+        ])).await.unwrap();
+        migrate_legacy_certificates(&storage, &directory).await;
+        assert!(storage.load(&certs_entry(&directory)).await.unwrap().is_none());
+
+        let mut stored = storage.list_tls(&issuer(&directory)).await.unwrap();
+        stored.sort_by(|a, b| a.host.cmp(&b.host));
+        assert_eq!(stored.iter().map(|entry| entry.host.as_str()).collect::<Vec<_>>(), vec!["a.example.com", "b.example.com"]);
+        assert_eq!(stored[0].cert_pem, cert_pem);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn can_convert_account_to_secret() {
+        let kube_api = Client::try_default()
+            .await
+            .expect("Expected a valid KUBECONFIG environment variable");
+
+        let secrets: Api<Secret> = Api::namespaced(kube_api.clone(), crate::settings::DEFAULT_NAMESPACE);
+
+        let directory = Directory::from_url(AcmeDirectory::Production.to_url())
+            .await
+            .expect("Could not get letsencrypt directory");
 
+        let account = directory
+            .new_account("albert@framework.tools")
+            .await
+            .unwrap();
 
-        let mut data: Secret = serde_json::from_value(json!({
+        let private_key = account.private_key.private_key_to_pem_pkcs8().unwrap();
+
+        let data: Secret = serde_json::from_value(serde_json::json!({
             "apiVersion": "v1",
             "kind": "Secret",
             "metadata": {
-                "name": format!("letsencrypt-certs-{}", ENV.to_name()),
-                "namespace": NAMESPACE,
+                "name": format!("letsencrypt-account-{}", AcmeDirectory::Production.to_name()),
+                "namespace": crate::settings::DEFAULT_NAMESPACE
             },
             "data": {
-                "certs": base64::encode(json!(entries).to_string()),
+                "private_key": base64::encode(&private_key),
+                "email": base64::encode("albert@framework.tools"),
+                "es_key": base64::encode(&account.es_key),
             }
         }))
-        .unwrap();
-
-        match certs_secret {
-            Some(secret) => {
-                // first we need to get the data and get revision so we can replace
-                data.metadata.resource_version = secret.resource_version();
-                secrets
-                    .replace(
-                        &format!("letsencrypt-certs-{}", ENV.to_name()),
-                        &PostParams::default(),
-                        &data,
-                    )
-                    .await
-                    .expect("Failed to replace secret");
-            }
-            None => {
-                secrets
-                    .create(&PostParams::default(), &data)
-                    .await
-                    .expect("Failed to create secret");
-            }
-        }
+        .expect("Err creating secret");
 
-        Ok(())
+        secrets
+            .create(&PostParams::default(), &data)
+            .await
+            .expect("Failed to create secret");
     }
-}
-
-#[ignore]
-#[tokio::test]
-async fn can_convert_account_to_secret() {
-    let kube_api = Client::try_default()
-        .await
-        .expect("Expected a valid KUBECONFIG environment variable");
-
-    let secrets: Api<Secret> = Api::namespaced(kube_api.clone(), NAMESPACE);
-
-    let directory = Directory::from_url(ENV.to_url())
-        .await
-        .expect("Could not get letsencrypt directory");
-
-    let account = directory
-        .new_account("albert@framework.tools")
-        .await
-        .unwrap();
-
-    let private_key = account.private_key.private_key_to_pem_pkcs8().unwrap();
-
-    let data: Secret = serde_json::from_value(json!({
-        "apiVersion": "v1",
-        "kind": "Secret",
-        "metadata": {
-            "name": format!("letsencrypt-account-{}", ENV.to_name()),
-            "namespace": NAMESPACE
-        },
-        "data": {
-            "private_key": base64::encode(&private_key),
-            "email": base64::encode("albert@framework.tools"),
-            "es_key": base64::encode(&account.es_key),
-        }
-    }))
-    .expect("Err creating secret");
-
-    secrets
-        .create(&PostParams::default(), &data)
-        .await
-        .expect("Failed to create secret");
-}
 
-#[ignore]
-#[tokio::test]
-async fn can_convert_account_to_secret_2() {
-    let kube_api = Client::try_default()
-        .await
-        .expect("Expected a valid KUBECONFIG environment variable");
+    #[ignore]
+    #[tokio::test]
+    async fn can_convert_account_to_secret_2() {
+        let kube_api = Client::try_default()
+            .await
+            .expect("Expected a valid KUBECONFIG environment variable");
 
-    let _account = CertGenerator::get_account(&kube_api).await;
+        let acme = AcmeSettings {
+            directory: AcmeDirectory::Production,
+            email: "albert@framework.tools".to_string(),
+        };
+        let _account = AcmeIssuer::<crate::certificate_state::CertificateState>::get_account(&(Arc::new(crate::cert_storage::KubernetesStore::new(kube_api, crate::settings::DEFAULT_NAMESPACE.to_string())) as CertStorage), &acme).await.unwrap();
+    }
 }
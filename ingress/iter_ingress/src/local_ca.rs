@@ -0,0 +1,269 @@
+//! # Local CA
+//!
+//! Clusters the CA's validation requests can never reach, like kind, internal networks and air-gapped sites, can't
+//! complete an HTTP-01 challenge. With `local_ca` set, certificates are issued by a private root CA instead of ACME,
+//! behind the same [`CertIssuer`] interface, so every routed host gets a short-lived certificate which is renewed like
+//! any other.
+//!
+//! The root is loaded from the storage entry named by [`LocalCaSettings::secret`], e.g. a secret in the ingress'
+//! namespace or `<name>.json` in `cert_dir`. It holds a PEM encoded `tls.crt` and `tls.key`, so bringing a root of
+//! your own is `kubectl create secret tls iter-local-ca --cert ca.crt --key ca.key -n iter`. Without one, the leader
+//! generates a root when it issues the first certificate and stores it there.
+//!
+//! Clients trust the issued certificates once the root is in their trust store. It's exported by the admin API at
+//! `GET /local-ca.crt`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier,
+};
+use openssl::x509::{X509NameBuilder, X509};
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::cert_manager::CertIssuer;
+use crate::cert_storage::{CertStorage, StorageData};
+use crate::certificate_state::{cert_key_from_pem, CertKey, KeyType};
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::fallback_cert::generate_key;
+
+pub const ROOT_COMMON_NAME: &str = "iter-ingress local CA";
+/// Labels the certificates the local CA issued in storage.
+const ISSUER_NAME: &str = "local-ca";
+const ROOT_DAYS: u32 = 3650;
+const CERT_KEY: &str = "tls.crt";
+const KEY_KEY: &str = "tls.key";
+
+#[derive(Debug, Clone)]
+pub struct LocalCaSettings {
+    /// storage entry holding the root
+    pub secret: String,
+    /// how long issued certificates are valid for
+    pub cert_days: u32,
+}
+
+struct Root {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+pub struct LocalCa {
+    settings: LocalCaSettings,
+    storage: CertStorage,
+    root: OnceCell<Root>,
+}
+
+impl LocalCa {
+    pub fn new(settings: LocalCaSettings, storage: CertStorage) -> LocalCa {
+        LocalCa {
+            settings,
+            storage,
+            root: OnceCell::new(),
+        }
+    }
+
+    /// The PEM encoded root certificate for clients' trust stores, `None` until one has been generated.
+    pub async fn root_pem(&self) -> Option<Vec<u8>> {
+        if let Some(root) = self.root.get() {
+            return root.cert.to_pem().ok();
+        }
+
+        // replicas which aren't the leader never load the root's key, but it's in storage once the leader has
+        let data = self.storage.load(&self.settings.secret).await.ok()??;
+        let cert = X509::from_pem(data.get(CERT_KEY)?).ok()?;
+        cert.to_pem().ok()
+    }
+
+    async fn root(&self) -> Result<&Root, IngressLoadBalancerError> {
+        self.root.get_or_try_init(|| async {
+            let failed = |e: String| IngressLoadBalancerError::general(Code::InvalidCertificate, format!("{}: {}", self.settings.secret, e));
+
+            // only a root which doesn't exist is generated, one which can't be loaded right now may still be there
+            if let Some(data) = self.storage.load(&self.settings.secret).await? {
+                return root_from(&data).map_err(failed);
+            }
+
+            let root = generate_root().map_err(|e| failed(e.to_string()))?;
+            let data: StorageData = [
+                (CERT_KEY.to_string(), root.cert.to_pem().map_err(|e| failed(e.to_string()))?),
+                (KEY_KEY.to_string(), root.key.private_key_to_pem_pkcs8().map_err(|e| failed(e.to_string()))?),
+            ].into_iter().collect();
+            self.storage.save(&self.settings.secret, data).await?;
+
+            info!("local_ca: generated a root CA and stored it in {}", self.settings.secret);
+            Ok(root)
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl CertIssuer for LocalCa {
+    fn name(&self) -> String {
+        ISSUER_NAME.to_string()
+    }
+
+    async fn issue(&self, host: &str, key_type: KeyType) -> Result<CertKey, IngressLoadBalancerError> {
+        let root = self.root().await?;
+        issue_leaf(root, host, key_type, self.settings.cert_days)
+    }
+}
+
+fn root_from(data: &StorageData) -> Result<Root, String> {
+    let cert = data.get(CERT_KEY).ok_or_else(|| format!("{} is missing", CERT_KEY))?;
+    let key = data.get(KEY_KEY).ok_or_else(|| format!("{} is missing", KEY_KEY))?;
+
+    let cert = X509::from_pem(cert).map_err(|e| format!("could not read {}: {}", CERT_KEY, e))?;
+    let key = PKey::private_key_from_pem(key).map_err(|e| format!("could not read {}: {}", KEY_KEY, e))?;
+
+    let matches = cert.public_key().map(|public_key| public_key.public_eq(&key)).unwrap_or(false);
+    if !matches {
+        return Err(format!("{} is not the key of {}", KEY_KEY, CERT_KEY));
+    }
+
+    Ok(Root { cert, key })
+}
+
+fn random_serial() -> Result<openssl::asn1::Asn1Integer, openssl::error::ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+fn generate_root() -> Result<Root, openssl::error::ErrorStack> {
+    let key = generate_key(KeyType::EcdsaP256)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", ROOT_COMMON_NAME)?;
+    let name = name.build();
+
+    let serial = random_serial()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(ROOT_DAYS)?;
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    cert.append_extension(BasicConstraints::new().critical().ca().pathlen(0).build()?)?;
+    cert.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+    let subject_key_id = SubjectKeyIdentifier::new().build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(subject_key_id)?;
+    cert.sign(&key, MessageDigest::sha256())?;
+
+    Ok(Root { cert: cert.build(), key })
+}
+
+/// A certificate for `host` signed by the root, valid for `days`.
+fn issue_leaf(root: &Root, host: &str, key_type: KeyType, days: u32) -> Result<CertKey, IngressLoadBalancerError> {
+    let failed = |e: openssl::error::ErrorStack| IngressLoadBalancerError::general(Code::CouldNotGenerateCertificate, e.to_string());
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or_default();
+
+    let leaf: Result<(X509, PKey<Private>), openssl::error::ErrorStack> = try {
+        let key = generate_key(key_type)?;
+
+        // a common name is at most 64 characters, longer hosts are only named by the subject alt name
+        let mut name = X509NameBuilder::new()?;
+        if host.len() <= 64 {
+            name.append_entry_by_text("CN", host)?;
+        }
+        let name = name.build();
+
+        let serial = random_serial()?;
+        // an hour of leeway for clients whose clocks are behind
+        let not_before = Asn1Time::from_unix(now - 3600)?;
+        let not_after = Asn1Time::days_from_now(days)?;
+
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_serial_number(&serial)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(root.cert.subject_name())?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&not_before)?;
+        cert.set_not_after(&not_after)?;
+        cert.append_extension(BasicConstraints::new().critical().build()?)?;
+        let key_usage = match key_type {
+            KeyType::Rsa => KeyUsage::new().critical().digital_signature().key_encipherment().build()?,
+            _ => KeyUsage::new().critical().digital_signature().build()?,
+        };
+        cert.append_extension(key_usage)?;
+        cert.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+        let subject_alt_name = SubjectAlternativeName::new().dns(host).build(&cert.x509v3_context(Some(&root.cert), None))?;
+        cert.append_extension(subject_alt_name)?;
+        let authority_key_id = AuthorityKeyIdentifier::new().keyid(false).build(&cert.x509v3_context(Some(&root.cert), None))?;
+        cert.append_extension(authority_key_id)?;
+        cert.sign(&root.key, MessageDigest::sha256())?;
+
+        (cert.build(), key)
+    };
+    let (leaf, key) = leaf.map_err(failed)?;
+
+    cert_key_from_pem(&leaf.to_pem().map_err(failed)?, &key.private_key_to_pem_pkcs8().map_err(failed)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_storage::MemoryStore;
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::X509StoreContext;
+
+    #[tokio::test]
+    async fn issues_certificates_clients_trusting_the_root_accept() {
+        let storage: CertStorage = std::sync::Arc::new(MemoryStore::new());
+        let settings = LocalCaSettings { secret: "iter-local-ca".to_string(), cert_days: 7 };
+        let ca = LocalCa::new(settings.clone(), storage.clone());
+
+        assert!(ca.root_pem().await.is_none());
+        let cert = ca.issue("app.internal", KeyType::EcdsaP256).await.unwrap();
+        assert_eq!(cert.info().unwrap().subject_alt_names, vec!["app.internal"]);
+        // a second may pass between issuing and checking, which rounds the days left down
+        assert!((6..=7).contains(&cert.info().unwrap().valid_days_left));
+
+        // another replica loads the root the first one generated
+        let root = X509::from_pem(&LocalCa::new(settings, storage).root_pem().await.unwrap()).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(root).unwrap();
+        let store = store.build();
+
+        let leaf = X509::from_der(&cert.certs[0]).unwrap();
+        let mut context = X509StoreContext::new().unwrap();
+        let (verified, error) = context.init(&store, &leaf, &Stack::new().unwrap(), |context| {
+            Ok((context.verify_cert()?, context.error()))
+        }).unwrap();
+        assert!(verified, "{}", error);
+    }
+
+    #[test]
+    fn refuses_a_root_whose_key_does_not_match() {
+        let root = generate_root().unwrap();
+        let other = generate_root().unwrap();
+        let data: StorageData = [
+            (CERT_KEY.to_string(), root.cert.to_pem().unwrap()),
+            (KEY_KEY.to_string(), other.key.private_key_to_pem_pkcs8().unwrap()),
+        ].into_iter().collect();
+
+        assert!(root_from(&data).is_err());
+    }
+
+    #[test]
+    fn issues_certificates_for_hosts_too_long_for_a_common_name() {
+        let root = generate_root().unwrap();
+        let host = format!("{}.internal", "a".repeat(61));
+        assert_eq!(host.len(), 70);
+
+        let cert = issue_leaf(&root, &host, KeyType::EcdsaP256, 7).unwrap();
+        assert_eq!(cert.info().unwrap().subject_alt_names, vec![host]);
+    }
+}
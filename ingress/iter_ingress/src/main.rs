@@ -4,9 +4,9 @@
 //!
 //! ## How it works
 //! 1. A task which regularly queries the kubernetes api for the list of services, ingresses, and listens for changes.
-//! 2. letsencrypt is used to generate certificates for the hosts configured in the ingress.
+//! 2. letsencrypt, or a [local CA](local_ca) where it can't reach the cluster, is used to generate certificates for the hosts configured in the ingress.
 //! 3. Constructs a routing rable based on the loaded kubernetes ingress configurations.
-//! 4. listen on :80 and :443 for incoming http and https requests. The requests are routed to the appropriate service
+//! 4. listen on :80 and :443 (or the addresses in the [settings](settings)) for incoming http and https requests. The requests are routed to the appropriate service
 //! according to the routing table, and a reverse proxy is used to forward the request to the service.
 //! 5. incoming https requests are matched against the appropriate SSL certificate generated by letsencrypt.
 //!
@@ -16,10 +16,31 @@ use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 use kube_config_tracker::{RoutingTable};
-use proxy::proxy_request;
-use std::net::SocketAddr;
+use proxy::{proxy_request, ProxyState};
+use telemetry::{TelemetryConfig, Tracer};
+use admin::AdminApi;
+use metrics::Metrics;
+use shutdown::Shutdown;
+use settings::Settings;
+use stream_proxy::StreamProxy;
 use std::sync::Arc;
-use iter_tls_acceptor::tls_acceptor::TlsAcceptor;
+use iter_tls_acceptor::proxy_protocol::{ClientAcceptor, ClientStream};
+use iter_tls_acceptor::tls_acceptor::{HandlesPassthrough, TlsAcceptor, TlsAcceptorOptions, TlsConn};
+use forwarding::ClientInfo;
+use passthrough::TlsPassthrough;
+use serverless::{KubernetesScaler, Serverless};
+use acme_challenges::ClusterChallenges;
+use cert_manager::{CertIssuer, CertManager};
+use user_certs::UserCertificates;
+use leader_election::LeaderElection;
+use ocsp::OcspStapler;
+use lets_encrypt::AcmeIssuer;
+use local_ca::LocalCa;
+use tls_policy::TlsPolicies;
+use on_demand::OnDemand;
+use events::IngressEvents;
+use ingress_status::IngressStatus;
+use tracing::{info, warn};
 
 use crate::error::{Code, IngressLoadBalancerError};
 
@@ -28,6 +49,30 @@ mod error;
 mod kube_config_tracker;
 mod proxy;
 mod certificate_state;
+mod telemetry;
+mod metrics;
+mod admin;
+mod shutdown;
+mod config_source;
+mod file_config;
+mod cert_storage;
+mod stream_proxy;
+mod passthrough;
+mod forwarding;
+mod tunnel;
+mod settings;
+mod serverless;
+mod acme_challenges;
+mod leader_election;
+mod cert_manager;
+mod user_certs;
+mod fallback_cert;
+mod ocsp;
+mod tls_policy;
+mod on_demand;
+mod local_ca;
+mod events;
+mod ingress_status;
 
 //  Components
 //  - Ingress
@@ -45,37 +90,293 @@ type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 #[tokio::main]
 async fn main() -> Result<(), IngressLoadBalancerError> {
+    let settings = Settings::load()?;
+    tracing_subscriber::fmt().with_max_level(settings.log_level).init();
+
     let routing_table = Arc::new(RoutingTable::new());
-    let certificate_state = Arc::new(certificate_state::CertificateState::new());
+    let tracer = Arc::new(Tracer::new(TelemetryConfig::from_env()));
+    let metrics = Arc::new(Metrics::new());
+    let certificate_state = Arc::new(certificate_state::CertificateState::new().with_metrics(metrics.clone()));
+    *certificate_state.fallback.write().await = Some(fallback_cert::self_signed(fallback_cert::FALLBACK_COMMON_NAME, certificate_state::KeyType::Rsa, 365)?);
+    let shutdown = Arc::new(Shutdown::new(settings.shutdown_drain_delay, settings.shutdown_grace_period));
 
-    // start a task which listens for changes to the kubernetes api
+    // start a task which listens for changes to the configuration, kubernetes or a config file,
     // and updates the routing table accordingly
-    tokio::spawn(routing_table.clone().start_watching());
+    tokio::spawn({
+        let config_source = config_source::from_settings(&settings);
+        let (routing_table, certificate_state) = (routing_table.clone(), certificate_state.clone());
+        async move {
+            if let Err(e) = config_source.watch(routing_table, certificate_state).await {
+                warn!("config_source: stopped watching the configuration, routes are no longer updated: {}", e);
+            }
+        }
+    });
+
+    let ocsp = settings.ocsp_stapling.then(|| Arc::new(OcspStapler::new(certificate_state.clone())));
+    if let Some(ocsp) = &ocsp {
+        tokio::spawn(ocsp.clone().run());
+    }
+
+    // tcp and udp listeners are declared in config maps, so they are only available when running in kubernetes
+    let stream_proxy = Arc::new(StreamProxy::new(
+        settings.stream.clone(),
+        settings.namespace.clone(),
+        settings.cluster_domain.clone(),
+        metrics.clone(),
+        shutdown.clone(),
+    ));
+    if settings.stream_proxy && settings.config_file.is_none() {
+        let stream_proxy = stream_proxy.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stream_proxy.watch_config_maps().await {
+                warn!("stream_proxy: could not watch config maps: {}", e);
+            }
+        });
+    }
+
+    let kube_client = match settings.config_file {
+        Some(_) => None,
+        None => match kube::Client::try_default().await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("kubernetes is not available, certificates are only issued and served by this replica: {}", e);
+                None
+            }
+        },
+    };
+
+    // one replica, the leader, issues certificates and reports on ingresses, the others load certificates from storage
+    let leader_election = kube_client.as_ref().map(|client| Arc::new(LeaderElection::new(
+        client.clone(),
+        &settings.namespace,
+        settings.pod_name.clone(),
+        settings.leader_lease_duration,
+    )));
+    if let Some(leader_election) = &leader_election {
+        tokio::spawn(leader_election.clone().run());
+    }
+
+    match (&settings.fallback_cert_secret, &kube_client) {
+        (Some(secret), Some(client)) => {
+            tokio::spawn(fallback_cert::watch_secret(client.clone(), secret.clone(), certificate_state.clone()));
+        }
+        (Some(secret), None) => warn!("fallback_cert: kubernetes is not available to load {} from, serving a self-signed certificate", secret),
+        (None, _) => {}
+    }
+
+    // certificates teams bring themselves in their ingresses' spec.tls
+    if let Some(client) = &kube_client {
+        let user_certificates = Arc::new(UserCertificates::new(client.clone(), routing_table.clone(), certificate_state.clone()));
+        tokio::spawn(user_certificates.run());
+    }
+
+    // per host tls settings and client certificate authorities, from annotations or the config file
+    tokio::spawn(Arc::new(TlsPolicies::new(kube_client.clone(), routing_table.clone(), certificate_state.clone())).run());
+
+    let on_demand = settings.on_demand.clone().map(|on_demand_settings| {
+        let cluster = kube_client.as_ref().map(|client| (client.clone(), settings.namespace.clone()));
+        Arc::new(OnDemand::new(on_demand_settings, routing_table.clone(), cluster))
+    });
+    if let Some(on_demand) = &on_demand {
+        tokio::spawn(on_demand.clone().watch());
+        *certificate_state.on_demand.write().await = Some(on_demand.clone());
+    }
+
+    let events = kube_client.as_ref().map(|client| {
+        Arc::new(IngressEvents::new(client.clone(), settings.pod_name.clone(), routing_table.clone()))
+    });
+
+    // the leader reports what it makes of every ingress, in the ingress' status and events
+    if let (Some(client), Some(leader_election), Some(events)) = (&kube_client, &leader_election, &events) {
+        let ingress_status = Arc::new(IngressStatus::new(
+            client.clone(),
+            settings.ingress_status.clone(),
+            settings.cluster_domain.clone(),
+            events.clone(),
+        ));
+        tokio::spawn(ingress_status.run(leader_election.subscribe()));
+    }
+
+    // certificates are issued by ACME or the local CA, never both
+    let storage = match settings.acme.is_some() || settings.local_ca.is_some() {
+        true => Some(cert_storage::from_settings(&settings).await),
+        false => None,
+    };
+    let local_ca = match (&settings.local_ca, &storage) {
+        (Some(local_ca), Some(storage)) => Some(Arc::new(LocalCa::new(local_ca.clone(), storage.clone()))),
+        _ => None,
+    };
+    let issuer: Option<Arc<dyn CertIssuer>> = match (&settings.acme, &local_ca, &storage) {
+        (Some(acme), _, Some(storage)) => {
+            lets_encrypt::migrate_legacy_certificates(storage, &acme.directory).await;
+
+            match &kube_client {
+                Some(client) => {
+                    // acme challenges are published to every replica, as the CA's validation request may reach any of them
+                    let http_port = settings.http_listen.first().map(|addr| addr.port()).unwrap_or(80);
+                    let challenges = Arc::new(ClusterChallenges::new(
+                        client.clone(),
+                        settings.namespace.clone(),
+                        settings.peer_selector.clone(),
+                        http_port,
+                        certificate_state.clone(),
+                    ));
+                    tokio::spawn({
+                        let challenges = challenges.clone();
+                        async move {
+                            if let Err(e) = challenges.watch().await {
+                                warn!("acme_challenges: could not watch challenges: {}", e);
+                            }
+                        }
+                    });
+                    Some(Arc::new(AcmeIssuer::new(acme.clone(), storage.clone(), challenges)))
+                }
+                None => Some(Arc::new(AcmeIssuer::new(acme.clone(), storage.clone(), certificate_state.clone()))),
+            }
+        }
+        (None, Some(local_ca), _) => Some(local_ca.clone()),
+        _ => None,
+    };
+
+    // a single replica outside kubernetes is always the leader
+    let (_always_leader, always_leader) = tokio::sync::watch::channel(true);
+
+    if let (Some(issuer), Some(storage)) = (issuer, storage) {
+        tokio::spawn(cert_manager::follow_certificates(storage.clone(), issuer.name(), certificate_state.clone()));
+
+        let leader = match &leader_election {
+            Some(leader_election) => leader_election.subscribe(),
+            None => always_leader.clone(),
+        };
+
+        let manager = CertManager::new(
+            routing_table.clone(),
+            certificate_state.clone(),
+            storage,
+            issuer,
+            settings.certificates.clone(),
+            metrics.clone(),
+            events.clone(),
+        ).await;
+        tokio::spawn(manager.run(leader));
+    }
+
+    let admin_api = Arc::new(AdminApi {
+        routing_table: routing_table.clone(),
+        cert_state: certificate_state.clone(),
+        metrics: metrics.clone(),
+        local_ca,
+        ocsp,
+    });
+    let admin_config = settings.admin.clone();
+    tokio::spawn(async move {
+        if let Err(e) = admin_api.serve(admin_config).await {
+            warn!("admin: server error: {}", e);
+        }
+    });
+
+    let tls_passthrough: Option<Arc<dyn HandlesPassthrough + Send + Sync>> = match settings.tls_passthrough {
+        true => Some(Arc::new(TlsPassthrough {
+            routing_table: routing_table.clone(),
+            metrics: metrics.clone(),
+            idle_timeout: settings.stream.idle_timeout,
+        })),
+        false => None,
+    };
+
+    // scale-to-zero deployments are woken up by requests and scaled down again once idle
+    let serverless = Arc::new(Serverless::new(settings.serverless.clone(), Arc::new(KubernetesScaler::default())));
+    let leader = match &leader_election {
+        Some(leader_election) => leader_election.subscribe(),
+        None => always_leader.clone(),
+    };
+    tokio::spawn(serverless.clone().run(routing_table.clone(), leader));
+
+    let proxy_state = Arc::new(ProxyState {
+        routing_table,
+        cert_state: certificate_state.clone(),
+        tracer,
+        metrics: metrics.clone(),
+        shutdown: shutdown.clone(),
+        tunnel: settings.tunnel.clone(),
+        serverless,
+    });
 
-    let cert_state = certificate_state.clone();
-    let proxy_service_handler = Arc::new(move || {
-        let routing_table = routing_table.clone();
-        let cert_state = cert_state.clone();
+    let proxy_service_handler = Arc::new(move |client: ClientInfo| {
+        let proxy_state = proxy_state.clone();
+        // the guard lives as long as the connection's service, so it is dropped when the connection closes
+        let connection = proxy_state.metrics.track_connection();
         async move {
             Ok::<_, Error>(service_fn(move |req| {
-                proxy_request(routing_table.clone(), req, cert_state.clone())
+                let _connection = &connection;
+                proxy_request(proxy_state.clone(), client.clone(), req)
             }))
         }
     });
 
-    let proxy_service_handler_clone = proxy_service_handler.clone();
-    let proxy_service_http = make_service_fn(move |_| proxy_service_handler_clone.clone()());
-    let proxy_service_https = make_service_fn(move |_| proxy_service_handler.clone()());
+    // load balancers in front of the ingress may announce the real client address with the PROXY protocol
+    let proxy_protocol = Arc::new(settings.proxy_protocol.clone());
+
+    let mut server_tasks = Vec::new();
 
-    let https_incoming = AddrIncoming::bind(&SocketAddr::from(([0, 0, 0, 0], 443))).unwrap();
-    let incoming_tls_acceptor = TlsAcceptor::new(https_incoming, certificate_state.clone());
-    let http_server_task = tokio::task::spawn(Server::bind(&SocketAddr::from(([0, 0, 0, 0], 80))).serve(proxy_service_http));
-    let https_server_task = tokio::task::spawn(Server::builder(incoming_tls_acceptor).serve(proxy_service_https));
+    for addr in &settings.http_listen {
+        let incoming = AddrIncoming::bind(addr)
+            .map_err(|e| IngressLoadBalancerError::general(Code::InvalidConfig, format!("could not listen on {}: {}", addr, e)))?;
+        let handler = proxy_service_handler.clone();
+        let service = make_service_fn(move |conn: &ClientStream| handler.clone()(ClientInfo::from_stream(conn, false)));
+
+        info!("listening for http on {}", addr);
+        server_tasks.push(tokio::task::spawn(Server::builder(ClientAcceptor::new(incoming, proxy_protocol.clone()))
+            .serve(service)
+            .with_graceful_shutdown(shutdown.clone().listeners_closed())));
+    }
+
+    for addr in &settings.https_listen {
+        let incoming = AddrIncoming::bind(addr)
+            .map_err(|e| IngressLoadBalancerError::general(Code::InvalidConfig, format!("could not listen on {}: {}", addr, e)))?;
+        let acceptor = TlsAcceptor::with_options(incoming, certificate_state.clone(), TlsAcceptorOptions {
+            passthrough: tls_passthrough.clone(),
+            proxy_protocol: proxy_protocol.clone(),
+        });
+        let handler = proxy_service_handler.clone();
+        let service = make_service_fn(move |conn: &TlsConn| handler.clone()(ClientInfo::from_tls(conn)));
+
+        info!("listening for https on {}", addr);
+        server_tasks.push(tokio::task::spawn(Server::builder(acceptor)
+            .serve(service)
+            .with_graceful_shutdown(shutdown.clone().listeners_closed())));
+    }
+
+    // resolves once every server has stopped accepting and its connections have finished
+    let servers = async {
+        futures::future::try_join_all(server_tasks.into_iter().map(|task| async { task.await.unwrap() }))
+            .await
+            .map_err(IngressLoadBalancerError::HyperError)
+    };
+    tokio::pin!(servers);
 
     tokio::select! {
-        http_server = http_server_task => http_server.unwrap().unwrap(),
-        https_server = https_server_task => https_server.unwrap().unwrap(),
+        result = &mut servers => return result.map(|_| ()),
+        _ = shutdown.drain_on_signal() => {},
+    }
+
+    // hand over certificate issuance straight away instead of letting the lease run out
+    if let Some(leader_election) = &leader_election {
+        leader_election.release().await;
+    }
+
+    let drained = tokio::time::timeout(shutdown.grace_period, async {
+        servers.await?;
+        metrics.tunnels_closed().await;
+        stream_proxy.connections_closed().await;
+        Ok::<_, IngressLoadBalancerError>(())
+    }).await;
+
+    match drained {
+        Ok(result) => result?,
+        Err(_) => info!("shutdown: grace period elapsed, closing remaining connections"),
     }
 
+    info!("shutdown: complete");
     Ok(())
 }
@@ -0,0 +1,242 @@
+//! # Metrics
+//!
+//! Counters shared between the listeners, the proxy and the admin api.
+//!
+//! Metrics are exposed in the prometheus text format on the admin listener under `/metrics`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::RwLock;
+
+/// An upstream is reported as unhealthy after this many requests to it have failed in a row.
+const UNHEALTHY_AFTER_CONSECUTIVE_FAILURES: u64 = 3;
+
+pub struct Metrics {
+    pub active_connections: AtomicI64,
+    pub active_tunnels: AtomicI64,
+    pub requests_total: AtomicU64,
+    pub upstreams: RwLock<HashMap<String, UpstreamHealth>>,
+    pub stream_listeners: RwLock<HashMap<String, Arc<StreamListenerMetrics>>>,
+    /// handshakes completed with the fallback certificate because the client sent no sni
+    pub tls_fallback_no_sni_total: AtomicU64,
+    /// handshakes completed with the fallback certificate because there is no certificate for the host
+    pub tls_fallback_unknown_host_total: AtomicU64,
+    /// hosts with issued certificates which no longer route, waiting out the grace period
+    pub orphaned_certificate_hosts: AtomicI64,
+    pub certificates_archived_total: AtomicU64,
+    pub certificates_deleted_total: AtomicU64,
+    pub certificates_revoked_total: AtomicU64,
+    /// failed certificate orders by reason, e.g. `RateLimited`
+    pub certificate_order_failures: RwLock<HashMap<String, u64>>,
+    /// certificates whose orders wait out a backoff or rate limit
+    pub certificates_backing_off: AtomicI64,
+}
+
+/// Why a handshake was completed with the fallback certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsFallback {
+    NoSni,
+    UnknownHost,
+}
+
+/// Metrics of a single tcp or udp listener, named like `tcp/5432`.
+#[derive(Debug, Default)]
+pub struct StreamListenerMetrics {
+    /// open tcp connections or live udp sessions
+    pub active: AtomicI64,
+    pub accepted_total: AtomicU64,
+    /// connections or sessions refused because the listener was at its limit
+    pub rejected_total: AtomicU64,
+    pub upstream_errors_total: AtomicU64,
+    pub idle_timeouts_total: AtomicU64,
+    /// bytes from clients to the upstream
+    pub received_bytes_total: AtomicU64,
+    /// bytes from the upstream to clients
+    pub sent_bytes_total: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHealth {
+    pub requests: u64,
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+}
+
+impl UpstreamHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures < UNHEALTHY_AFTER_CONSECUTIVE_FAILURES
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            active_connections: AtomicI64::new(0),
+            active_tunnels: AtomicI64::new(0),
+            requests_total: AtomicU64::new(0),
+            upstreams: RwLock::new(HashMap::new()),
+            stream_listeners: RwLock::new(HashMap::new()),
+            tls_fallback_no_sni_total: AtomicU64::new(0),
+            tls_fallback_unknown_host_total: AtomicU64::new(0),
+            orphaned_certificate_hosts: AtomicI64::new(0),
+            certificates_archived_total: AtomicU64::new(0),
+            certificates_deleted_total: AtomicU64::new(0),
+            certificates_revoked_total: AtomicU64::new(0),
+            certificate_order_failures: RwLock::new(HashMap::new()),
+            certificates_backing_off: AtomicI64::new(0),
+        }
+    }
+
+    /// Tracks a connection for as long as the returned guard is alive.
+    pub fn track_connection(self: &Arc<Self>) -> GaugeGuard {
+        GaugeGuard::new(self.clone(), |metrics| &metrics.active_connections)
+    }
+
+    /// Tracks an upgraded connection, which hyper no longer knows about, for as long as the returned guard is alive.
+    pub fn track_tunnel(self: &Arc<Self>) -> GaugeGuard {
+        GaugeGuard::new(self.clone(), |metrics| &metrics.active_tunnels)
+    }
+
+    /// Resolves once every upgraded connection has closed.
+    pub async fn tunnels_closed(&self) {
+        while self.active_tunnels.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub async fn record_upstream_success(&self, upstream: &str) {
+        let mut upstreams = self.upstreams.write().await;
+        let health = upstreams.entry(upstream.to_string()).or_default();
+        health.requests += 1;
+        health.consecutive_failures = 0;
+        health.last_success = Some(SystemTime::now());
+    }
+
+    pub async fn record_upstream_failure<E: std::fmt::Display>(&self, upstream: &str, error: E) {
+        let mut upstreams = self.upstreams.write().await;
+        let health = upstreams.entry(upstream.to_string()).or_default();
+        health.requests += 1;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+        health.last_failure = Some(SystemTime::now());
+    }
+
+    /// Metrics for the named stream listener, created on first use and kept across listener restarts.
+    pub async fn stream_listener(&self, listener: &str) -> Arc<StreamListenerMetrics> {
+        self.stream_listeners
+            .write()
+            .await
+            .entry(listener.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn record_tls_fallback(&self, reason: TlsFallback) {
+        let counter = match reason {
+            TlsFallback::NoSni => &self.tls_fallback_no_sni_total,
+            TlsFallback::UnknownHost => &self.tls_fallback_unknown_host_total,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn record_certificate_failure(&self, reason: &str) {
+        *self.certificate_order_failures.write().await.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// Forgets everything recorded about upstreams.
+    pub async fn purge_upstreams(&self) {
+        self.upstreams.write().await.clear();
+    }
+
+    /// Renders all metrics in the prometheus text exposition format.
+    pub async fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# TYPE iter_ingress_active_connections gauge");
+        let _ = writeln!(out, "iter_ingress_active_connections {}", self.active_connections.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE iter_ingress_active_tunnels gauge");
+        let _ = writeln!(out, "iter_ingress_active_tunnels {}", self.active_tunnels.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE iter_ingress_requests_total counter");
+        let _ = writeln!(out, "iter_ingress_requests_total {}", self.requests_total.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# TYPE iter_ingress_tls_fallback_total counter");
+        let _ = writeln!(out, "iter_ingress_tls_fallback_total{{reason=\"no_sni\"}} {}", self.tls_fallback_no_sni_total.load(Ordering::Relaxed));
+        let _ = writeln!(out, "iter_ingress_tls_fallback_total{{reason=\"unknown_host\"}} {}", self.tls_fallback_unknown_host_total.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# TYPE iter_ingress_orphaned_certificate_hosts gauge");
+        let _ = writeln!(out, "iter_ingress_orphaned_certificate_hosts {}", self.orphaned_certificate_hosts.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE iter_ingress_certificates_removed_total counter");
+        let _ = writeln!(out, "iter_ingress_certificates_removed_total{{action=\"archive\"}} {}", self.certificates_archived_total.load(Ordering::Relaxed));
+        let _ = writeln!(out, "iter_ingress_certificates_removed_total{{action=\"delete\"}} {}", self.certificates_deleted_total.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE iter_ingress_certificates_revoked_total counter");
+        let _ = writeln!(out, "iter_ingress_certificates_revoked_total {}", self.certificates_revoked_total.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# TYPE iter_ingress_certificate_order_failures_total counter");
+        for (reason, failures) in self.certificate_order_failures.read().await.iter() {
+            let _ = writeln!(out, "iter_ingress_certificate_order_failures_total{{reason=\"{}\"}} {}", reason, failures);
+        }
+        let _ = writeln!(out, "# TYPE iter_ingress_certificates_backing_off gauge");
+        let _ = writeln!(out, "iter_ingress_certificates_backing_off {}", self.certificates_backing_off.load(Ordering::Relaxed));
+
+        let upstreams = self.upstreams.read().await;
+        let _ = writeln!(out, "# TYPE iter_ingress_upstream_requests_total counter");
+        for (upstream, health) in upstreams.iter() {
+            let _ = writeln!(out, "iter_ingress_upstream_requests_total{{upstream=\"{}\"}} {}", upstream, health.requests);
+        }
+        let _ = writeln!(out, "# TYPE iter_ingress_upstream_failures_total counter");
+        for (upstream, health) in upstreams.iter() {
+            let _ = writeln!(out, "iter_ingress_upstream_failures_total{{upstream=\"{}\"}} {}", upstream, health.failures);
+        }
+        let _ = writeln!(out, "# TYPE iter_ingress_upstream_healthy gauge");
+        for (upstream, health) in upstreams.iter() {
+            let _ = writeln!(out, "iter_ingress_upstream_healthy{{upstream=\"{}\"}} {}", upstream, health.is_healthy() as u8);
+        }
+
+        let stream_listeners = self.stream_listeners.read().await;
+        type Read = fn(&StreamListenerMetrics) -> i64;
+        let stream_metrics: [(&str, &str, Read); 7] = [
+            ("iter_ingress_stream_active", "gauge", |m| m.active.load(Ordering::Relaxed)),
+            ("iter_ingress_stream_accepted_total", "counter", |m| m.accepted_total.load(Ordering::Relaxed) as i64),
+            ("iter_ingress_stream_rejected_total", "counter", |m| m.rejected_total.load(Ordering::Relaxed) as i64),
+            ("iter_ingress_stream_upstream_errors_total", "counter", |m| m.upstream_errors_total.load(Ordering::Relaxed) as i64),
+            ("iter_ingress_stream_idle_timeouts_total", "counter", |m| m.idle_timeouts_total.load(Ordering::Relaxed) as i64),
+            ("iter_ingress_stream_received_bytes_total", "counter", |m| m.received_bytes_total.load(Ordering::Relaxed) as i64),
+            ("iter_ingress_stream_sent_bytes_total", "counter", |m| m.sent_bytes_total.load(Ordering::Relaxed) as i64),
+        ];
+        for (name, kind, value) in stream_metrics {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (listener, metrics) in stream_listeners.iter() {
+                let _ = writeln!(out, "{}{{listener=\"{}\"}} {}", name, listener, value(metrics));
+            }
+        }
+
+        out
+    }
+}
+
+/// Increments a gauge when created and decrements it again when dropped.
+pub struct GaugeGuard {
+    metrics: Arc<Metrics>,
+    gauge: fn(&Metrics) -> &AtomicI64,
+}
+
+impl GaugeGuard {
+    fn new(metrics: Arc<Metrics>, gauge: fn(&Metrics) -> &AtomicI64) -> GaugeGuard {
+        gauge(&metrics).fetch_add(1, Ordering::Relaxed);
+        GaugeGuard { metrics, gauge }
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics).fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,431 @@
+//! # OCSP Stapling
+//!
+//! Fetches an OCSP response for every served certificate from the responder named in the certificate's authority
+//! information access extension, and staples it to the handshake, so clients don't have to ask the responder
+//! themselves before the first request.
+//!
+//! Responses are cached per certificate and fetched again halfway through their validity. Stapling fails open: a
+//! responder which can't be reached, or answers with anything but a valid `good` response, leaves the previous
+//! response stapled for as long as it is valid and the certificate served without one after that.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use k8s_openapi::chrono::NaiveDateTime;
+use openssl::asn1::Asn1GeneralizedTimeRef;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, info, warn};
+
+use crate::certificate_state::{CertKey, CertificateState};
+use crate::error::{Code, IngressLoadBalancerError};
+
+/// How often served certificates are checked for responses due a refresh.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Wait before asking a responder which failed again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Refresh interval of responses without a `nextUpdate`.
+const DEFAULT_REFRESH: Duration = Duration::from_secs(60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clock skew allowed between the ingress and the responder.
+const MAX_SKEW_SECONDS: u32 = 5 * 60;
+
+/// A `good` response for a certificate.
+#[derive(Debug, Clone)]
+struct Staple {
+    /// DER encoded `OCSPResponse`, as it is stapled
+    response: Vec<u8>,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+}
+
+impl Staple {
+    fn is_valid(&self, now: SystemTime) -> bool {
+        self.next_update.is_none_or(|next_update| now < next_update)
+    }
+
+    /// Halfway between `thisUpdate` and `nextUpdate`, so there's plenty of time to retry before it expires.
+    fn refresh_at(&self) -> SystemTime {
+        match self.next_update.and_then(|next_update| next_update.duration_since(self.this_update).ok()) {
+            Some(validity) => self.this_update + validity / 2,
+            None => self.this_update + DEFAULT_REFRESH,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedStaple {
+    staple: Option<Staple>,
+    refresh_at: SystemTime,
+}
+
+pub struct OcspStapler {
+    state: Arc<CertificateState>,
+    client: Client<HttpConnector>,
+    /// keyed by the DER of the leaf certificate
+    cache: Mutex<HashMap<Vec<u8>, CachedStaple>>,
+    /// wakes the stapler to fetch what's due straight away
+    refresh: Notify,
+}
+
+impl OcspStapler {
+    pub fn new(state: Arc<CertificateState>) -> OcspStapler {
+        OcspStapler {
+            state,
+            client: Client::new(),
+            cache: Mutex::new(HashMap::new()),
+            refresh: Notify::new(),
+        }
+    }
+
+    /// Keeps a response stapled to every served certificate with a responder, for as long as the ingress runs.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            self.staple_all().await;
+
+            tokio::select! {
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+                _ = self.refresh.notified() => {}
+            }
+        }
+    }
+
+    /// Fetches every response again now. Stapled responses stay stapled until their replacements arrive.
+    pub async fn purge(&self) {
+        for cached in self.cache.lock().await.values_mut() {
+            cached.refresh_at = UNIX_EPOCH;
+        }
+        self.refresh.notify_one();
+    }
+
+    async fn staple_all(&self) {
+        let mut certs: Vec<CertKey> = Vec::new();
+        certs.extend(self.state.certs.read().await.values().flat_map(|certs| certs.iter().cloned()));
+        certs.extend(self.state.user_certs.read().await.values().flat_map(|certs| certs.iter().cloned()));
+        certs.extend(self.state.fallback.read().await.iter().cloned());
+
+        // responders can be slow, so the cache isn't locked while they are asked
+        let now = SystemTime::now();
+        let mut due: Vec<(CertKey, Vec<u8>, Option<Staple>)> = Vec::new();
+        {
+            let mut cache = self.cache.lock().await;
+            cache.retain(|leaf, _| certs.iter().any(|cert| cert.certs.first() == Some(leaf)));
+
+            for cert in certs {
+                let leaf = match cert.certs.first() {
+                    Some(leaf) => leaf.clone(),
+                    None => continue,
+                };
+
+                match cache.get(&leaf) {
+                    Some(cached) if cached.refresh_at > now => {
+                        // a certificate loaded again, e.g. by another replica, is a new key to staple to
+                        let response = cached.staple.as_ref().filter(|staple| staple.is_valid(now)).map(|staple| staple.response.clone());
+                        if cert.ocsp() != response {
+                            cert.staple(response);
+                        }
+                    }
+                    cached => {
+                        let previous = cached.and_then(|cached| cached.staple.clone());
+                        due.push((cert, leaf, previous));
+                    }
+                }
+            }
+        }
+
+        for (cert, leaf, previous) in due {
+            let cached = match self.fetch(&cert).await {
+                Ok(Some(staple)) => {
+                    match previous {
+                        Some(_) => debug!("ocsp: refreshed response for {}", subject(&cert)),
+                        None => info!("ocsp: stapling response for {}", subject(&cert)),
+                    }
+                    CachedStaple { refresh_at: staple.refresh_at(), staple: Some(staple) }
+                }
+                // nothing to staple, e.g. self-signed certificates
+                Ok(None) => CachedStaple { staple: None, refresh_at: now + DEFAULT_REFRESH },
+                Err(e) => {
+                    warn!("ocsp: could not fetch response for {}, retrying in {:?}: {}", subject(&cert), RETRY_INTERVAL, e);
+                    CachedStaple { staple: previous.filter(|staple| staple.is_valid(now)), refresh_at: now + RETRY_INTERVAL }
+                }
+            };
+
+            cert.staple(cached.staple.as_ref().map(|staple| staple.response.clone()));
+            self.cache.lock().await.insert(leaf, cached);
+        }
+    }
+
+    /// Asks the certificate's responder for its status. `None` when the certificate names no responder, or its
+    /// chain doesn't include the issuer the request is built from.
+    async fn fetch(&self, cert: &CertKey) -> Result<Option<Staple>, IngressLoadBalancerError> {
+        let failed = |msg: String| IngressLoadBalancerError::general(Code::InvalidCertificate, msg);
+
+        let (leaf, issuer) = match (cert.certs.first(), cert.certs.get(1)) {
+            (Some(leaf), Some(issuer)) => (
+                X509::from_der(leaf).map_err(|e| failed(e.to_string()))?,
+                X509::from_der(issuer).map_err(|e| failed(e.to_string()))?,
+            ),
+            _ => return Ok(None),
+        };
+        let responder = match leaf.ocsp_responders().ok().and_then(|responders| responders.iter().next().map(|url| url.to_string())) {
+            Some(responder) => responder,
+            None => return Ok(None),
+        };
+
+        let mut request = OcspRequest::new().map_err(|e| failed(e.to_string()))?;
+        request.add_id(cert_id(&leaf, &issuer)?).map_err(|e| failed(e.to_string()))?;
+        let request = Request::post(&responder)
+            .header(CONTENT_TYPE, "application/ocsp-request")
+            .body(Body::from(request.to_der().map_err(|e| failed(e.to_string()))?))
+            .map_err(|e| failed(format!("invalid responder {}: {}", responder, e)))?;
+
+        let body = tokio::time::timeout(FETCH_TIMEOUT, async {
+            let response = self.client.request(request).await?;
+            let status = response.status();
+            hyper::body::to_bytes(response.into_body()).await.map(|body| (status, body))
+        }).await;
+
+        let body = match body {
+            Ok(Ok((status, body))) if status.is_success() => body,
+            Ok(Ok((status, _))) => return Err(failed(format!("{} answered {}", responder, status))),
+            Ok(Err(e)) => return Err(failed(format!("{}: {}", responder, e))),
+            Err(_) => return Err(failed(format!("{} did not answer within {:?}", responder, FETCH_TIMEOUT))),
+        };
+
+        parse_response(&body, &leaf, &issuer).map(Some)
+    }
+}
+
+fn cert_id(leaf: &X509, issuer: &X509) -> Result<OcspCertId, IngressLoadBalancerError> {
+    OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)
+        .map_err(|e| IngressLoadBalancerError::general(Code::InvalidCertificate, e.to_string()))
+}
+
+/// Checks the response is signed by the issuer, or a responder it delegated to, and says the certificate is good.
+fn parse_response(der: &[u8], leaf: &X509, issuer: &X509) -> Result<Staple, IngressLoadBalancerError> {
+    let invalid = |msg: String| IngressLoadBalancerError::general(Code::InvalidCertificate, format!("invalid OCSP response: {}", msg));
+
+    let response = OcspResponse::from_der(der).map_err(|e| invalid(e.to_string()))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(invalid(format!("responder status {}", response.status().as_raw())));
+    }
+    let basic = response.basic().map_err(|e| invalid(e.to_string()))?;
+
+    let verified: Result<(), openssl::error::ErrorStack> = try {
+        let mut certs = Stack::new()?;
+        certs.push(issuer.clone())?;
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(issuer.clone())?;
+        basic.verify(&certs, &store.build(), OcspFlag::TRUST_OTHER)?;
+    };
+    verified.map_err(|e| invalid(format!("bad signature: {}", e)))?;
+
+    let id = cert_id(leaf, issuer)?;
+    let status = basic.find_status(&id).ok_or_else(|| invalid("no status for the certificate".to_string()))?;
+    if status.status == OcspCertStatus::REVOKED {
+        return Err(invalid("the certificate is revoked".to_string()));
+    }
+    if status.status != OcspCertStatus::GOOD {
+        return Err(invalid("the certificate's status is unknown".to_string()));
+    }
+    status.check_validity(MAX_SKEW_SECONDS, None).map_err(|e| invalid(format!("expired or not yet valid: {}", e)))?;
+
+    Ok(Staple {
+        response: der.to_vec(),
+        this_update: system_time(status.this_update).ok_or_else(|| invalid("unreadable thisUpdate".to_string()))?,
+        next_update: match status.next_update() {
+            Some(next_update) => Some(system_time(next_update).ok_or_else(|| invalid("unreadable nextUpdate".to_string()))?),
+            None => None,
+        },
+    })
+}
+
+/// Reads the time openssl prints as e.g. `Jan  2 03:04:05 2025 GMT`, there is no other way to get at it.
+fn system_time(time: &Asn1GeneralizedTimeRef) -> Option<SystemTime> {
+    let time = NaiveDateTime::parse_from_str(&time.to_string(), "%b %e %H:%M:%S %Y GMT").ok()?;
+    Some(time.and_utc().into())
+}
+
+fn subject(cert: &CertKey) -> String {
+    cert.info()
+        .and_then(|info| info.subject_alt_names.first().cloned())
+        .unwrap_or_else(|| "certificate without names".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Extension, X509NameBuilder};
+
+    use super::*;
+    use crate::certificate_state::{cert_key_from, HostCerts};
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len if len < 0x80 => out.push(len as u8),
+            len if len < 0x100 => out.extend([0x81, len as u8]),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend(content);
+        out
+    }
+
+    fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+        der(0x30, &parts.concat())
+    }
+
+    fn certificate(common_name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, responder: Option<&str>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(issuer.map_or(&name, |(issuer, _)| issuer.subject_name())).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(90).unwrap()).unwrap();
+
+        match issuer {
+            None => cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap(),
+            Some(_) => {
+                let san = SubjectAlternativeName::new().dns(common_name).build(&cert.x509v3_context(None, None)).unwrap();
+                cert.append_extension(san).unwrap();
+            }
+        }
+        if let Some(responder) = responder {
+            // authority information access: SEQUENCE { SEQUENCE { id-ad-ocsp, [6] uri } }
+            let ocsp = [0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01].to_vec();
+            let aia = seq(&[seq(&[ocsp, der(0x86, responder.as_bytes())])]);
+            let oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.1").unwrap();
+            cert.append_extension(X509Extension::new_from_der(&oid, false, &Asn1OctetString::new_from_bytes(&aia).unwrap()).unwrap()).unwrap();
+        }
+
+        cert.sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    /// A `good` response for `leaf`, signed by its issuer, valid from 2000 until 2099.
+    fn good_response(leaf: &X509, issuer: &X509, issuer_key: &PKey<Private>) -> Vec<u8> {
+        let sha1 = |data: &[u8]| der(0x04, &openssl::hash::hash(MessageDigest::sha1(), data).unwrap());
+        let time = |time: &str| der(0x18, time.as_bytes());
+
+        let key_hash = sha1(&issuer_key.rsa().unwrap().public_key_to_der_pkcs1().unwrap());
+        let mut serial = leaf.serial_number().to_bn().unwrap().to_vec();
+        if serial.first().is_none_or(|byte| byte & 0x80 != 0) {
+            serial.insert(0, 0);
+        }
+
+        let cert_id = seq(&[
+            seq(&[vec![0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a], vec![0x05, 0x00]]),
+            sha1(&issuer.subject_name().to_der().unwrap()),
+            key_hash.clone(),
+            der(0x02, &serial),
+        ]);
+        let single = seq(&[cert_id, vec![0x80, 0x00], time("20000101000000Z"), der(0xa0, &time("20990101000000Z"))]);
+        let tbs = seq(&[der(0xa2, &key_hash), time("20000101000000Z"), seq(&[single])]);
+
+        let mut signer = Signer::new(MessageDigest::sha256(), issuer_key).unwrap();
+        signer.update(&tbs).unwrap();
+        let mut signature = vec![0];
+        signature.extend(signer.sign_to_vec().unwrap());
+
+        let sha256_with_rsa = vec![0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+        let basic = seq(&[tbs, seq(&[sha256_with_rsa, vec![0x05, 0x00]]), der(0x03, &signature)]);
+        let ocsp_basic = vec![0x06, 0x09, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+        seq(&[der(0x0a, &[0]), der(0xa0, &seq(&[ocsp_basic, der(0x04, &basic)]))])
+    }
+
+    /// A stand-in responder answering every request with whatever `answer` holds.
+    async fn responder(answer: Arc<std::sync::Mutex<Vec<u8>>>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let answer = answer.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let body = answer.lock().unwrap().clone();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn staples_responses_and_fails_open() {
+        let answer = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let addr = responder(answer.clone()).await;
+
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca = certificate("Test CA", &ca_key, None, None);
+        let leaf_key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let leaf = certificate("shop.example.com", &leaf_key, Some((&ca, &ca_key)), Some(&format!("http://{}/", addr)));
+        let good = good_response(&leaf, &ca, &ca_key);
+        *answer.lock().unwrap() = good.clone();
+
+        let cert = cert_key_from(vec![leaf.to_der().unwrap(), ca.to_der().unwrap()], leaf_key.private_key_to_der().unwrap()).unwrap();
+        let state = Arc::new(CertificateState::new());
+        state.certs.write().await.insert("shop.example.com".to_string(), HostCerts::from(cert.clone()));
+        let stapler = OcspStapler::new(state.clone());
+
+        stapler.staple_all().await;
+        assert_eq!(cert.ocsp(), Some(good.clone()));
+        let cached = stapler.cache.lock().await;
+        let staple = cached[&leaf.to_der().unwrap()].staple.clone().unwrap();
+        assert_eq!(staple.this_update, UNIX_EPOCH + Duration::from_secs(946_684_800));
+        assert_eq!(staple.refresh_at(), UNIX_EPOCH + Duration::from_secs(946_684_800 + (4_070_908_800 - 946_684_800) / 2));
+        drop(cached);
+
+        // the responder fails, the response already stapled is still valid
+        *answer.lock().unwrap() = OcspResponse::create(OcspResponseStatus::TRY_LATER, None).unwrap().to_der().unwrap();
+        stapler.cache.lock().await.values_mut().for_each(|cached| cached.refresh_at = UNIX_EPOCH);
+        stapler.staple_all().await;
+        assert_eq!(cert.ocsp(), Some(good));
+
+        // without a valid response nothing is stapled, and the certificate is still served
+        stapler.cache.lock().await.clear();
+        cert.staple(None);
+        stapler.staple_all().await;
+        assert_eq!(cert.ocsp(), None);
+    }
+
+    #[test]
+    fn rejects_responses_for_other_certificates() {
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca = certificate("Test CA", &ca_key, None, None);
+        let leaf_key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let leaf = certificate("shop.example.com", &leaf_key, Some((&ca, &ca_key)), None);
+        let other = certificate("api.example.com", &leaf_key, Some((&ca, &ca_key)), None);
+
+        assert!(parse_response(&good_response(&leaf, &ca, &ca_key), &leaf, &ca).is_ok());
+        assert!(parse_response(&good_response(&other, &ca, &ca_key), &leaf, &ca).is_err());
+    }
+}
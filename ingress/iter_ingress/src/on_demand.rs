@@ -0,0 +1,447 @@
+//! # On-Demand TLS
+//!
+//! Without it a host only gets a certificate once the [certificate manager](crate::cert_manager) has noticed it, and
+//! until then clients get the fallback certificate. With on-demand issuance, the handshake of a host without a
+//! certificate is held while one is issued, and completed with it, for up to [`OnDemandSettings::timeout`].
+//!
+//! Only allowed hosts are issued for: hosts of an ingress, hosts matching [`OnDemandSettings::allowed_hosts`], exact
+//! or `*.example.com` for any subdomain one level down, and hosts the [`OnDemandSettings::approval_webhook`]
+//! answers `GET <webhook>?host=<host>` for with a 2xx.
+//!
+//! Anyone can send a client hello with a made up server name, and those mustn't use up the CA's rate limits. At most
+//! [`OnDemandSettings::max_concurrent`] hosts are approved or waited for at once on each replica, further handshakes
+//! get the fallback straight away, and at most [`OnDemandSettings::max_per_hour`] new hosts are approved per hour in
+//! the whole cluster. Hosts which weren't allowed are refused without asking again for [`DENIED_TTL`].
+//!
+//! Only the leader orders certificates. In a cluster, the replica a handshake arrived at publishes the approved host as
+//! a config map labelled [`ON_DEMAND_LABEL`], which every replica watches, and waits for the certificate to show up in
+//! storage. The config maps keep the host's certificate renewed after restarts, and count towards the hourly limit
+//! of every replica. Deleting a host's config map withdraws its approval, and its certificate is removed like those
+//! of hosts no ingress routes anymore.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::StreamExt;
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use hyper_rustls::HttpsConnector;
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{ListParams, ObjectMeta, PostParams};
+use kube::runtime::watcher::Event;
+use kube::{runtime, Api, Client};
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+use tracing::{debug, info, warn};
+
+use crate::cert_storage::name_hash;
+use crate::certificate_state::{CertificateState, Host};
+use crate::kube_config_tracker::RoutingTable;
+
+pub const ON_DEMAND_LABEL: &str = "iter.earth/on-demand-host";
+
+/// How long a host which wasn't allowed is refused without checking again.
+pub const DENIED_TTL: Duration = Duration::from_secs(600);
+/// Denied hosts remembered at most, so made up server names can't grow the list forever.
+const MAX_DENIED: usize = 10_000;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+pub struct OnDemandSettings {
+    /// hosts issued for without asking the webhook, exact or `*.example.com`
+    pub allowed_hosts: Vec<String>,
+    /// asked about every other host which isn't an ingress host
+    pub approval_webhook: Option<Uri>,
+    pub max_concurrent: usize,
+    pub max_per_hour: u32,
+    /// longest a handshake is held for its certificate
+    pub timeout: Duration,
+}
+
+/// Why a host may be issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Approval {
+    /// the certificate manager issues it anyway
+    Ingress,
+    Allowed,
+    Webhook,
+}
+
+pub struct OnDemand {
+    settings: OnDemandSettings,
+    routing_table: Arc<RoutingTable>,
+    /// publishes approved hosts to the leader, `None` outside kubernetes
+    cluster: Option<(Client, String)>,
+    http: hyper::Client<HttpsConnector<HttpConnector>>,
+    /// approved hosts, which the certificate manager keeps a certificate for
+    hosts: Mutex<HashSet<Host>>,
+    /// hosts a handshake is being held for
+    pending: Mutex<HashSet<Host>>,
+    denied: Mutex<HashMap<Host, Instant>>,
+    /// when each host approved in the last hour was approved, on any replica
+    approved_at: Mutex<HashMap<Host, SystemTime>>,
+    slots: Semaphore,
+    /// notified whenever a host is approved
+    pub changed: Notify,
+}
+
+impl OnDemand {
+    pub fn new(settings: OnDemandSettings, routing_table: Arc<RoutingTable>, cluster: Option<(Client, String)>) -> OnDemand {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        OnDemand {
+            slots: Semaphore::new(settings.max_concurrent),
+            settings,
+            routing_table,
+            cluster,
+            http: hyper::Client::builder().build(https),
+            hosts: Mutex::new(HashSet::new()),
+            pending: Mutex::new(HashSet::new()),
+            denied: Mutex::new(HashMap::new()),
+            approved_at: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Hosts approved on this replica or published by any other.
+    pub fn hosts(&self) -> HashSet<Host> {
+        self.hosts.lock().unwrap().clone()
+    }
+
+    /// Holds the handshake for `host` until it has a certificate, returning whether it got one in time.
+    pub async fn certificate(&self, state: &CertificateState, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        if !is_host_name(&host) || self.is_denied(&host) {
+            return false;
+        }
+
+        // handshakes for a host which is already being waited for wait along, without being counted again
+        let first = self.pending.lock().unwrap().insert(host.clone());
+        let _permit = match first {
+            true => match self.approve(&host).await {
+                Ok(permit) => Some(permit),
+                Err(reason) => {
+                    self.pending.lock().unwrap().remove(&host);
+                    debug!("on_demand: not issuing a certificate for {}: {}", host, reason);
+                    return false;
+                }
+            },
+            false => None,
+        };
+
+        let issued = tokio::time::timeout(self.settings.timeout, async {
+            loop {
+                if state.certs.read().await.contains_key(&host) {
+                    return true;
+                }
+                // the handshake which asked first found the host isn't allowed
+                if !self.pending.lock().unwrap().contains(&host) && !self.hosts.lock().unwrap().contains(&host) {
+                    return false;
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }).await.unwrap_or(false);
+
+        if first {
+            self.pending.lock().unwrap().remove(&host);
+        }
+        if !issued {
+            info!("on_demand: no certificate for {} after {:?}, serving the fallback", host, self.settings.timeout);
+        }
+        issued
+    }
+
+    /// Checks `host` against the policy and the limits, and has the leader issue it a certificate.
+    async fn approve(&self, host: &str) -> Result<SemaphorePermit<'_>, String> {
+        let permit = self.slots.try_acquire().map_err(|_| "too many certificates are being issued on demand".to_string())?;
+        if self.hosts.lock().unwrap().contains(host) {
+            return Ok(permit);
+        }
+
+        let approval = match self.approval(host).await {
+            Some(approval) => approval,
+            None => {
+                self.deny(host);
+                return Err("the host is not allowed".to_string());
+            }
+        };
+        if approval == Approval::Ingress {
+            return Ok(permit);
+        }
+
+        {
+            let mut approved_at = self.approved_at.lock().unwrap();
+            let now = SystemTime::now();
+            approved_at.retain(|_, at| now.duration_since(*at).unwrap_or_default() < HOUR);
+            if approved_at.len() >= self.settings.max_per_hour as usize && !approved_at.contains_key(host) {
+                return Err(format!("{} hosts were approved in the last hour already", approved_at.len()));
+            }
+            approved_at.insert(host.to_string(), now);
+        }
+
+        info!("on_demand: issuing a certificate for {} ({:?})", host, approval);
+        self.add_host(host.to_string());
+        if let Some((client, namespace)) = &self.cluster {
+            if let Err(e) = publish(client, namespace, host).await {
+                warn!("on_demand: could not publish {} to the leader: {}", host, e);
+            }
+        }
+
+        Ok(permit)
+    }
+
+    async fn approval(&self, host: &str) -> Option<Approval> {
+        if self.routing_table.tls_hosts().await.contains(host) {
+            return Some(Approval::Ingress);
+        }
+        if self.settings.allowed_hosts.iter().any(|pattern| matches_host(pattern, host)) {
+            return Some(Approval::Allowed);
+        }
+
+        let webhook = self.settings.approval_webhook.as_ref()?;
+        let separator = if webhook.query().is_some() { '&' } else { '?' };
+        let uri: Uri = format!("{}{}host={}", webhook, separator, host).parse().ok()?;
+
+        match tokio::time::timeout(WEBHOOK_TIMEOUT, self.http.get(uri)).await {
+            Ok(Ok(response)) => response.status().is_success().then_some(Approval::Webhook),
+            Ok(Err(e)) => {
+                warn!("on_demand: approval webhook failed for {}: {}", host, e);
+                None
+            }
+            Err(_) => {
+                warn!("on_demand: approval webhook timed out for {}", host);
+                None
+            }
+        }
+    }
+
+    fn add_host(&self, host: Host) {
+        if self.hosts.lock().unwrap().insert(host) {
+            self.changed.notify_one();
+        }
+    }
+
+    /// Counts a host published by any replica towards the hourly limit, from when its config map was created.
+    fn published(&self, config_map: &ConfigMap) -> Option<Host> {
+        let host = host_from_config_map(config_map)?;
+        let created = config_map.metadata.creation_timestamp.as_ref().map(|Time(created)| SystemTime::from(*created));
+        if let Some(created) = created.filter(|created| created.elapsed().unwrap_or_default() < HOUR) {
+            self.approved_at.lock().unwrap().entry(host.clone()).or_insert(created);
+        }
+        Some(host)
+    }
+
+    fn apply(&self, event: Event<ConfigMap>) {
+        match event {
+            Event::Applied(config_map) => {
+                if let Some(host) = self.published(&config_map) {
+                    self.add_host(host);
+                }
+            }
+            Event::Deleted(config_map) => {
+                if let Some(host) = host_from_config_map(&config_map) {
+                    info!("on_demand: approval of {} was withdrawn", host);
+                    self.hosts.lock().unwrap().remove(&host);
+                }
+            }
+            // config maps deleted while the watch was down are withdrawn too
+            Event::Restarted(config_maps) => {
+                let published: HashSet<Host> = config_maps.iter().filter_map(|config_map| self.published(config_map)).collect();
+                let mut hosts = self.hosts.lock().unwrap();
+                if *hosts != published {
+                    *hosts = published;
+                    self.changed.notify_one();
+                }
+            }
+        }
+    }
+
+    fn is_denied(&self, host: &str) -> bool {
+        let mut denied = self.denied.lock().unwrap();
+        match denied.get(host) {
+            Some(at) if at.elapsed() < DENIED_TTL => true,
+            Some(_) => {
+                denied.remove(host);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn deny(&self, host: &str) {
+        let mut denied = self.denied.lock().unwrap();
+        if denied.len() >= MAX_DENIED {
+            denied.retain(|_, at| at.elapsed() < DENIED_TTL);
+        }
+        if denied.len() < MAX_DENIED {
+            denied.insert(host.to_string(), Instant::now());
+        }
+    }
+
+    /// Keeps the approved hosts in line with the published config maps.
+    pub async fn watch(self: Arc<Self>) {
+        let (client, namespace) = match &self.cluster {
+            Some(cluster) => cluster.clone(),
+            None => return,
+        };
+        let config_maps: Api<ConfigMap> = Api::namespaced(client, &namespace);
+        let mut stream = Box::pin(runtime::watcher(config_maps, ListParams::default().labels(ON_DEMAND_LABEL)));
+
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(event) => self.apply(event),
+                Err(e) => warn!("on_demand: error watching approved hosts: {}", e),
+            }
+        }
+    }
+}
+
+async fn publish(client: &Client, namespace: &str, host: &str) -> Result<(), kube::Error> {
+    let config_maps: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+
+    match config_maps.create(&PostParams::default(), &host_config_map(namespace, host)).await {
+        Ok(_) => Ok(()),
+        // approved by another replica already
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn host_config_map(namespace: &str, host: &str) -> ConfigMap {
+    ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(format!("on-demand-{}", name_hash(host, 10))),
+            namespace: Some(namespace.to_string()),
+            labels: Some([(ON_DEMAND_LABEL.to_string(), "true".to_string())].into_iter().collect()),
+            ..Default::default()
+        },
+        data: Some([("host".to_string(), host.to_string())].into_iter().collect()),
+        ..Default::default()
+    }
+}
+
+pub fn host_from_config_map(config_map: &ConfigMap) -> Option<Host> {
+    config_map.data.as_ref()?.get("host").cloned()
+}
+
+/// Whether `host` is `pattern`, or a subdomain one level below `*.domain`.
+pub fn matches_host(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest == domain),
+        None => pattern == host,
+    }
+}
+
+/// A lowercase dns name of at least two labels, anything else in a client hello isn't issued for.
+fn is_host_name(host: &str) -> bool {
+    host.len() <= 253
+        && host.contains('.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate_state::{HostCerts, KeyType};
+    use crate::fallback_cert::self_signed;
+
+    fn on_demand(allowed_hosts: &[&str], max_concurrent: usize, max_per_hour: u32) -> Arc<OnDemand> {
+        Arc::new(OnDemand::new(OnDemandSettings {
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            approval_webhook: None,
+            max_concurrent,
+            max_per_hour,
+            timeout: Duration::from_secs(2),
+        }, Arc::new(RoutingTable::new()), None))
+    }
+
+    #[test]
+    fn matches_exact_hosts_and_wildcards() {
+        assert!(matches_host("shop.example.com", "shop.example.com"));
+        assert!(matches_host("*.example.com", "shop.example.com"));
+        assert!(!matches_host("*.example.com", "example.com"));
+        assert!(!matches_host("*.example.com", "a.shop.example.com"));
+        assert!(!is_host_name("127.0.0.1:443"));
+        assert!(!is_host_name("localhost"));
+    }
+
+    #[tokio::test]
+    async fn holds_handshakes_of_allowed_hosts_until_issued() {
+        let state = Arc::new(CertificateState::new());
+        let on_demand = on_demand(&["*.example.com"], 4, 10);
+
+        // stands in for the certificate manager
+        let issuer = tokio::spawn({
+            let (state, on_demand) = (state.clone(), on_demand.clone());
+            async move {
+                on_demand.changed.notified().await;
+                for host in on_demand.hosts() {
+                    let cert = self_signed(&host, KeyType::EcdsaP256, 30).unwrap();
+                    state.certs.write().await.insert(host, HostCerts::from(cert));
+                }
+            }
+        });
+
+        let (first, second) = tokio::join!(
+            on_demand.certificate(&state, "shop.example.com"),
+            on_demand.certificate(&state, "SHOP.example.com"),
+        );
+        assert!(first && second);
+        issuer.await.unwrap();
+
+        assert!(!on_demand.certificate(&state, "random.example.org").await);
+        assert!(on_demand.is_denied("random.example.org"));
+    }
+
+    #[tokio::test]
+    async fn limits_new_hosts_per_hour() {
+        let state = CertificateState::new();
+        let on_demand = on_demand(&["*.example.com"], 4, 1);
+
+        assert!(on_demand.approve("a.example.com").await.is_ok());
+        assert!(on_demand.approve("b.example.com").await.is_err());
+        assert_eq!(on_demand.hosts(), HashSet::from(["a.example.com".to_string()]));
+
+        // the only slot is taken by a host whose certificate never comes
+        let on_demand = self::on_demand(&["*.example.com"], 1, 10);
+        let permit = on_demand.slots.try_acquire().unwrap();
+        assert!(!on_demand.certificate(&state, "c.example.com").await);
+        drop(permit);
+        assert!(!on_demand.is_denied("c.example.com"));
+    }
+
+    #[tokio::test]
+    async fn follows_the_hosts_published_in_the_cluster() {
+        let on_demand = on_demand(&["*.example.com"], 4, 1);
+        let published = |host: &str| {
+            let mut config_map = host_config_map("iter", host);
+            config_map.metadata.creation_timestamp = Some(Time(k8s_openapi::chrono::Utc::now()));
+            config_map
+        };
+
+        // approved on another replica, which uses up the hourly limit here too
+        on_demand.apply(Event::Applied(published("a.example.com")));
+        assert_eq!(on_demand.hosts(), HashSet::from(["a.example.com".to_string()]));
+        assert!(on_demand.approve("b.example.com").await.is_err());
+
+        on_demand.apply(Event::Deleted(published("a.example.com")));
+        assert!(on_demand.hosts().is_empty());
+
+        on_demand.add_host("c.example.com".to_string());
+        on_demand.apply(Event::Restarted(vec![published("d.example.com")]));
+        assert_eq!(on_demand.hosts(), HashSet::from(["d.example.com".to_string()]));
+    }
+}
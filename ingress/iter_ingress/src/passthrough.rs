@@ -0,0 +1,123 @@
+//! # TLS Passthrough
+//!
+//! Forwards tls connections for hosts marked with [`TLS_PASSTHROUGH_ANNOTATION`](crate::kube_config_tracker::TLS_PASSTHROUGH_ANNOTATION)
+//! to their backend without terminating them, for services which handle tls themselves, e.g. to do mTLS with
+//! partners. The backend is picked by the sni of the client hello, so these hosts share the :443 listener with
+//! every other host.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+
+use iter_tls_acceptor::proxy_protocol::ClientStream;
+use iter_tls_acceptor::tls_acceptor::HandlesPassthrough;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::forwarding::{upstream_proxy_header, ClientInfo};
+use crate::kube_config_tracker::RoutingTable;
+use crate::metrics::Metrics;
+use crate::stream_proxy::splice;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TlsPassthrough {
+    pub routing_table: Arc<RoutingTable>,
+    pub metrics: Arc<Metrics>,
+    pub idle_timeout: Duration,
+}
+
+#[async_trait::async_trait]
+impl HandlesPassthrough for TlsPassthrough {
+    async fn is_passthrough(&self, server_name: &str) -> bool {
+        self.routing_table.get_passthrough_backend(server_name).await.is_some()
+    }
+
+    async fn forward(self: Arc<Self>, server_name: String, stream: ClientStream) {
+        let route = match self.routing_table.get_passthrough_backend(&server_name).await {
+            Some(route) => route,
+            None => return warn!("passthrough: {} is no longer a passthrough host", server_name),
+        };
+        let backend = route.address();
+        let client = ClientInfo::from_stream(&stream, true);
+
+        // counted as a tunnel so draining waits for it, hyper doesn't know about these connections
+        let _tunnel = self.metrics.track_tunnel();
+
+        let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&backend)).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => return self.metrics.record_upstream_failure(&backend, e).await,
+            Err(_) => return self.metrics.record_upstream_failure(&backend, "connect timed out").await,
+        };
+        self.metrics.record_upstream_success(&backend).await;
+
+        if let Some(proxy_header) = upstream_proxy_header(&route, &client) {
+            if let Err(e) = upstream.write_all(&proxy_header).await {
+                return warn!("passthrough: could not send PROXY header to {}: {}", backend, e);
+            }
+        }
+
+        info!("passthrough: {} -> {} ({})", client.addr.ip(), server_name, backend);
+
+        let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+        if let Err(e) = splice(stream, upstream, self.idle_timeout, (&sent, &received)).await {
+            warn!("passthrough: connection for {} to {} closed with error: {}", server_name, backend, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::server::conn::AddrIncoming;
+    use iter_tls_acceptor::tls_acceptor::{TlsAcceptor, TlsAcceptorOptions};
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::certificate_state::CertificateState;
+    use crate::kube_config_tracker::Backend;
+
+    #[tokio::test]
+    async fn forwards_client_hello_to_passthrough_backend() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        let routing_table = Arc::new(RoutingTable::new());
+        routing_table.apply("default/db".to_string(), vec![
+            Backend::with_prefix("db.example.com".to_string(), "/".to_string(), "127.0.0.1".to_string(), upstream_addr.port())
+                .with_tls_passthrough(true),
+        ]).await;
+
+        let passthrough = Arc::new(TlsPassthrough {
+            routing_table,
+            metrics: Arc::new(Metrics::new()),
+            idle_timeout: Duration::from_secs(5),
+        });
+        let incoming = AddrIncoming::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = incoming.local_addr();
+        let _acceptor = TlsAcceptor::with_options(incoming, Arc::new(CertificateState::new()), TlsAcceptorOptions {
+            passthrough: Some(passthrough),
+            ..Default::default()
+        });
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let client = TcpStream::connect(addr).await.unwrap();
+        tokio::spawn(tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("db.example.com").unwrap(), client));
+
+        let (mut forwarded, _) = tokio::time::timeout(Duration::from_secs(2), upstream.accept()).await.unwrap().unwrap();
+        let mut client_hello = vec![0u8; 512];
+        let read = forwarded.read(&mut client_hello).await.unwrap();
+
+        // a tls handshake record, still unencrypted, with the requested server name in it
+        assert_eq!(client_hello[0], 0x16);
+        assert!(client_hello[..read].windows(14).any(|window| window == b"db.example.com"));
+    }
+}
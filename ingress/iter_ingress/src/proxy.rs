@@ -1,31 +1,77 @@
-use futures::join;
 use hyper::{Body, Uri, StatusCode};
 use std::str::FromStr;
 use std::sync::Arc;
-use hyper::header::{UPGRADE, HOST};
+use std::sync::atomic::Ordering;
+use hyper::header::HOST;
 use hyper::{Request, Response, Client};
+use tracing::{debug, warn};
 
 use crate::certificate_state::CertificateState;
+use crate::forwarding::{set_forwarding_headers, ClientInfo, UpstreamConnector};
 use crate::kube_config_tracker::RoutingTable;
+use crate::metrics::Metrics;
+use crate::serverless::Serverless;
+use crate::shutdown::Shutdown;
+use crate::telemetry::{ServerSpan, Tracer};
+use crate::tunnel::{self, TunnelSettings};
 use crate::{IngressLoadBalancerError, Code};
 
+pub struct ProxyState {
+    pub routing_table: Arc<RoutingTable>,
+    pub cert_state: Arc<CertificateState>,
+    pub tracer: Arc<Tracer>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: Arc<Shutdown>,
+    pub tunnel: TunnelSettings,
+    pub serverless: Arc<Serverless>,
+}
+
 pub async fn proxy_request(
-    rt: Arc<RoutingTable>,
+    state: Arc<ProxyState>,
+    client: ClientInfo,
     req: Request<Body>,
-    cert_state: Arc<CertificateState>,
 ) -> Result<Response<Body>, !> {
+    state.metrics.requests_total.fetch_add(1, Ordering::Relaxed);
+    let mut span = state.tracer.start_server_span(req.method().to_string(), req.headers());
+    span.set_attribute("http.method", req.method().as_str());
+    span.set_attribute("http.target", req.uri().path());
+    span.set_attribute("http.client_ip", client.addr.ip().to_string());
 
-    let result: Result<Response<Body>, IngressLoadBalancerError> = call_proxy(req, &rt, &cert_state).await;
+    let result: Result<Response<Body>, IngressLoadBalancerError> = call_proxy(req, &client, &state, &mut span).await;
 
-    match result {
-        Ok(response) => Ok(response),
+    let response = match result {
+        Ok(response) => response,
         Err(e) => {
-            let mut response = Response::new(format!("Ingress Error\n{:#?}", e).into());
-            eprintln!("{:#?}", e);
-            *response.status_mut() = StatusCode::BAD_GATEWAY;
-            Ok(response)
+            span.set_error(&e);
+            let mut response = match &e {
+                // e.g. a client which got the fallback certificate for a host the ingress doesn't know
+                IngressLoadBalancerError::General(Code::NonExistentHost, msg) => {
+                    debug!("{}", msg);
+                    Response::new(format!("404 Not Found\n{}\n", msg).into())
+                }
+                IngressLoadBalancerError::General(Code::ClientCertificateRequired, msg) => {
+                    debug!("{}", msg);
+                    Response::new(format!("403 Forbidden\n{}\n", msg).into())
+                }
+                _ => {
+                    warn!("{:#?}", e);
+                    Response::new(format!("Ingress Error\n{:#?}", e).into())
+                }
+            };
+            *response.status_mut() = match e {
+                IngressLoadBalancerError::General(Code::BackendUnavailable, _) => StatusCode::SERVICE_UNAVAILABLE,
+                IngressLoadBalancerError::General(Code::NonExistentHost, _) => StatusCode::NOT_FOUND,
+                IngressLoadBalancerError::General(Code::ClientCertificateRequired, _) => StatusCode::FORBIDDEN,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            response
         }
-    }
+    };
+
+    span.set_attribute("http.status_code", response.status().as_u16());
+    span.end();
+
+    Ok(response)
 }
 
 fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> Result<Uri, IngressLoadBalancerError> {
@@ -38,7 +84,27 @@ fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> Result<Uri, IngressLoa
         .map_err(|e| IngressLoadBalancerError::Other(format!("{:#?}", e).into()))
 }
 
-pub async fn call_proxy(mut request: Request<Body>, rt: &RoutingTable, cert_state: &CertificateState) -> Result<Response<Body>, IngressLoadBalancerError> {
+/// Removes the port from a `Host` header or authority, keeping the brackets of an ipv6 literal.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    }
+}
+
+async fn record_upstream<T>(metrics: &Metrics, upstream: &str, result: Result<T, hyper::Error>) -> Result<T, hyper::Error> {
+    match &result {
+        Ok(_) => metrics.record_upstream_success(upstream).await,
+        Err(e) => metrics.record_upstream_failure(upstream, e).await,
+    }
+    result
+}
+
+pub async fn call_proxy(mut request: Request<Body>, client: &ClientInfo, state: &ProxyState, span: &mut ServerSpan) -> Result<Response<Body>, IngressLoadBalancerError> {
     let headers = request.headers();
 
     let host = match (headers.get(HOST), request.uri().authority()) {
@@ -50,7 +116,7 @@ pub async fn call_proxy(mut request: Request<Body>, rt: &RoutingTable, cert_stat
         })?,
         (_, Some(authority)) => authority.host(),
         (None, None) => {
-            eprintln!("No host header or authority in request");
+            warn!("No host header or authority in request");
             Err(IngressLoadBalancerError::general(
                 Code::NonExistentHost,
                 "no host or authority header found",
@@ -58,129 +124,118 @@ pub async fn call_proxy(mut request: Request<Body>, rt: &RoutingTable, cert_stat
         }
     };
 
-    // get the path from the uri
-    let path = request.uri().path();
+    // routes never include a port, and CONNECT requests always name one
+    let host = strip_port(host);
 
-    if let Some(res) = cert_state.handle_if_challenge(host, path).await {
+    // get the path from the uri, CONNECT requests have none
+    let path = match request.uri().path() {
+        "" => "/",
+        path => path,
+    };
+
+    if let Some(res) = state.cert_state.handle_if_challenge(host, path).await {
         // print path
-        println!("Matched Challenge: {}{}", host, path);
+        debug!("Matched Challenge: {}{}", host, path);
+        span.discard();
         return Ok(res);
     }
 
     // print path
-    println!("{} {:?} {}{}", request.method(), request.uri().scheme(), host, path);
+    debug!("{} {} {}://{}{}", client.addr.ip(), request.method(), client.scheme(), host, path);
 
-    // if the URL is /health-check then return a 200
+    // if the URL is /health-check then return a 200, or a 503 while shutting down so load balancers drain this instance
     if path == "/health-check" {
+        span.discard();
         let mut response = Response::new(Body::empty());
-        *response.status_mut() = StatusCode::OK;
+        *response.status_mut() = match state.shutdown.is_draining() {
+            true => StatusCode::SERVICE_UNAVAILABLE,
+            false => StatusCode::OK,
+        };
         return Ok(response);
     }
 
     // get the backend for the host and path
-    let backend = rt.get_backend(&host, &path).await?;
-
-
-    let is_websocket_upgrade = request.headers().contains_key(UPGRADE) && request.headers().get(UPGRADE).unwrap().to_str().unwrap().to_lowercase() == "websocket";
-
-    let client = Client::new();
-
-    if is_websocket_upgrade {
-        // is there a cleaner way of proxying the websockets here? maybe by possibly avoiding creating proxy reqs and responses
-        let prox_req = {
-            let headers = request.headers().clone();
-
-            let mut proxied_ws = Request::builder()
-                .uri(forward_uri(&format!("http://{}", backend), &request)?)
-                .method(request.method().clone());
-
-            let prox_headers = proxied_ws.headers_mut().unwrap();
-            *prox_headers = headers;
-            proxied_ws.body(Body::empty())
-                .map_err(|_| IngressLoadBalancerError::general(Code::InternalServerError, "Error creating proxied request"))?
-        };
-
-        println!("proxy req {:#?}, request {:#?}", prox_req, request);
-
-        let mut response = client.request(prox_req)
-            .await
-            .map_err(|e| IngressLoadBalancerError::HyperError(e))?;
-
-        println!("response {:#?}", response);
-
-        // let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-        // println!("{}", String::from_utf8_lossy(&body.to_vec()));
-
-        let prox_res = {
-            let headers = response.headers().clone();
-            let status = response.status().clone();
-            let version = response.version().clone();
-
-
-            let mut proxied_ws = Response::builder()
-                .status(status)
-                .version(version);
-
-            let prox_headers = proxied_ws.headers_mut().unwrap();
-            *prox_headers = headers;
-            proxied_ws.body(Body::empty())
-                .map_err(|_| IngressLoadBalancerError::general(Code::InternalServerError, "Error creating proxied response"))?
-        };
+    let route = state.routing_table.find_backend(&host, &path).await?;
+
+    // the certificate was verified for the host named in the handshake, which a request may not switch away from
+    if route.requires_client_certificate() && (client.client_cert_subject.is_none() || client.server_name.as_deref() != Some(host)) {
+        return Err(IngressLoadBalancerError::general(
+            Code::ClientCertificateRequired,
+            format!(
+                "{} requires a client certificate presented in a tls handshake for it, the handshake was for {}",
+                host,
+                client.server_name.as_deref().unwrap_or("no server name"),
+            ),
+        ));
+    }
+    let backend = route.address();
+    span.add_event("routing");
+    span.set_attribute("http.host", host);
+    span.set_attribute("upstream.address", backend.as_str());
+
+    // scale-to-zero backends may have to be woken up first, the guard keeps them up until the response body is sent
+    // or the tunnel is closed
+    let activity = match &route.serverless {
+        Some(target) => {
+            span.add_event("serverless.hold");
+            Some(state.serverless.hold(target).await?)
+        }
+        None => None,
+    };
 
-        tokio::task::spawn(async move {
-            let client_stream = match hyper::upgrade::on(&mut request).await {
-                Ok(client_stream) => Ok(client_stream),
-                Err(e) => Err(IngressLoadBalancerError::general(Code::WebsocketUpgradeError, format!{"Error when upgrading client websockets: {:#?}", e})),
-            };
+    let host = host.to_string();
+    let client_info = client;
 
-            let server_stream = match hyper::upgrade::on(&mut response).await {
-                Ok(server_stream) => Ok(server_stream),
-                Err(e) => Err(IngressLoadBalancerError::general(Code::WebsocketUpgradeError, format!{"Error when upgrading server websockets: {:#?}", e})),
-            };
-
-            let (client_stream, server_stream) = match (client_stream, server_stream) {
-                (Ok(client_stream), Ok(server_stream)) => (client_stream, server_stream),
-                (Err(e), _) | (_, Err(e)) => {
-                    println!("Error when upgrading client or server websockets: {:#?}", e);
-                    return;
-                },
-            };
+    if tunnel::is_connect(&request) {
+        span.add_event("upstream.connect");
+        let guard = (state.metrics.track_tunnel(), activity);
+        let result = tunnel::connect(&mut request, &route, client_info, state.tunnel.clone(), guard).await;
+        match &result {
+            Ok(_) => state.metrics.record_upstream_success(&backend).await,
+            Err(e) => state.metrics.record_upstream_failure(&backend, e).await,
+        }
+        return result;
+    }
 
-            println!("proxying request");
-
-            // we need to proxy the client stream to the server stream
-            // and vice versa into two different tasks
-            let (mut client_read, mut client_write) = tokio::io::split(client_stream);
-            let (mut server_read, mut server_write) = tokio::io::split(server_stream);
-
-            let _ = join!{
-                tokio::task::spawn(async move {
-                    loop {
-                        match tokio::io::copy(&mut client_read, &mut server_write).await {
-                            _ => break,
-                        }
-                    }
-                }),
-                tokio::task::spawn(async move {
-                    loop {
-                        match tokio::io::copy(&mut server_read, &mut client_write).await {
-                            _ => break
-                        }
-                    }
-                })
-            };
-        });
+    // taken before the request is forwarded, the request itself goes to the upstream unchanged
+    let client_upgrade = match tunnel::is_upgrade(&request) {
+        true => Some(hyper::upgrade::on(&mut request)),
+        false => None,
+    };
 
-        return Ok(prox_res);
-    }
+    let client = Client::builder().build::<_, Body>(UpstreamConnector::new(&route, client_info));
 
     // ensure the URI is forwarded correctly
     *request.uri_mut() = forward_uri(&format!("http://{}", &backend), &request)?;
     *request.version_mut() = hyper::Version::HTTP_11;
-
-    let response = client.request(request)
+    set_forwarding_headers(request.headers_mut(), client_info, &host, route.requires_client_certificate());
+    span.inject(request.headers_mut());
+
+    span.add_event("upstream.connect");
+    let response = client.request(request).await;
+    if let (Err(e), Some(target)) = (&response, &route.serverless) {
+        if e.is_connect() {
+            state.serverless.unavailable(target);
+        }
+    }
+    let mut response = record_upstream(&state.metrics, &backend, response)
         .await
         .map_err(|e| IngressLoadBalancerError::HyperError(e))?;
+    span.add_event("upstream.response");
+
+    if let Some(client_upgrade) = client_upgrade {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            span.add_event("upgrade");
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            let description = format!("{} {} -> {}", client_info.addr.ip(), host, backend);
+            let guard = (state.metrics.track_tunnel(), activity);
+            tunnel::spawn_upgraded(description, client_upgrade, upstream_upgrade, state.tunnel.clone(), guard);
+            return Ok(response);
+        }
+    }
 
-    Ok(response)
+    Ok(match activity {
+        Some(activity) => response.map(|body| activity.hold_until_sent(body)),
+        None => response,
+    })
 }
@@ -0,0 +1,573 @@
+//! # Serverless
+//!
+//! Scale-to-zero backends. An ingress annotated with
+//! [`SERVERLESS_ANNOTATION`](crate::kube_config_tracker::SERVERLESS_ANNOTATION) names the Deployment behind its
+//! services, which may be scaled down to zero replicas while nobody uses it.
+//!
+//! When a request arrives for a deployment without ready pods, the request is held in a bounded queue and the
+//! deployment is scaled up to one replica. Held requests are released as soon as a pod is ready, or answered with a
+//! 503 once the hold timeout passes. Deployments which haven't served a request for their idle timeout are scaled
+//! back down to zero.
+//!
+//! Every replica records when it last served a deployment in the
+//! [`ACTIVITY_ANNOTATION`] of the Deployment, and only the leader scales down, once no replica has been busy for the
+//! idle timeout. The annotation is only updated once it is behind by a [`RECORD_FRACTION`] of the idle timeout, so
+//! busy deployments aren't patched by every replica on every sync. Replicas re-check that their deployments are still up, and a backend which refuses connections is
+//! woken up again by the next request.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::StreamExt;
+use hyper::Body;
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::chrono::{DateTime, SecondsFormat, Utc};
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client, ResourceExt};
+use serde_json::json;
+use tokio::sync::{watch, OnceCell};
+use tracing::{info, warn};
+
+use crate::kube_config_tracker::RoutingTable;
+use crate::{Code, IngressLoadBalancerError};
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The annotation may lag the activity of a replica by this fraction of the idle timeout.
+const RECORD_FRACTION: u32 = 4;
+
+/// When any ingress replica last served a request for the Deployment, as an RFC 3339 timestamp.
+pub const ACTIVITY_ANNOTATION: &str = "iter.earth/serverless-last-activity";
+
+/// The deployment behind a scale-to-zero backend.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerlessTarget {
+    pub namespace: String,
+    pub deployment: String,
+    /// overrides [`ServerlessSettings::idle_timeout`] for this deployment
+    pub idle_timeout: Option<Duration>,
+}
+
+impl std::fmt::Display for ServerlessTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.deployment)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerlessSettings {
+    /// scale deployments to zero after this long without requests
+    pub idle_timeout: Duration,
+    /// how long a request is held while its deployment scales up
+    pub hold_timeout: Duration,
+    /// requests held per deployment, any more are turned away
+    pub max_held_requests: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replicas {
+    pub desired: i32,
+    pub ready: i32,
+    /// when any replica last recorded activity on the deployment
+    pub last_activity: Option<SystemTime>,
+}
+
+/// Reads and changes the replica count of deployments.
+#[async_trait::async_trait]
+pub trait Scaler: Send + Sync {
+    async fn replicas(&self, target: &ServerlessTarget) -> Result<Replicas, anyhow::Error>;
+
+    async fn scale(&self, target: &ServerlessTarget, replicas: i32) -> Result<(), anyhow::Error>;
+
+    /// Records that the deployment served a request at `at`, for every replica to see.
+    async fn record_activity(&self, target: &ServerlessTarget, at: SystemTime) -> Result<(), anyhow::Error>;
+}
+
+/// Scales deployments through the kubernetes api. The client is only created once a deployment needs scaling, so
+/// the ingress still starts without kubernetes.
+#[derive(Default)]
+pub struct KubernetesScaler {
+    client: OnceCell<Client>,
+}
+
+impl KubernetesScaler {
+    async fn deployments(&self, namespace: &str) -> Result<Api<Deployment>, anyhow::Error> {
+        let client = self.client.get_or_try_init(Client::try_default).await?;
+        Ok(Api::namespaced(client.clone(), namespace))
+    }
+}
+
+#[async_trait::async_trait]
+impl Scaler for KubernetesScaler {
+    async fn replicas(&self, target: &ServerlessTarget) -> Result<Replicas, anyhow::Error> {
+        let deployment = self.deployments(&target.namespace).await?.get(&target.deployment).await?;
+        let last_activity = deployment.annotations()
+            .get(ACTIVITY_ANNOTATION)
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .map(SystemTime::from);
+
+        Ok(Replicas {
+            desired: deployment.spec.and_then(|spec| spec.replicas).unwrap_or(1),
+            ready: deployment.status.and_then(|status| status.ready_replicas).unwrap_or(0),
+            last_activity,
+        })
+    }
+
+    async fn scale(&self, target: &ServerlessTarget, replicas: i32) -> Result<(), anyhow::Error> {
+        let patch = Patch::Merge(json!({ "spec": { "replicas": replicas } }));
+        self.deployments(&target.namespace).await?
+            .patch(&target.deployment, &PatchParams::default(), &patch)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn record_activity(&self, target: &ServerlessTarget, at: SystemTime) -> Result<(), anyhow::Error> {
+        let at = DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let patch = Patch::Merge(json!({ "metadata": { "annotations": { ACTIVITY_ANNOTATION: at } } }));
+        self.deployments(&target.namespace).await?
+            .patch(&target.deployment, &PatchParams::default(), &patch)
+            .await?;
+
+        Ok(())
+    }
+}
+
+struct TargetState {
+    ready: watch::Sender<bool>,
+    last_activity: Instant,
+    in_flight: usize,
+    held: usize,
+    waking: bool,
+    /// the newest activity recorded on the deployment, by this or another replica
+    recorded_activity: Option<Instant>,
+}
+
+impl TargetState {
+    fn new() -> TargetState {
+        TargetState {
+            ready: watch::channel(false).0,
+            last_activity: Instant::now(),
+            in_flight: 0,
+            held: 0,
+            waking: false,
+            recorded_activity: None,
+        }
+    }
+}
+
+pub struct Serverless {
+    settings: ServerlessSettings,
+    scaler: Arc<dyn Scaler>,
+    targets: Mutex<HashMap<ServerlessTarget, TargetState>>,
+    /// scaling up and down never overlap, so a scale down can't undo a scale up which is still in progress
+    scaling: tokio::sync::Mutex<()>,
+}
+
+impl Serverless {
+    pub fn new(settings: ServerlessSettings, scaler: Arc<dyn Scaler>) -> Serverless {
+        Serverless {
+            settings,
+            scaler,
+            targets: Mutex::new(HashMap::new()),
+            scaling: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Waits until the deployment has a ready pod, scaling it up if needed. The returned guard keeps the deployment
+    /// from being scaled down until it is dropped.
+    pub async fn hold(self: &Arc<Self>, target: &ServerlessTarget) -> Result<ActivityGuard, IngressLoadBalancerError> {
+        let mut ready = {
+            let mut targets = self.targets.lock().unwrap();
+            let state = targets.entry(target.clone()).or_insert_with(TargetState::new);
+            state.last_activity = Instant::now();
+
+            if *state.ready.borrow() {
+                state.in_flight += 1;
+                return Ok(ActivityGuard { serverless: self.clone(), target: target.clone() });
+            }
+
+            if state.held >= self.settings.max_held_requests {
+                return Err(IngressLoadBalancerError::general(
+                    Code::BackendUnavailable,
+                    format!("too many requests waiting for {} to scale up", target),
+                ));
+            }
+
+            state.held += 1;
+            state.in_flight += 1;
+            if !state.waking {
+                state.waking = true;
+                tokio::spawn(self.clone().wake(target.clone()));
+            }
+            state.ready.subscribe()
+        };
+
+        // from here on the guard releases the request's place, whether or not the deployment becomes ready
+        let guard = ActivityGuard { serverless: self.clone(), target: target.clone() };
+        let woke = tokio::time::timeout(self.settings.hold_timeout, ready.wait_for(|ready| *ready)).await;
+
+        if let Some(state) = self.targets.lock().unwrap().get_mut(target) {
+            state.held -= 1;
+        }
+
+        match woke {
+            Ok(Ok(_)) => Ok(guard),
+            _ => Err(IngressLoadBalancerError::general(
+                Code::BackendUnavailable,
+                format!("{} did not become ready within {:?}", target, self.settings.hold_timeout),
+            )),
+        }
+    }
+
+    /// Scales the deployment up if nothing is running and waits for a ready pod.
+    async fn wake(self: Arc<Self>, target: ServerlessTarget) {
+        let _scaling = self.scaling.lock().await;
+        let started = Instant::now();
+        let mut scaled = false;
+
+        while started.elapsed() < self.settings.hold_timeout {
+            match self.scaler.replicas(&target).await {
+                Ok(replicas) if replicas.ready > 0 => {
+                    info!("serverless: {} is ready", target);
+                    self.set_ready(&target, true);
+                    break;
+                }
+                Ok(replicas) if replicas.desired == 0 && !scaled => {
+                    info!("serverless: scaling up {}", target);
+                    match self.scaler.scale(&target, 1).await {
+                        Ok(()) => scaled = true,
+                        Err(e) => warn!("serverless: could not scale up {}: {}", target, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("serverless: could not read replicas of {}: {}", target, e),
+            }
+
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+
+        if let Some(state) = self.targets.lock().unwrap().get_mut(&target) {
+            state.waking = false;
+        }
+    }
+
+    fn set_ready(&self, target: &ServerlessTarget, ready: bool) {
+        if let Some(state) = self.targets.lock().unwrap().get_mut(target) {
+            state.ready.send_replace(ready);
+        }
+    }
+
+    /// Called when the deployment refused a connection, so the next request wakes it up again.
+    pub fn unavailable(&self, target: &ServerlessTarget) {
+        self.set_ready(target, false);
+    }
+
+    /// Records the activity of this replica on the deployments, and notices deployments scaled down by the leader.
+    pub async fn sync(&self) {
+        let targets: Vec<(ServerlessTarget, bool, Option<Instant>)> = {
+            let targets = self.targets.lock().unwrap();
+            targets
+                .iter()
+                .map(|(target, state)| {
+                    // requests still in flight keep the deployment busy until they are done
+                    let active_at = match state.in_flight {
+                        0 => state.last_activity,
+                        _ => Instant::now(),
+                    };
+                    let active_at = Some(active_at).filter(|at| !self.recorded(target, state.recorded_activity, *at));
+                    (target.clone(), *state.ready.borrow(), active_at)
+                })
+                .collect()
+        };
+
+        for (target, ready, active_at) in targets {
+            if let Some(at) = active_at {
+                self.record_activity(&target, at).await;
+            }
+
+            if ready {
+                match self.scaler.replicas(&target).await {
+                    Ok(replicas) if replicas.ready == 0 => self.set_ready(&target, false),
+                    Ok(_) => {}
+                    Err(e) => warn!("serverless: could not read replicas of {}: {}", target, e),
+                }
+            }
+        }
+    }
+
+    /// Whether activity at `at` is close enough to what the deployment already has recorded.
+    fn recorded(&self, target: &ServerlessTarget, recorded: Option<Instant>, at: Instant) -> bool {
+        let idle_timeout = target.idle_timeout.unwrap_or(self.settings.idle_timeout);
+        recorded.is_some_and(|recorded| at <= recorded + idle_timeout / RECORD_FRACTION)
+    }
+
+    /// Records activity at `at` on the deployment, unless another replica recorded enough already.
+    async fn record_activity(&self, target: &ServerlessTarget, at: Instant) {
+        let stored = match self.scaler.replicas(target).await {
+            Ok(replicas) => replicas.last_activity.and_then(|stored| Instant::now().checked_sub(stored.elapsed().unwrap_or_default())),
+            Err(e) => return warn!("serverless: could not read replicas of {}: {}", target, e),
+        };
+
+        let recorded = match self.recorded(target, stored, at) {
+            true => stored,
+            false => match self.scaler.record_activity(target, SystemTime::now() - at.elapsed()).await {
+                Ok(()) => Some(at),
+                Err(e) => return warn!("serverless: could not record activity of {}: {}", target, e),
+            },
+        };
+
+        if let Some(state) = self.targets.lock().unwrap().get_mut(target) {
+            state.recorded_activity = state.recorded_activity.max(recorded);
+        }
+    }
+
+    /// Scales down every deployment which no replica has used for its idle timeout.
+    pub async fn scale_down_idle(&self) {
+        let _scaling = self.scaling.lock().await;
+
+        let idle: Vec<ServerlessTarget> = {
+            let targets = self.targets.lock().unwrap();
+            targets
+                .iter()
+                .filter(|(target, state)| {
+                    let idle_timeout = target.idle_timeout.unwrap_or(self.settings.idle_timeout);
+                    state.in_flight == 0 && !state.waking && state.last_activity.elapsed() >= idle_timeout
+                })
+                .map(|(target, _)| target.clone())
+                .collect()
+        };
+
+        for target in idle {
+            let idle_timeout = target.idle_timeout.unwrap_or(self.settings.idle_timeout);
+            match self.scaler.replicas(&target).await {
+                Ok(replicas) if replicas.desired == 0 => {
+                    self.set_ready(&target, false);
+                    continue;
+                }
+                Ok(Replicas { last_activity: Some(at), .. }) if at.elapsed().unwrap_or_default() < idle_timeout => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("serverless: could not read replicas of {}: {}", target, e);
+                    continue;
+                }
+            }
+
+            // requests arriving from now on are held until the deployment is scaled up again
+            self.set_ready(&target, false);
+            info!("serverless: scaling down idle {}", target);
+            if let Err(e) = self.scaler.scale(&target, 0).await {
+                warn!("serverless: could not scale down {}: {}", target, e);
+            }
+        }
+    }
+
+    /// Keeps track of the serverless deployments in the routing table, and scales them down once idle while `leader`
+    /// is true.
+    pub async fn run(self: Arc<Self>, routing_table: Arc<RoutingTable>, leader: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let routed = routing_table.serverless_targets().await;
+            {
+                let mut targets = self.targets.lock().unwrap();
+                targets.retain(|target, state| routed.contains(target) || state.in_flight > 0);
+                for target in routed {
+                    // deployments nobody has asked for yet count as idle from the moment they are routed to
+                    targets.entry(target).or_insert_with(TargetState::new);
+                }
+            }
+
+            self.sync().await;
+            if *leader.borrow() {
+                self.scale_down_idle().await;
+            }
+        }
+    }
+}
+
+/// Counts a request towards its deployment's activity until dropped.
+pub struct ActivityGuard {
+    serverless: Arc<Serverless>,
+    target: ServerlessTarget,
+}
+
+impl ActivityGuard {
+    /// Keeps counting the request until its response body has been sent, which may stream long after the headers.
+    pub fn hold_until_sent(self, body: Body) -> Body {
+        Body::wrap_stream(body.map(move |chunk| {
+            let _activity = &self;
+            chunk
+        }))
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.serverless.targets.lock().unwrap().get_mut(&self.target) {
+            state.in_flight -= 1;
+            state.last_activity = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use super::*;
+
+    /// A deployment whose pods become ready a moment after it is scaled up.
+    #[derive(Default)]
+    struct FakeScaler {
+        desired: Arc<AtomicI32>,
+        ready: Arc<AtomicI32>,
+        last_activity: Mutex<Option<SystemTime>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Scaler for FakeScaler {
+        async fn replicas(&self, _target: &ServerlessTarget) -> Result<Replicas, anyhow::Error> {
+            Ok(Replicas {
+                desired: self.desired.load(Ordering::SeqCst),
+                ready: self.ready.load(Ordering::SeqCst),
+                last_activity: *self.last_activity.lock().unwrap(),
+            })
+        }
+
+        async fn scale(&self, _target: &ServerlessTarget, replicas: i32) -> Result<(), anyhow::Error> {
+            self.desired.store(replicas, Ordering::SeqCst);
+            let ready = self.ready.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                ready.store(replicas, Ordering::SeqCst);
+            });
+            Ok(())
+        }
+
+        async fn record_activity(&self, _target: &ServerlessTarget, at: SystemTime) -> Result<(), anyhow::Error> {
+            *self.last_activity.lock().unwrap() = Some(at);
+            Ok(())
+        }
+    }
+
+    fn target() -> ServerlessTarget {
+        ServerlessTarget { namespace: "default".to_string(), deployment: "fn".to_string(), idle_timeout: None }
+    }
+
+    fn serverless(scaler: Arc<FakeScaler>, max_held_requests: usize) -> Arc<Serverless> {
+        Arc::new(Serverless::new(ServerlessSettings {
+            idle_timeout: Duration::from_secs(0),
+            hold_timeout: Duration::from_secs(2),
+            max_held_requests,
+        }, scaler))
+    }
+
+    #[tokio::test]
+    async fn holds_requests_until_scaled_up() {
+        let scaler = Arc::new(FakeScaler::default());
+        let serverless = serverless(scaler.clone(), 10);
+
+        let target = target();
+        let (first, second) = tokio::join!(serverless.hold(&target), serverless.hold(&target));
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(scaler.desired.load(Ordering::SeqCst), 1);
+
+        // the deployment stays up while requests are in flight
+        serverless.scale_down_idle().await;
+        assert_eq!(scaler.desired.load(Ordering::SeqCst), 1);
+
+        drop((first, second));
+        serverless.scale_down_idle().await;
+        assert_eq!(scaler.desired.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn turns_away_requests_beyond_the_queue() {
+        let serverless = serverless(Arc::new(FakeScaler::default()), 1);
+
+        let held = tokio::spawn({
+            let serverless = serverless.clone();
+            async move { serverless.hold(&target()).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(matches!(serverless.hold(&target()).await, Err(IngressLoadBalancerError::General(Code::BackendUnavailable, _))));
+        assert!(held.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn counts_requests_until_their_body_is_sent() {
+        let serverless = serverless(Arc::new(FakeScaler::default()), 10);
+        let in_flight = || serverless.targets.lock().unwrap()[&target()].in_flight;
+
+        let body = serverless.hold(&target()).await.unwrap().hold_until_sent(Body::from("streamed"));
+        assert_eq!(in_flight(), 1);
+
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "streamed");
+        assert_eq!(in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn stays_up_while_other_replicas_are_busy() {
+        let scaler = Arc::new(FakeScaler::default());
+        scaler.desired.store(1, Ordering::SeqCst);
+        let serverless = serverless(scaler.clone(), 10);
+        let target = ServerlessTarget { idle_timeout: Some(Duration::from_secs(60)), ..target() };
+
+        let mut state = TargetState::new();
+        state.last_activity = Instant::now() - Duration::from_secs(120);
+        serverless.targets.lock().unwrap().insert(target.clone(), state);
+
+        // another replica served a request a moment ago
+        *scaler.last_activity.lock().unwrap() = Some(SystemTime::now());
+        serverless.scale_down_idle().await;
+        assert_eq!(scaler.desired.load(Ordering::SeqCst), 1);
+
+        *scaler.last_activity.lock().unwrap() = Some(SystemTime::now() - Duration::from_secs(120));
+        serverless.scale_down_idle().await;
+        assert_eq!(scaler.desired.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn records_activity_for_other_replicas() {
+        let scaler = Arc::new(FakeScaler::default());
+        let serverless = serverless(scaler.clone(), 10);
+
+        let held = serverless.hold(&target()).await.unwrap();
+        serverless.sync().await;
+        assert!(scaler.last_activity.lock().unwrap().is_some());
+        drop(held);
+
+        // once scaled down elsewhere, requests are held again until the deployment is back
+        scaler.ready.store(0, Ordering::SeqCst);
+        serverless.sync().await;
+        assert!(!*serverless.targets.lock().unwrap()[&target()].ready.borrow());
+    }
+
+    #[tokio::test]
+    async fn records_busy_deployments_once_per_fraction_of_the_idle_timeout() {
+        let scaler = Arc::new(FakeScaler::default());
+        let serverless = serverless(scaler.clone(), 10);
+        let target = ServerlessTarget { idle_timeout: Some(Duration::from_secs(60)), ..target() };
+
+        // another replica recorded activity a moment ago
+        let elsewhere = SystemTime::now() - Duration::from_secs(1);
+        *scaler.last_activity.lock().unwrap() = Some(elsewhere);
+        let _held = serverless.hold(&target).await.unwrap();
+        serverless.sync().await;
+        assert_eq!(*scaler.last_activity.lock().unwrap(), Some(elsewhere));
+
+        *scaler.last_activity.lock().unwrap() = None;
+        serverless.sync().await;
+        assert_eq!(*scaler.last_activity.lock().unwrap(), None);
+
+        serverless.targets.lock().unwrap().get_mut(&target).unwrap().recorded_activity = Some(Instant::now() - Duration::from_secs(20));
+        serverless.sync().await;
+        assert!(scaler.last_activity.lock().unwrap().is_some());
+    }
+}
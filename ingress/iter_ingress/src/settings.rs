@@ -0,0 +1,710 @@
+//! # Settings
+//!
+//! Runtime settings of the ingress. Each one can be set with a command line flag, an environment variable or a
+//! key in an optional YAML or TOML settings file, in that order of precedence. Everything is checked at startup,
+//! so a typo stops the ingress straight away instead of surfacing on the first request.
+//!
+//! Run `iter_ingress --help` to list every flag and its environment variable. Keys in the settings file are the
+//! flag names with underscores:
+//! ```yaml
+//! https_listen: ["0.0.0.0:443", "[::]:443"]
+//! acme_directory: staging
+//! acme_email: ops@example.com
+//! log_level: debug
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Parser;
+use iter_tls_acceptor::proxy_protocol::ProxyProtocolConfig;
+use serde::Deserialize;
+
+use crate::admin::AdminConfig;
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::ingress_status::IngressStatusSettings;
+use crate::lets_encrypt::AcmeDirectory;
+use crate::local_ca::LocalCaSettings;
+use crate::on_demand::OnDemandSettings;
+use crate::serverless::ServerlessSettings;
+use crate::cert_manager::{CertManagerSettings, OrphanAction};
+use crate::cert_storage::StorageKind;
+use crate::certificate_state::KeyType;
+use crate::stream_proxy::StreamSettings;
+use crate::tunnel::TunnelSettings;
+use crate::user_certs::SecretRef;
+
+pub const DEFAULT_NAMESPACE: &str = "iter";
+
+/// Settings as given on the command line, in the environment or in the settings file, before defaults are applied.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(name = "iter_ingress", about = "Routes http, https and raw tcp/udp traffic to kubernetes services")]
+#[serde(default, deny_unknown_fields)]
+pub struct SettingsArgs {
+    /// YAML or TOML file with defaults for any of these settings
+    #[arg(long, env = "ITER_SETTINGS_FILE")]
+    #[serde(skip)]
+    pub settings_file: Option<PathBuf>,
+
+    /// Addresses to serve http on, comma separated [default: 0.0.0.0:80]
+    #[arg(long, env = "ITER_HTTP_LISTEN", value_delimiter = ',')]
+    pub http_listen: Option<Vec<SocketAddr>>,
+
+    /// Addresses to serve https on, comma separated [default: 0.0.0.0:443]
+    #[arg(long, env = "ITER_HTTPS_LISTEN", value_delimiter = ',')]
+    pub https_listen: Option<Vec<SocketAddr>>,
+
+    /// Namespace holding the ingress' own secrets and config maps [default: iter]
+    #[arg(long, env = "ITER_NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// Label selector matching every ingress pod, which all serve ACME challenges [default: app=iter-ingress-pod]
+    #[arg(long, env = "ITER_PEER_SELECTOR")]
+    pub peer_selector: Option<String>,
+
+    /// Identity of this replica in leader election [default: a random name]
+    #[arg(long, env = "CURRENT_POD_NAME")]
+    pub pod_name: Option<String>,
+
+    /// Seconds a leader holds its lease without renewing it before another replica takes over [default: 15]
+    #[arg(long, env = "ITER_LEADER_LEASE_DURATION_SECONDS")]
+    pub leader_lease_duration_seconds: Option<u64>,
+
+    /// DNS domain of the cluster, used to address services [default: cluster.local]
+    #[arg(long, env = "ITER_CLUSTER_DOMAIN")]
+    pub cluster_domain: Option<String>,
+
+    /// One of error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "ITER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Issue certificates with ACME, needs a contact address [default: true]
+    #[arg(long, env = "ITER_ACME")]
+    pub acme: Option<bool>,
+
+    /// `production`, `staging` or the URL of any ACME directory [default: production]
+    #[arg(long, env = "ITER_ACME_DIRECTORY")]
+    pub acme_directory: Option<String>,
+
+    /// Contact address registered with the ACME account
+    #[arg(long, env = "ITER_ACME_EMAIL")]
+    pub acme_email: Option<String>,
+
+    /// Issue certificates from a private root CA instead of ACME, for clusters the CA can't reach [default: false]
+    #[arg(long, env = "ITER_LOCAL_CA")]
+    pub local_ca: Option<bool>,
+
+    /// Secret in the namespace, or file in cert_dir, with the local CA's root, generated if missing [default: iter-local-ca]
+    #[arg(long, env = "ITER_LOCAL_CA_SECRET")]
+    pub local_ca_secret: Option<String>,
+
+    /// Days certificates from the local CA are valid for [default: 7]
+    #[arg(long, env = "ITER_LOCAL_CA_CERT_DAYS")]
+    pub local_ca_cert_days: Option<u32>,
+
+    /// Renew certificates with fewer than this many days left [default: 30]
+    #[arg(long, env = "ITER_CERT_RENEW_BEFORE_DAYS")]
+    pub cert_renew_before_days: Option<u32>,
+
+    /// Longest wait before retrying a host whose certificate could not be issued [default: 21600]
+    #[arg(long, env = "ITER_CERT_MAX_RETRY_SECONDS")]
+    pub cert_max_retry_seconds: Option<u64>,
+
+    /// Key types to issue a certificate of for every host, comma separated rsa, ecdsa-p256 or ecdsa-p384, at most
+    /// one ECDSA [default: ecdsa-p256,rsa]
+    #[arg(long, env = "ITER_CERT_KEY_TYPES", value_delimiter = ',')]
+    pub cert_key_types: Option<Vec<String>>,
+
+    /// Days a host goes without needing its issued certificates before they are removed [default: 30]
+    #[arg(long, env = "ITER_CERT_ORPHAN_GRACE_DAYS")]
+    pub cert_orphan_grace_days: Option<u32>,
+
+    /// What happens to removed certificates, archive or delete [default: archive]
+    #[arg(long, env = "ITER_CERT_ORPHAN_ACTION")]
+    pub cert_orphan_action: Option<String>,
+
+    /// Revoke certificates before removing them [default: false]
+    #[arg(long, env = "ITER_CERT_REVOKE_ORPHANS")]
+    pub cert_revoke_orphans: Option<bool>,
+
+    /// Staple OCSP responses from the responders named in served certificates [default: true]
+    #[arg(long, env = "ITER_OCSP_STAPLING")]
+    pub ocsp_stapling: Option<bool>,
+
+    /// Hold handshakes of hosts without a certificate while one is issued, for allowed hosts [default: false]
+    #[arg(long, env = "ITER_ON_DEMAND_TLS")]
+    pub on_demand_tls: Option<bool>,
+
+    /// Hosts issued for on demand besides ingress hosts, comma separated, exact or `*.example.com`
+    #[arg(long, env = "ITER_ON_DEMAND_HOSTS", value_delimiter = ',')]
+    pub on_demand_hosts: Option<Vec<String>>,
+
+    /// URL asked `?host=<host>` about any other host, which is issued for if it answers with a 2xx
+    #[arg(long, env = "ITER_ON_DEMAND_WEBHOOK")]
+    pub on_demand_webhook: Option<String>,
+
+    /// Hosts approved or waited for on demand at once, further handshakes get the fallback [default: 10]
+    #[arg(long, env = "ITER_ON_DEMAND_MAX_CONCURRENT")]
+    pub on_demand_max_concurrent: Option<usize>,
+
+    /// New hosts approved on demand per hour [default: 20]
+    #[arg(long, env = "ITER_ON_DEMAND_MAX_PER_HOUR")]
+    pub on_demand_max_per_hour: Option<u32>,
+
+    /// Longest a handshake is held for its certificate [default: 30]
+    #[arg(long, env = "ITER_ON_DEMAND_TIMEOUT_SECONDS")]
+    pub on_demand_timeout_seconds: Option<u64>,
+
+    /// Route from this YAML or TOML file instead of watching ingresses
+    #[arg(long, env = "ITER_CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Store certificates in this directory instead of kubernetes secrets
+    #[arg(long, env = "ITER_CERT_DIR")]
+    pub cert_dir: Option<PathBuf>,
+
+    /// Where certificates are stored: kubernetes, disk or memory [default: kubernetes when available and cert_dir
+    /// isn't set, disk otherwise]
+    #[arg(long, env = "ITER_CERT_STORAGE")]
+    pub cert_storage: Option<String>,
+
+    /// `kubernetes.io/tls` secret, `name` or `namespace/name`, served to clients without sni or for unknown hosts
+    /// [default: a self-signed certificate]
+    #[arg(long, env = "ITER_FALLBACK_CERT_SECRET")]
+    pub fallback_cert_secret: Option<String>,
+
+    /// Service, `name` or `namespace/name`, whose load balancer addresses are written to the status of routed ingresses
+    #[arg(long, env = "ITER_PUBLISH_SERVICE")]
+    pub publish_service: Option<String>,
+
+    /// Ips or hostnames written to the status of routed ingresses instead of a service's, comma separated
+    #[arg(long, env = "ITER_PUBLISH_STATUS_ADDRESSES", value_delimiter = ',')]
+    pub publish_status_addresses: Option<Vec<String>>,
+
+    /// Address of the admin API [default: 127.0.0.1:9090]
+    #[arg(long, env = "ITER_ADMIN_ADDR")]
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Bearer token for the admin API, which only starts when one is set
+    #[arg(long, env = "ITER_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Serve the tcp-services and udp-services config maps [default: true]
+    #[arg(long, env = "ITER_STREAM_PROXY")]
+    pub stream_proxy: Option<bool>,
+
+    /// Close tcp streams with no traffic for this long [default: 600]
+    #[arg(long, env = "ITER_STREAM_IDLE_TIMEOUT_SECONDS")]
+    pub stream_idle_timeout_seconds: Option<u64>,
+
+    /// Forget udp sessions with no traffic for this long [default: 60]
+    #[arg(long, env = "ITER_UDP_SESSION_TIMEOUT_SECONDS")]
+    pub udp_session_timeout_seconds: Option<u64>,
+
+    /// Maximum tcp connections or udp sessions per stream listener [default: 1024]
+    #[arg(long, env = "ITER_STREAM_MAX_CONNECTIONS")]
+    pub stream_max_connections: Option<usize>,
+
+    /// Forward tls untouched for hosts which ask for it [default: true]
+    #[arg(long, env = "ITER_TLS_PASSTHROUGH")]
+    pub tls_passthrough: Option<bool>,
+
+    /// Close websocket and other tunnels with no traffic for this long [default: 300]
+    #[arg(long, env = "ITER_TUNNEL_IDLE_TIMEOUT_SECONDS")]
+    pub tunnel_idle_timeout_seconds: Option<u64>,
+
+    /// Close tunnels this long after they opened, 0 for no limit [default: 86400]
+    #[arg(long, env = "ITER_TUNNEL_MAX_LIFETIME_SECONDS")]
+    pub tunnel_max_lifetime_seconds: Option<u64>,
+
+    /// Scale serverless deployments to zero after this long without requests [default: 900]
+    #[arg(long, env = "ITER_SERVERLESS_IDLE_TIMEOUT_SECONDS")]
+    pub serverless_idle_timeout_seconds: Option<u64>,
+
+    /// Answer requests with a 503 if their serverless deployment isn't ready after this long [default: 60]
+    #[arg(long, env = "ITER_SERVERLESS_HOLD_TIMEOUT_SECONDS")]
+    pub serverless_hold_timeout_seconds: Option<u64>,
+
+    /// Requests held per serverless deployment while it scales up [default: 100]
+    #[arg(long, env = "ITER_SERVERLESS_MAX_HELD_REQUESTS")]
+    pub serverless_max_held_requests: Option<usize>,
+
+    /// CIDRs of load balancers allowed to send PROXY protocol headers, comma separated
+    #[arg(long, env = "ITER_PROXY_PROTOCOL_TRUSTED_CIDRS", value_delimiter = ',')]
+    pub proxy_protocol_trusted_cidrs: Option<Vec<String>>,
+
+    /// Time between failing health checks and closing listeners on shutdown [default: 5]
+    #[arg(long, env = "ITER_SHUTDOWN_DRAIN_DELAY_SECONDS")]
+    pub shutdown_drain_delay_seconds: Option<u64>,
+
+    /// Time in-flight work gets to finish once listeners are closed [default: 30]
+    #[arg(long, env = "ITER_SHUTDOWN_GRACE_SECONDS")]
+    pub shutdown_grace_seconds: Option<u64>,
+}
+
+impl SettingsArgs {
+    /// Fills in everything not set here from `other`.
+    fn or(self, other: SettingsArgs) -> SettingsArgs {
+        SettingsArgs {
+            settings_file: self.settings_file.or(other.settings_file),
+            http_listen: self.http_listen.or(other.http_listen),
+            https_listen: self.https_listen.or(other.https_listen),
+            namespace: self.namespace.or(other.namespace),
+            peer_selector: self.peer_selector.or(other.peer_selector),
+            pod_name: self.pod_name.or(other.pod_name),
+            leader_lease_duration_seconds: self.leader_lease_duration_seconds.or(other.leader_lease_duration_seconds),
+            cluster_domain: self.cluster_domain.or(other.cluster_domain),
+            log_level: self.log_level.or(other.log_level),
+            acme: self.acme.or(other.acme),
+            acme_directory: self.acme_directory.or(other.acme_directory),
+            acme_email: self.acme_email.or(other.acme_email),
+            local_ca: self.local_ca.or(other.local_ca),
+            local_ca_secret: self.local_ca_secret.or(other.local_ca_secret),
+            local_ca_cert_days: self.local_ca_cert_days.or(other.local_ca_cert_days),
+            cert_renew_before_days: self.cert_renew_before_days.or(other.cert_renew_before_days),
+            cert_max_retry_seconds: self.cert_max_retry_seconds.or(other.cert_max_retry_seconds),
+            cert_key_types: self.cert_key_types.or(other.cert_key_types),
+            cert_orphan_grace_days: self.cert_orphan_grace_days.or(other.cert_orphan_grace_days),
+            cert_orphan_action: self.cert_orphan_action.or(other.cert_orphan_action),
+            cert_revoke_orphans: self.cert_revoke_orphans.or(other.cert_revoke_orphans),
+            ocsp_stapling: self.ocsp_stapling.or(other.ocsp_stapling),
+            on_demand_tls: self.on_demand_tls.or(other.on_demand_tls),
+            on_demand_hosts: self.on_demand_hosts.or(other.on_demand_hosts),
+            on_demand_webhook: self.on_demand_webhook.or(other.on_demand_webhook),
+            on_demand_max_concurrent: self.on_demand_max_concurrent.or(other.on_demand_max_concurrent),
+            on_demand_max_per_hour: self.on_demand_max_per_hour.or(other.on_demand_max_per_hour),
+            on_demand_timeout_seconds: self.on_demand_timeout_seconds.or(other.on_demand_timeout_seconds),
+            config_file: self.config_file.or(other.config_file),
+            cert_dir: self.cert_dir.or(other.cert_dir),
+            cert_storage: self.cert_storage.or(other.cert_storage),
+            fallback_cert_secret: self.fallback_cert_secret.or(other.fallback_cert_secret),
+            publish_service: self.publish_service.or(other.publish_service),
+            publish_status_addresses: self.publish_status_addresses.or(other.publish_status_addresses),
+            admin_addr: self.admin_addr.or(other.admin_addr),
+            admin_token: self.admin_token.or(other.admin_token),
+            stream_proxy: self.stream_proxy.or(other.stream_proxy),
+            stream_idle_timeout_seconds: self.stream_idle_timeout_seconds.or(other.stream_idle_timeout_seconds),
+            udp_session_timeout_seconds: self.udp_session_timeout_seconds.or(other.udp_session_timeout_seconds),
+            stream_max_connections: self.stream_max_connections.or(other.stream_max_connections),
+            tls_passthrough: self.tls_passthrough.or(other.tls_passthrough),
+            tunnel_idle_timeout_seconds: self.tunnel_idle_timeout_seconds.or(other.tunnel_idle_timeout_seconds),
+            tunnel_max_lifetime_seconds: self.tunnel_max_lifetime_seconds.or(other.tunnel_max_lifetime_seconds),
+            serverless_idle_timeout_seconds: self.serverless_idle_timeout_seconds.or(other.serverless_idle_timeout_seconds),
+            serverless_hold_timeout_seconds: self.serverless_hold_timeout_seconds.or(other.serverless_hold_timeout_seconds),
+            serverless_max_held_requests: self.serverless_max_held_requests.or(other.serverless_max_held_requests),
+            proxy_protocol_trusted_cidrs: self.proxy_protocol_trusted_cidrs.or(other.proxy_protocol_trusted_cidrs),
+            shutdown_drain_delay_seconds: self.shutdown_drain_delay_seconds.or(other.shutdown_drain_delay_seconds),
+            shutdown_grace_seconds: self.shutdown_grace_seconds.or(other.shutdown_grace_seconds),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<SettingsArgs, IngressLoadBalancerError> {
+        let invalid = |e: String| IngressLoadBalancerError::general(Code::InvalidConfig, format!("{}: {}", path.display(), e));
+        let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+            _ => Err(invalid("expected a .yaml, .yml or .toml file".to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcmeSettings {
+    pub directory: AcmeDirectory,
+    pub email: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub http_listen: Vec<SocketAddr>,
+    pub https_listen: Vec<SocketAddr>,
+    pub namespace: String,
+    pub peer_selector: String,
+    pub pod_name: String,
+    pub leader_lease_duration: Duration,
+    pub cluster_domain: String,
+    pub log_level: tracing::Level,
+    /// `None` when certificates aren't issued with ACME
+    pub acme: Option<AcmeSettings>,
+    /// `None` when certificates aren't issued by the local CA, never set together with `acme`
+    pub local_ca: Option<LocalCaSettings>,
+    pub certificates: CertManagerSettings,
+    pub ocsp_stapling: bool,
+    /// `None` when certificates aren't issued on demand
+    pub on_demand: Option<OnDemandSettings>,
+    pub config_file: Option<PathBuf>,
+    pub cert_dir: Option<PathBuf>,
+    /// `None` picks kubernetes or disk, see [`crate::cert_storage::from_settings`]
+    pub cert_storage: Option<StorageKind>,
+    /// `None` serves a self-signed fallback certificate
+    pub fallback_cert_secret: Option<SecretRef>,
+    pub ingress_status: IngressStatusSettings,
+    pub admin: AdminConfig,
+    pub stream_proxy: bool,
+    pub stream: StreamSettings,
+    pub tls_passthrough: bool,
+    pub tunnel: TunnelSettings,
+    pub serverless: ServerlessSettings,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub shutdown_drain_delay: Duration,
+    pub shutdown_grace_period: Duration,
+}
+
+impl Settings {
+    /// Reads the command line, environment and settings file.
+    pub fn load() -> Result<Settings, IngressLoadBalancerError> {
+        let args = SettingsArgs::parse();
+
+        let args = match &args.settings_file {
+            Some(path) => {
+                let file = SettingsArgs::from_file(path)?;
+                args.or(file)
+            }
+            None => args,
+        };
+
+        Settings::resolve(args)
+    }
+
+    /// Applies defaults and validates everything.
+    pub fn resolve(args: SettingsArgs) -> Result<Settings, IngressLoadBalancerError> {
+        let invalid = |setting: &str, e: String| IngressLoadBalancerError::general(Code::InvalidConfig, format!("{}: {}", setting, e));
+        let seconds = |value: Option<u64>, default: u64| Duration::from_secs(value.unwrap_or(default));
+
+        let http_listen = args.http_listen.unwrap_or_else(|| vec![SocketAddr::from(([0, 0, 0, 0], 80))]);
+        let https_listen = args.https_listen.unwrap_or_else(|| vec![SocketAddr::from(([0, 0, 0, 0], 443))]);
+        if http_listen.is_empty() && https_listen.is_empty() {
+            return Err(invalid("http_listen", "at least one http or https listener is needed".to_string()));
+        }
+
+        let namespace = args.namespace.unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+        if !is_dns_label(&namespace) {
+            return Err(invalid("namespace", format!("{:?} is not a valid namespace", namespace)));
+        }
+
+        let cluster_domain = args.cluster_domain.unwrap_or_else(|| "cluster.local".to_string());
+        if !cluster_domain.split('.').all(is_dns_label) {
+            return Err(invalid("cluster_domain", format!("{:?} is not a valid domain", cluster_domain)));
+        }
+
+        let leader_lease_duration = seconds(args.leader_lease_duration_seconds, 15);
+        if leader_lease_duration < Duration::from_secs(3) {
+            return Err(invalid("leader_lease_duration_seconds", "must be at least 3".to_string()));
+        }
+
+        let log_level = tracing::Level::from_str(args.log_level.as_deref().unwrap_or("info"))
+            .map_err(|_| invalid("log_level", "expected one of error, warn, info, debug or trace".to_string()))?;
+
+        let directory = AcmeDirectory::from_str(args.acme_directory.as_deref().unwrap_or("production"))
+            .map_err(|e| invalid("acme_directory", e))?;
+        let local_ca = match args.local_ca.unwrap_or(false) {
+            true => {
+                let local_ca = LocalCaSettings {
+                    secret: args.local_ca_secret.filter(|secret| !secret.is_empty()).unwrap_or_else(|| "iter-local-ca".to_string()),
+                    cert_days: args.local_ca_cert_days.unwrap_or(7),
+                };
+                if !local_ca.secret.split('.').all(is_dns_label) {
+                    return Err(invalid("local_ca_secret", format!("{:?} is not a secret name", local_ca.secret)));
+                }
+                if local_ca.cert_days < 2 || local_ca.cert_days > 397 {
+                    return Err(invalid("local_ca_cert_days", "must be between 2 and 397".to_string()));
+                }
+                Some(local_ca)
+            }
+            false => None,
+        };
+
+        let acme = match (args.acme.unwrap_or(true), args.acme_email) {
+            _ if local_ca.is_some() && args.acme == Some(true) => {
+                return Err(invalid("local_ca", "certificates are issued with either ACME or the local CA, not both".to_string()));
+            }
+            // the local CA takes over from the default
+            _ if local_ca.is_some() => None,
+            (true, Some(email)) => {
+                if !is_email(&email) {
+                    return Err(invalid("acme_email", format!("{:?} is not an email address", email)));
+                }
+                Some(AcmeSettings { directory, email })
+            }
+            (true, None) => {
+                tracing::warn!("settings: no acme_email is set, certificates won't be issued with ACME");
+                None
+            }
+            (false, _) => None,
+        };
+
+        let key_types = match args.cert_key_types {
+            Some(key_types) => key_types.iter()
+                .map(|key_type| key_type.trim().parse::<KeyType>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid("cert_key_types", e))?,
+            None => vec![KeyType::EcdsaP256, KeyType::Rsa],
+        };
+        if key_types.is_empty() || key_types.iter().filter(|key_type| key_type.is_ecdsa()).count() > 1 || key_types.iter().filter(|key_type| !key_type.is_ecdsa()).count() > 1 {
+            return Err(invalid("cert_key_types", "expected at least one key type, at most one rsa and one ecdsa".to_string()));
+        }
+
+        let cert_storage = args.cert_storage
+            .filter(|kind| !kind.is_empty())
+            .map(|kind| kind.parse::<StorageKind>())
+            .transpose()
+            .map_err(|e| invalid("cert_storage", e))?;
+
+        let certificates = CertManagerSettings {
+            renew_before_days: args.cert_renew_before_days.unwrap_or(30),
+            max_retry: seconds(args.cert_max_retry_seconds, 21_600),
+            key_types,
+            orphan_grace: Duration::from_secs(args.cert_orphan_grace_days.unwrap_or(30) as u64 * 24 * 60 * 60),
+            orphan_action: args.cert_orphan_action
+                .filter(|action| !action.is_empty())
+                .map(|action| action.parse::<OrphanAction>())
+                .transpose()
+                .map_err(|e| invalid("cert_orphan_action", e))?
+                .unwrap_or(OrphanAction::Archive),
+            revoke_orphans: args.cert_revoke_orphans.unwrap_or(false),
+        };
+        if certificates.renew_before_days == 0 || certificates.renew_before_days >= 90 {
+            return Err(invalid("cert_renew_before_days", "must be between 1 and 89".to_string()));
+        }
+        if certificates.orphan_grace.is_zero() {
+            return Err(invalid("cert_orphan_grace_days", "must be at least 1".to_string()));
+        }
+        if certificates.max_retry < Duration::from_secs(60) {
+            return Err(invalid("cert_max_retry_seconds", "must be at least 60".to_string()));
+        }
+
+        let on_demand = match args.on_demand_tls.unwrap_or(false) {
+            true => {
+                if acme.is_none() && local_ca.is_none() {
+                    return Err(invalid("on_demand_tls", "certificates are issued with ACME, which needs acme_email, or the local CA".to_string()));
+                }
+
+                let allowed_hosts: Vec<String> = args.on_demand_hosts.unwrap_or_default().iter()
+                    .map(|host| host.trim().to_ascii_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect();
+                if let Some(host) = allowed_hosts.iter().find(|host| !host.trim_start_matches("*.").split('.').all(is_dns_label)) {
+                    return Err(invalid("on_demand_hosts", format!("{:?} is not a host or *.domain", host)));
+                }
+
+                let approval_webhook = match args.on_demand_webhook.filter(|url| !url.is_empty()) {
+                    Some(url) => {
+                        let uri = url.parse::<hyper::Uri>().map_err(|e| invalid("on_demand_webhook", e.to_string()))?;
+                        if !matches!(uri.scheme_str(), Some("http") | Some("https")) {
+                            return Err(invalid("on_demand_webhook", format!("{:?} is not an http or https url", url)));
+                        }
+                        Some(uri)
+                    }
+                    None => None,
+                };
+
+                let on_demand = OnDemandSettings {
+                    allowed_hosts,
+                    approval_webhook,
+                    max_concurrent: args.on_demand_max_concurrent.unwrap_or(10),
+                    max_per_hour: args.on_demand_max_per_hour.unwrap_or(20),
+                    timeout: seconds(args.on_demand_timeout_seconds, 30),
+                };
+                if on_demand.max_concurrent == 0 {
+                    return Err(invalid("on_demand_max_concurrent", "must be at least 1".to_string()));
+                }
+                if on_demand.timeout.is_zero() {
+                    return Err(invalid("on_demand_timeout_seconds", "must be at least 1".to_string()));
+                }
+                Some(on_demand)
+            }
+            false => None,
+        };
+
+        let fallback_cert_secret = match args.fallback_cert_secret.filter(|secret| !secret.is_empty()) {
+            Some(secret) => {
+                let (secret_namespace, name) = secret.split_once('/').unwrap_or((&namespace, &secret));
+                if !is_dns_label(secret_namespace) || !name.split('.').all(is_dns_label) {
+                    return Err(invalid("fallback_cert_secret", format!("{:?} is not a secret name", secret)));
+                }
+                Some(SecretRef { namespace: secret_namespace.to_string(), name: name.to_string() })
+            }
+            None => None,
+        };
+
+        let publish_service = match args.publish_service.filter(|service| !service.is_empty()) {
+            Some(service) => {
+                let (service_namespace, name) = service.split_once('/').unwrap_or((&namespace, &service));
+                if !is_dns_label(service_namespace) || !is_dns_label(name) {
+                    return Err(invalid("publish_service", format!("{:?} is not a service name", service)));
+                }
+                Some((service_namespace.to_string(), name.to_string()))
+            }
+            None => None,
+        };
+        let addresses: Vec<String> = args.publish_status_addresses.unwrap_or_default().iter()
+            .map(|address| address.trim().to_ascii_lowercase())
+            .filter(|address| !address.is_empty())
+            .collect();
+        if let Some(address) = addresses.iter().find(|address| address.parse::<IpAddr>().is_err() && !address.split('.').all(is_dns_label)) {
+            return Err(invalid("publish_status_addresses", format!("{:?} is not an ip or hostname", address)));
+        }
+        let ingress_status = IngressStatusSettings { addresses, publish_service };
+
+        let stream = StreamSettings {
+            idle_timeout: seconds(args.stream_idle_timeout_seconds, 600),
+            udp_session_timeout: seconds(args.udp_session_timeout_seconds, 60),
+            max_connections: args.stream_max_connections.unwrap_or(1024),
+        };
+        if stream.max_connections == 0 {
+            return Err(invalid("stream_max_connections", "must be at least 1".to_string()));
+        }
+
+        let tunnel = TunnelSettings {
+            idle_timeout: seconds(args.tunnel_idle_timeout_seconds, 300),
+            max_lifetime: Some(args.tunnel_max_lifetime_seconds.unwrap_or(86_400))
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+        };
+        if tunnel.idle_timeout.is_zero() {
+            return Err(invalid("tunnel_idle_timeout_seconds", "must be at least 1".to_string()));
+        }
+
+        let serverless = ServerlessSettings {
+            idle_timeout: seconds(args.serverless_idle_timeout_seconds, 900),
+            hold_timeout: seconds(args.serverless_hold_timeout_seconds, 60),
+            max_held_requests: args.serverless_max_held_requests.unwrap_or(100),
+        };
+        if serverless.hold_timeout.is_zero() {
+            return Err(invalid("serverless_hold_timeout_seconds", "must be at least 1".to_string()));
+        }
+
+        let proxy_protocol = ProxyProtocolConfig::parse(&args.proxy_protocol_trusted_cidrs.unwrap_or_default().join(","))
+            .map_err(|e| invalid("proxy_protocol_trusted_cidrs", e))?;
+
+        Ok(Settings {
+            http_listen,
+            https_listen,
+            namespace,
+            peer_selector: args.peer_selector.unwrap_or_else(|| "app=iter-ingress-pod".to_string()),
+            pod_name: args.pod_name.unwrap_or_else(|| format!("iter-ingress-{}", uuid::Uuid::new_v4())),
+            leader_lease_duration,
+            cluster_domain,
+            log_level,
+            acme,
+            local_ca,
+            certificates,
+            ocsp_stapling: args.ocsp_stapling.unwrap_or(true),
+            on_demand,
+            config_file: args.config_file.filter(|path| !path.as_os_str().is_empty()),
+            cert_dir: args.cert_dir.filter(|path| !path.as_os_str().is_empty()),
+            cert_storage,
+            fallback_cert_secret,
+            ingress_status,
+            admin: AdminConfig {
+                addr: args.admin_addr.unwrap_or(SocketAddr::from(([127, 0, 0, 1], 9090))),
+                token: args.admin_token.filter(|token| !token.is_empty()),
+            },
+            stream_proxy: args.stream_proxy.unwrap_or(true),
+            stream,
+            tls_passthrough: args.tls_passthrough.unwrap_or(true),
+            tunnel,
+            serverless,
+            proxy_protocol,
+            shutdown_drain_delay: seconds(args.shutdown_drain_delay_seconds, 5),
+            shutdown_grace_period: seconds(args.shutdown_grace_seconds, 30),
+        })
+    }
+}
+
+/// The in-cluster address of a service, e.g. `web.default.svc.cluster.local`.
+pub fn service_host(service: &str, namespace: &str, cluster_domain: &str) -> String {
+    format!("{}.{}.svc.{}", service, namespace, cluster_domain)
+}
+
+fn is_dns_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+fn is_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => !local.is_empty() && domain.contains('.') && !email.chars().any(char::is_whitespace),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> SettingsArgs {
+        SettingsArgs::try_parse_from(std::iter::once("iter_ingress").chain(flags.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn applies_defaults() {
+        let settings = Settings::resolve(SettingsArgs::default()).unwrap();
+
+        assert_eq!(settings.http_listen, vec![SocketAddr::from(([0, 0, 0, 0], 80))]);
+        assert_eq!(settings.namespace, DEFAULT_NAMESPACE);
+        assert_eq!(service_host("web", "default", &settings.cluster_domain), "web.default.svc.cluster.local");
+        assert!(settings.acme.is_none());
+        assert!(settings.stream_proxy);
+        assert_eq!(settings.certificates.key_types, vec![KeyType::EcdsaP256, KeyType::Rsa]);
+        assert_eq!(settings.certificates.orphan_action, OrphanAction::Archive);
+        assert!(!settings.certificates.revoke_orphans);
+    }
+
+    #[test]
+    fn flags_override_the_settings_file() {
+        let file: SettingsArgs = serde_yaml::from_str("https_listen: ['[::]:8443']\nnamespace: from-file\nacme_email: ops@example.com").unwrap();
+        let settings = Settings::resolve(args(&["--namespace", "from-flag", "--acme-directory", "staging"]).or(file)).unwrap();
+
+        assert_eq!(settings.namespace, "from-flag");
+        assert_eq!(settings.https_listen, vec!["[::]:8443".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(settings.acme.unwrap().directory, AcmeDirectory::Staging);
+    }
+
+    #[test]
+    fn publishes_a_service_or_fixed_addresses() {
+        let settings = Settings::resolve(args(&["--publish-service", "iter-ingress"])).unwrap();
+        assert_eq!(settings.ingress_status.publish_service, Some((DEFAULT_NAMESPACE.to_string(), "iter-ingress".to_string())));
+
+        let settings = Settings::resolve(args(&["--publish-status-addresses", "203.0.113.7, LB.example.com"])).unwrap();
+        assert_eq!(settings.ingress_status.addresses, vec!["203.0.113.7", "lb.example.com"]);
+    }
+
+    #[test]
+    fn local_ca_replaces_acme() {
+        let settings = Settings::resolve(args(&["--local-ca", "true", "--acme-email", "ops@example.com"])).unwrap();
+
+        assert!(settings.acme.is_none());
+        assert_eq!(settings.local_ca.unwrap().secret, "iter-local-ca");
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Settings::resolve(args(&["--namespace", "Not_Valid"])).is_err());
+        assert!(Settings::resolve(args(&["--acme-email", " @framework.tools"])).is_err());
+        assert!(Settings::resolve(args(&["--acme-directory", "ftp://acme"])).is_err());
+        assert!(Settings::resolve(args(&["--log-level", "loud"])).is_err());
+        assert!(Settings::resolve(args(&["--proxy-protocol-trusted-cidrs", "10.0.0.0/8,nope"])).is_err());
+        assert!(Settings::resolve(args(&["--fallback-cert-secret", "web/Not_Valid"])).is_err());
+        assert!(Settings::resolve(args(&["--publish-service", "ingress/Not_Valid"])).is_err());
+        assert!(Settings::resolve(args(&["--publish-status-addresses", "203.0.113.7,not an address"])).is_err());
+        assert!(Settings::resolve(args(&["--cert-key-types", "ecdsa-p256,ecdsa-p384"])).is_err());
+        assert!(Settings::resolve(args(&["--cert-key-types", "dsa"])).is_err());
+        assert!(Settings::resolve(args(&["--cert-storage", "etcd"])).is_err());
+        assert!(Settings::resolve(args(&["--cert-orphan-grace-days", "0"])).is_err());
+        assert!(Settings::resolve(args(&["--cert-orphan-action", "shred"])).is_err());
+        assert!(Settings::resolve(args(&["--on-demand-tls", "true"])).is_err());
+        assert!(Settings::resolve(args(&["--local-ca", "true", "--acme", "true"])).is_err());
+        assert!(Settings::resolve(args(&["--local-ca", "true", "--local-ca-cert-days", "1"])).is_err());
+        assert!(Settings::resolve(args(&["--acme-email", "ops@example.com", "--on-demand-tls", "true", "--on-demand-hosts", "*.Not_Valid"])).is_err());
+        assert!(Settings::resolve(args(&["--acme-email", "ops@example.com", "--on-demand-tls", "true", "--on-demand-webhook", "ftp://approve"])).is_err());
+        assert!(serde_yaml::from_str::<SettingsArgs>("namepsace: typo").is_err());
+    }
+}
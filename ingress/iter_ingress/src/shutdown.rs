@@ -0,0 +1,127 @@
+//! # Shutdown
+//!
+//! Graceful shutdown and connection draining.
+//!
+//! On SIGTERM (or SIGINT) the ingress:
+//! 1. starts failing `/health-check` so load balancers stop sending new traffic,
+//! 2. waits for the drain delay, giving load balancers time to notice,
+//! 3. stops accepting new connections and lets in-flight requests and upgraded connections finish,
+//! 4. exits once everything has finished or the grace period has run out.
+//!
+//! The drain delay and grace period are part of the [`Settings`](crate::settings::Settings).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownState {
+    Running,
+    /// health checks fail but connections are still accepted
+    Draining,
+    /// listeners are closed and in-flight work is finishing
+    Stopping,
+}
+
+pub struct Shutdown {
+    pub drain_delay: Duration,
+    pub grace_period: Duration,
+    state: watch::Sender<ShutdownState>,
+    receiver: watch::Receiver<ShutdownState>,
+}
+
+impl Shutdown {
+    pub fn new(drain_delay: Duration, grace_period: Duration) -> Shutdown {
+        let (state, receiver) = watch::channel(ShutdownState::Running);
+
+        Shutdown {
+            drain_delay,
+            grace_period,
+            state,
+            receiver,
+        }
+    }
+
+    pub fn state(&self) -> ShutdownState {
+        *self.receiver.borrow()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state() != ShutdownState::Running
+    }
+
+    fn advance(&self, state: ShutdownState) {
+        if self.state() < state {
+            let _ = self.state.send(state);
+        }
+    }
+
+    /// Resolves once the shutdown has reached at least the given state.
+    pub async fn reached(&self, state: ShutdownState) {
+        let mut receiver = self.receiver.clone();
+        while *receiver.borrow() < state {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Resolves when listeners should stop accepting connections, for use with `with_graceful_shutdown`.
+    pub async fn listeners_closed(self: Arc<Self>) {
+        self.reached(ShutdownState::Stopping).await
+    }
+
+    /// Waits for SIGTERM or SIGINT, then drains: health checks start failing, and after the drain delay the
+    /// listeners are closed.
+    pub async fn drain_on_signal(&self) {
+        wait_for_signal().await;
+        info!("shutdown: signal received, draining for {:?}", self.drain_delay);
+        self.advance(ShutdownState::Draining);
+
+        tokio::time::sleep(self.drain_delay).await;
+        info!("shutdown: closing listeners, waiting up to {:?} for in-flight work", self.grace_period);
+        self.advance(ShutdownState::Stopping);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listeners_close_only_once_stopping() {
+        let shutdown = Arc::new(Shutdown::new(Duration::from_secs(0), Duration::from_secs(0)));
+        let listeners_closed = tokio::spawn(shutdown.clone().listeners_closed());
+
+        shutdown.advance(ShutdownState::Draining);
+        assert!(shutdown.is_draining());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!listeners_closed.is_finished());
+
+        shutdown.advance(ShutdownState::Stopping);
+        tokio::time::timeout(Duration::from_secs(1), listeners_closed).await.unwrap().unwrap();
+
+        // the state never moves backwards
+        shutdown.advance(ShutdownState::Draining);
+        assert_eq!(shutdown.state(), ShutdownState::Stopping);
+    }
+}
@@ -0,0 +1,662 @@
+//! # Stream Proxy
+//!
+//! Layer 4 proxying of raw tcp streams and udp datagrams to services, for workloads such as postgres, redis or
+//! mqtt which don't speak http.
+//!
+//! Listeners are declared in the `tcp-services` and `udp-services` config maps in the ingress' namespace, using the
+//! same format as ingress-nginx. Each key is a port to listen on and each value the service port to forward to:
+//! ```yaml
+//! apiVersion: v1
+//! kind: ConfigMap
+//! metadata:
+//!   name: tcp-services
+//!   namespace: iter
+//! data:
+//!   "5432": "databases/postgres:5432"
+//! ```
+//!
+//! Timeouts and connection limits are part of the [`Settings`](crate::settings::Settings).
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::ListParams;
+use kube::runtime::watcher::Event;
+use kube::{runtime, Api, Client, ResourceExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::metrics::{Metrics, StreamListenerMetrics};
+use crate::settings::service_host;
+use crate::shutdown::{Shutdown, ShutdownState};
+
+pub const TCP_SERVICES: &str = "tcp-services";
+pub const UDP_SERVICES: &str = "udp-services";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Datagrams kept per client while its udp session is being opened, later ones are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamListener {
+    pub protocol: Protocol,
+    pub port: u16,
+}
+
+impl std::fmt::Display for StreamListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.protocol {
+            Protocol::Tcp => write!(f, "tcp/{}", self.port),
+            Protocol::Udp => write!(f, "udp/{}", self.port),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamSettings {
+    pub idle_timeout: Duration,
+    pub udp_session_timeout: Duration,
+    pub max_connections: usize,
+}
+
+/// Parses the data of a `tcp-services` or `udp-services` config map into listeners and `address:port` targets.
+///
+/// Values are `namespace/service:port`, anything after the port (such as ingress-nginx's `:PROXY` flags) is ignored.
+pub fn parse_services(protocol: Protocol, data: &BTreeMap<String, String>, cluster_domain: &str) -> HashMap<StreamListener, String> {
+    let mut services = HashMap::new();
+
+    for (port, target) in data {
+        let parsed: Option<(u16, String)> = try {
+            let port = port.trim().parse::<u16>().ok()?;
+            let (namespace, service) = target.trim().split_once('/')?;
+            let mut service = service.split(':');
+            let name = service.next().filter(|name| !name.is_empty())?;
+            let service_port = service.next()?.parse::<u16>().ok()?;
+
+            (port, format!("{}:{}", service_host(name, namespace, cluster_domain), service_port))
+        };
+
+        match parsed {
+            Some((port, target)) => {
+                services.insert(StreamListener { protocol, port }, target);
+            }
+            None => warn!("stream_proxy: ignoring invalid service {:?}: {:?}", port, target),
+        }
+    }
+
+    services
+}
+
+struct RunningListener {
+    target: watch::Sender<String>,
+    task: JoinHandle<()>,
+}
+
+pub struct StreamProxy {
+    settings: StreamSettings,
+    namespace: String,
+    cluster_domain: String,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<Shutdown>,
+    listeners: Mutex<HashMap<StreamListener, RunningListener>>,
+}
+
+impl StreamProxy {
+    pub fn new(settings: StreamSettings, namespace: String, cluster_domain: String, metrics: Arc<Metrics>, shutdown: Arc<Shutdown>) -> StreamProxy {
+        StreamProxy {
+            settings,
+            namespace,
+            cluster_domain,
+            metrics,
+            shutdown,
+            listeners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts, retargets and stops listeners so exactly the given ones are running.
+    ///
+    /// Stopping a listener closes its socket, tcp connections it already accepted are left to finish.
+    pub async fn apply(&self, desired: HashMap<StreamListener, String>) {
+        let mut listeners = self.listeners.lock().await;
+
+        listeners.retain(|listener, running| {
+            let keep = desired.contains_key(listener) && !running.task.is_finished();
+            if !keep {
+                info!("stream_proxy: stopping {}", listener);
+                running.task.abort();
+            }
+            keep
+        });
+
+        for (listener, target) in desired {
+            if let Some(running) = listeners.get(&listener) {
+                if *running.target.borrow() != target {
+                    info!("stream_proxy: {} now forwards to {}", listener, target);
+                    let _ = running.target.send(target);
+                }
+                continue;
+            }
+
+            let metrics = self.metrics.stream_listener(&listener.to_string()).await;
+            let (target_sender, target_receiver) = watch::channel(target.clone());
+            // `[::]` accepts ipv4 clients too, `0.0.0.0` is only used on hosts without ipv6
+            let addrs = [SocketAddr::from(([0u16; 8], listener.port)), SocketAddr::from(([0, 0, 0, 0], listener.port))];
+
+            let task = match listener.protocol {
+                Protocol::Tcp => match TcpListener::bind(&addrs[..]).await {
+                    Ok(socket) => tokio::spawn(run_tcp(socket, target_receiver, self.settings.clone(), metrics, self.shutdown.clone())),
+                    Err(e) => {
+                        warn!("stream_proxy: could not listen on {}: {}", listener, e);
+                        continue;
+                    }
+                },
+                Protocol::Udp => match UdpSocket::bind(&addrs[..]).await {
+                    Ok(socket) => tokio::spawn(run_udp(socket, target_receiver, self.settings.clone(), metrics, self.shutdown.clone())),
+                    Err(e) => {
+                        warn!("stream_proxy: could not listen on {}: {}", listener, e);
+                        continue;
+                    }
+                },
+            };
+
+            info!("stream_proxy: listening on {} forwarding to {}", listener, target);
+            listeners.insert(listener, RunningListener { target: target_sender, task });
+        }
+    }
+
+    /// Watches the `tcp-services` and `udp-services` config maps and applies them.
+    pub async fn watch_config_maps(self: Arc<Self>) -> Result<(), anyhow::Error> {
+        let client = Client::try_default().await?;
+        let config_maps: Api<ConfigMap> = Api::namespaced(client, &self.namespace);
+        let mut data_by_name: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+
+        let mut stream = Box::pin(runtime::watcher(config_maps, ListParams::default()));
+
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(Event::Applied(config_map)) => {
+                    data_by_name.insert(config_map.name(), config_map.data.unwrap_or_default());
+                }
+                Ok(Event::Deleted(config_map)) => {
+                    data_by_name.remove(&config_map.name());
+                }
+                Ok(Event::Restarted(config_maps)) => {
+                    data_by_name = config_maps
+                        .into_iter()
+                        .map(|config_map| (config_map.name(), config_map.data.unwrap_or_default()))
+                        .collect();
+                }
+                Err(e) => {
+                    warn!("stream_proxy: error watching config maps: {}", e);
+                    continue;
+                }
+            }
+
+            let mut desired = HashMap::new();
+            if let Some(data) = data_by_name.get(TCP_SERVICES) {
+                desired.extend(parse_services(Protocol::Tcp, data, &self.cluster_domain));
+            }
+            if let Some(data) = data_by_name.get(UDP_SERVICES) {
+                desired.extend(parse_services(Protocol::Udp, data, &self.cluster_domain));
+            }
+
+            self.apply(desired).await;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves once every proxied tcp connection has closed.
+    pub async fn connections_closed(&self) {
+        loop {
+            let active: i64 = self.metrics.stream_listeners
+                .read()
+                .await
+                .iter()
+                .filter(|(listener, _)| listener.starts_with("tcp/"))
+                .map(|(_, metrics)| metrics.active.load(Ordering::Relaxed))
+                .sum();
+
+            if active <= 0 {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Counts a connection or session as active for as long as it is alive.
+struct ActiveGuard(Arc<StreamListenerMetrics>);
+
+impl ActiveGuard {
+    fn new(metrics: Arc<StreamListenerMetrics>) -> ActiveGuard {
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(metrics)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn run_tcp(
+    listener: TcpListener,
+    target: watch::Receiver<String>,
+    settings: StreamSettings,
+    metrics: Arc<StreamListenerMetrics>,
+    shutdown: Arc<Shutdown>,
+) {
+    let limit = Arc::new(Semaphore::new(settings.max_connections));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.reached(ShutdownState::Stopping) => return,
+        };
+
+        let (client, _) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("stream_proxy: error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        // dropping the connection closes it straight away
+        let permit = match limit.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                metrics.rejected_total.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        metrics.accepted_total.fetch_add(1, Ordering::Relaxed);
+        let target = target.borrow().clone();
+        let metrics = metrics.clone();
+        let idle_timeout = settings.idle_timeout;
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _active = ActiveGuard::new(metrics.clone());
+
+            let upstream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&target)).await {
+                Ok(Ok(upstream)) => upstream,
+                Ok(Err(e)) => {
+                    metrics.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+                    return warn!("stream_proxy: could not connect to {}: {}", target, e);
+                }
+                Err(_) => {
+                    metrics.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+                    return warn!("stream_proxy: timed out connecting to {}", target);
+                }
+            };
+
+            match splice(client, upstream, idle_timeout, (&metrics.received_bytes_total, &metrics.sent_bytes_total)).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    metrics.idle_timeouts_total.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => warn!("stream_proxy: connection to {} closed with error: {}", target, e),
+            }
+        });
+    }
+}
+
+struct UdpSession {
+    upstream: Arc<UdpSocket>,
+    last_activity: Arc<AtomicU64>,
+    replies: JoinHandle<()>,
+    _active: ActiveGuard,
+}
+
+async fn run_udp(
+    socket: UdpSocket,
+    target: watch::Receiver<String>,
+    settings: StreamSettings,
+    metrics: Arc<StreamListenerMetrics>,
+    shutdown: Arc<Shutdown>,
+) {
+    let socket = Arc::new(socket);
+    let started = Instant::now();
+    let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
+    // datagrams of clients whose session is still being opened, so resolving the upstream doesn't hold up others
+    let mut opening: HashMap<SocketAddr, Vec<Vec<u8>>> = HashMap::new();
+    let (opened_sender, mut opened) = unbounded_channel::<(SocketAddr, String, std::io::Result<UdpSession>)>();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut expiry = tokio::time::interval(settings.udp_session_timeout.max(Duration::from_secs(1)) / 2);
+
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            Some((client, target, session)) = opened.recv() => {
+                let queued = opening.remove(&client).unwrap_or_default();
+                match session {
+                    Ok(session) => {
+                        metrics.accepted_total.fetch_add(1, Ordering::Relaxed);
+                        for datagram in queued {
+                            forward_datagram(&session, &datagram, &metrics).await;
+                        }
+                        sessions.insert(client, session);
+                    }
+                    Err(e) => {
+                        metrics.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+                        warn!("stream_proxy: could not open udp session to {}: {}", target, e);
+                    }
+                }
+                continue;
+            }
+            _ = expiry.tick() => {
+                let now = started.elapsed().as_millis() as u64;
+                let timeout = settings.udp_session_timeout.as_millis() as u64;
+                sessions.retain(|_, session| {
+                    let alive = now.saturating_sub(session.last_activity.load(Ordering::Relaxed)) < timeout;
+                    if !alive {
+                        metrics.idle_timeouts_total.fetch_add(1, Ordering::Relaxed);
+                        session.replies.abort();
+                    }
+                    alive
+                });
+                continue;
+            }
+            _ = shutdown.reached(ShutdownState::Stopping) => break,
+        };
+
+        let (len, client) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("stream_proxy: error receiving datagram: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(session) = sessions.get(&client) {
+            session.last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+            forward_datagram(session, &buf[..len], &metrics).await;
+            continue;
+        }
+
+        if let Some(queued) = opening.get_mut(&client) {
+            if queued.len() < MAX_QUEUED_DATAGRAMS {
+                queued.push(buf[..len].to_vec());
+            }
+            continue;
+        }
+
+        if sessions.len() + opening.len() >= settings.max_connections {
+            metrics.rejected_total.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        opening.insert(client, vec![buf[..len].to_vec()]);
+        let target = target.borrow().clone();
+        let opened_sender = opened_sender.clone();
+        let listener = socket.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let session = match tokio::time::timeout(CONNECT_TIMEOUT, open_udp_session(&target, client, listener, started, metrics)).await {
+                Ok(session) => session,
+                Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out resolving upstream")),
+            };
+            let _ = opened_sender.send((client, target, session));
+        });
+    }
+
+    for session in sessions.values() {
+        session.replies.abort();
+    }
+}
+
+async fn forward_datagram(session: &UdpSession, datagram: &[u8], metrics: &StreamListenerMetrics) {
+    match session.upstream.send(datagram).await {
+        Ok(sent) => {
+            metrics.received_bytes_total.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+        Err(e) => {
+            metrics.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+            warn!("stream_proxy: could not forward datagram: {}", e);
+        }
+    }
+}
+
+/// Connects a socket to the upstream for a single client, and relays the upstream's replies back to the client.
+async fn open_udp_session(
+    target: &str,
+    client: SocketAddr,
+    listener: Arc<UdpSocket>,
+    started: Instant,
+    metrics: Arc<StreamListenerMetrics>,
+) -> std::io::Result<UdpSession> {
+    let upstream_addr = tokio::net::lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "could not resolve upstream"))?;
+
+    let bind_addr = match upstream_addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+
+    let upstream = Arc::new(UdpSocket::bind(bind_addr).await?);
+    upstream.connect(upstream_addr).await?;
+
+    let last_activity = Arc::new(AtomicU64::new(started.elapsed().as_millis() as u64));
+
+    let replies = {
+        let upstream = upstream.clone();
+        let last_activity = last_activity.clone();
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            while let Ok(len) = upstream.recv(&mut buf).await {
+                last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                if listener.send_to(&buf[..len], client).await.is_ok() {
+                    metrics.sent_bytes_total.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+        })
+    };
+
+    Ok(UdpSession {
+        upstream,
+        last_activity,
+        replies,
+        _active: ActiveGuard::new(metrics),
+    })
+}
+
+/// Copies bytes both ways between two streams until both directions have finished.
+///
+/// When one side stops sending, the write half of the other side is shut down, so half-closed connections keep
+/// working in the other direction. Fails with [`std::io::ErrorKind::TimedOut`] if neither side sends anything for
+/// `idle_timeout`. Transferred bytes are added to the counters as they are copied, `a` to `b` first.
+pub async fn splice<A, B>(a: A, b: B, idle_timeout: Duration, counters: (&AtomicU64, &AtomicU64)) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let last_activity = AtomicU64::new(0);
+
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+
+    let transfer = async {
+        tokio::try_join!(
+            copy_half(a_read, b_write, started, &last_activity, counters.0),
+            copy_half(b_read, a_write, started, &last_activity, counters.1),
+        )
+    };
+
+    let idle = async {
+        loop {
+            let idle_for = Duration::from_millis(started.elapsed().as_millis() as u64 - last_activity.load(Ordering::Relaxed));
+            if idle_for >= idle_timeout {
+                return;
+            }
+            tokio::time::sleep(idle_timeout - idle_for).await;
+        }
+    };
+
+    tokio::select! {
+        transferred = transfer => transferred,
+        _ = idle => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connection was idle for too long")),
+    }
+}
+
+async fn copy_half<R, W>(mut reader: R, mut writer: W, started: Instant, last_activity: &AtomicU64, counter: &AtomicU64) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut total = 0;
+
+    loop {
+        let read = reader.read(&mut buf).await?;
+
+        if read == 0 {
+            // propagate the half-close to the other side
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+
+        writer.write_all(&buf[..read]).await?;
+        writer.flush().await?;
+
+        total += read as u64;
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+        last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // read everything until the client half-closes, then answer and close
+                    let mut received = Vec::new();
+                    stream.read_to_end(&mut received).await.unwrap();
+                    stream.write_all(&received).await.unwrap();
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn parses_nginx_style_services() {
+        let data: BTreeMap<String, String> = [
+            ("5432".to_string(), "databases/postgres:5432".to_string()),
+            ("1883".to_string(), "iot/mqtt:1883:PROXY".to_string()),
+            ("abc".to_string(), "databases/postgres:5432".to_string()),
+            ("6379".to_string(), "redis:6379".to_string()),
+        ].into_iter().collect();
+
+        let services = parse_services(Protocol::Tcp, &data, "cluster.local");
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[&StreamListener { protocol: Protocol::Tcp, port: 5432 }], "postgres.databases.svc.cluster.local:5432");
+        assert_eq!(services[&StreamListener { protocol: Protocol::Tcp, port: 1883 }], "mqtt.iot.svc.cluster.local:1883");
+    }
+
+    #[tokio::test]
+    async fn proxies_tcp_with_half_close() {
+        let upstream = echo_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let metrics = Arc::new(StreamListenerMetrics::default());
+        let (_target, target_receiver) = watch::channel(upstream.to_string());
+        let settings = StreamSettings {
+            idle_timeout: Duration::from_secs(5),
+            udp_session_timeout: Duration::from_secs(5),
+            max_connections: 1,
+        };
+        let shutdown = Arc::new(Shutdown::new(Duration::from_secs(0), Duration::from_secs(0)));
+        tokio::spawn(run_tcp(listener, target_receiver, settings, metrics.clone(), shutdown));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"hello");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(metrics.accepted_total.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.received_bytes_total.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.sent_bytes_total.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn splice_times_out_when_idle() {
+        let (a, _a_peer) = tokio::io::duplex(64);
+        let (b, _b_peer) = tokio::io::duplex(64);
+        let counter = AtomicU64::new(0);
+
+        let result = splice(a, b, Duration::from_millis(50), (&counter, &counter)).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn proxies_udp_datagrams() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = upstream.recv_from(&mut buf).await {
+                upstream.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let metrics = Arc::new(StreamListenerMetrics::default());
+        let (_target, target_receiver) = watch::channel(upstream_addr.to_string());
+        let settings = StreamSettings {
+            idle_timeout: Duration::from_secs(5),
+            udp_session_timeout: Duration::from_secs(5),
+            max_connections: 8,
+        };
+        let shutdown = Arc::new(Shutdown::new(Duration::from_secs(0), Duration::from_secs(0)));
+        tokio::spawn(run_udp(socket, target_receiver, settings, metrics.clone(), shutdown));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", addr).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(metrics.active.load(Ordering::Relaxed), 1);
+    }
+}
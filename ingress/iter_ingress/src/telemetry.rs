@@ -0,0 +1,575 @@
+//! # Telemetry
+//!
+//! Distributed tracing for proxied requests.
+//!
+//! Incoming W3C `traceparent`/`tracestate` headers (and optionally B3 headers) are continued, otherwise a new
+//! trace is started subject to sampling. Every proxied request produces a server span covering routing, the
+//! upstream connection and the upstream response. Finished spans are batched and exported to a collector using
+//! OTLP over HTTP with the JSON encoding, so no gRPC stack is required.
+//!
+//! Configuration follows the standard OpenTelemetry environment variables:
+//! - `OTEL_EXPORTER_OTLP_ENDPOINT` - collector base url, spans are posted to `{endpoint}/v1/traces`.
+//!   When unset, spans are not recorded and trace headers are forwarded untouched.
+//! - `OTEL_TRACES_SAMPLER_ARG` - ratio of new traces to sample, between `0.0` and `1.0` (default `1.0`).
+//! - `OTEL_PROPAGATORS` - comma separated list, `b3` and `b3multi` enable B3 extraction and injection.
+//! - `OTEL_SERVICE_NAME` - service name reported to the collector (default `iter-ingress`).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Body, Client, Method, Request};
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::warn;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+const B3: &str = "b3";
+const B3_TRACE_ID: &str = "x-b3-traceid";
+const B3_SPAN_ID: &str = "x-b3-spanid";
+const B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+const B3_SAMPLED: &str = "x-b3-sampled";
+
+const MAX_BATCH_SIZE: usize = 512;
+/// Spans waiting for the exporter, further spans are dropped while the collector can't keep up.
+const MAX_QUEUED_SPANS: usize = 4 * MAX_BATCH_SIZE;
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Kind of a span as defined by the OTLP protobuf `SpanKind` enum.
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum B3Propagation {
+    /// B3 headers are ignored.
+    Disabled,
+    /// The single `b3` header.
+    Single,
+    /// The `X-B3-*` family of headers.
+    Multi,
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: Option<String>,
+    pub sample_ratio: f64,
+    pub b3: B3Propagation,
+    pub service_name: String,
+}
+
+impl TelemetryConfig {
+    pub fn from_env() -> Self {
+        let propagators = std::env::var("OTEL_PROPAGATORS").unwrap_or_default();
+        let propagators = propagators.split(',').map(|p| p.trim()).collect::<Vec<_>>();
+
+        let b3 = if propagators.contains(&"b3multi") {
+            B3Propagation::Multi
+        } else if propagators.contains(&"b3") {
+            B3Propagation::Single
+        } else {
+            B3Propagation::Disabled
+        };
+
+        TelemetryConfig {
+            otlp_endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|e| !e.is_empty()),
+            sample_ratio: std::env::var("OTEL_TRACES_SAMPLER_ARG")
+                .ok()
+                .and_then(|ratio| ratio.parse::<f64>().ok())
+                .unwrap_or(1.0)
+                .clamp(0.0, 1.0),
+            b3,
+            service_name: std::env::var("OTEL_SERVICE_NAME").unwrap_or("iter-ingress".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    pub trace_state: Option<String>,
+}
+
+impl SpanContext {
+    /// Parses a W3C `traceparent` header, `00-<trace-id>-<parent-id>-<flags>`.
+    pub fn from_traceparent(value: &str, trace_state: Option<&str>) -> Option<SpanContext> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        // version 00 has exactly four fields, future versions may append more
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(flags, 16).ok().filter(|_| flags.len() == 2)?;
+
+        Some(SpanContext {
+            trace_id: decode_id(trace_id)?,
+            span_id: decode_id(span_id)?,
+            sampled: flags & 0x01 == 0x01,
+            trace_state: trace_state.map(|s| s.to_string()).filter(|s| !s.is_empty()),
+        })
+    }
+
+    /// Parses the single B3 header, `<trace-id>-<span-id>[-<sampled>[-<parent-span-id>]]`.
+    pub fn from_b3_single(value: &str) -> Option<SpanContext> {
+        let mut parts = value.trim().split('-');
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let sampled = parts.next().map(|s| s == "1" || s == "d").unwrap_or(true);
+
+        Some(SpanContext {
+            trace_id: decode_b3_trace_id(trace_id)?,
+            span_id: decode_id(span_id)?,
+            sampled,
+            trace_state: None,
+        })
+    }
+
+    fn from_b3_multi(headers: &HeaderMap) -> Option<SpanContext> {
+        let trace_id = headers.get(B3_TRACE_ID)?.to_str().ok()?;
+        let span_id = headers.get(B3_SPAN_ID)?.to_str().ok()?;
+        let sampled = headers
+            .get(B3_SAMPLED)
+            .and_then(|s| s.to_str().ok())
+            .map(|s| s == "1" || s == "true")
+            .unwrap_or(true);
+
+        Some(SpanContext {
+            trace_id: decode_b3_trace_id(trace_id)?,
+            span_id: decode_id(span_id)?,
+            sampled,
+            trace_state: None,
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+
+    pub fn to_b3_single(&self) -> String {
+        format!("{}-{}-{}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a lowercase hex id of exactly `N` bytes, rejecting the all zero id.
+fn decode_id<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut id = [0u8; N];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    if id.iter().all(|b| *b == 0) {
+        return None;
+    }
+
+    Some(id)
+}
+
+/// B3 allows 64 bit trace ids, which are left padded to 128 bits.
+fn decode_b3_trace_id(value: &str) -> Option<[u8; 16]> {
+    match value.len() {
+        16 => decode_id::<16>(&format!("{:0>32}", value)),
+        _ => decode_id::<16>(value),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+pub struct Tracer {
+    config: TelemetryConfig,
+    exporter: Option<Sender<Value>>,
+}
+
+impl Tracer {
+    /// Creates a tracer, spawning the exporter task when a collector endpoint is configured.
+    pub fn new(config: TelemetryConfig) -> Self {
+        let exporter = config.otlp_endpoint.clone().map(|endpoint| {
+            let (sender, receiver) = channel(MAX_QUEUED_SPANS);
+            tokio::spawn(export_spans(endpoint, config.service_name.clone(), receiver));
+            sender
+        });
+
+        Tracer { config, exporter }
+    }
+
+    /// Extracts the caller's span context, trying `traceparent` before any B3 headers.
+    pub fn extract(&self, headers: &HeaderMap) -> Option<SpanContext> {
+        if let Some(traceparent) = headers.get(TRACEPARENT).and_then(|v| v.to_str().ok()) {
+            let trace_state = headers.get(TRACESTATE).and_then(|v| v.to_str().ok());
+            if let Some(context) = SpanContext::from_traceparent(traceparent, trace_state) {
+                return Some(context);
+            }
+        }
+
+        match self.config.b3 {
+            B3Propagation::Disabled => None,
+            _ => headers
+                .get(B3)
+                .and_then(|v| v.to_str().ok())
+                .and_then(SpanContext::from_b3_single)
+                .or_else(|| SpanContext::from_b3_multi(headers)),
+        }
+    }
+
+    /// Starts the server span for an incoming request.
+    ///
+    /// The span continues the caller's trace when one is present, in which case the caller's sampling decision
+    /// is respected. Otherwise a new trace is started and sampled according to the configured ratio.
+    pub fn start_server_span(&self, name: String, headers: &HeaderMap) -> ServerSpan {
+        let mut rng = rand::thread_rng();
+        let parent = self.extract(headers);
+
+        let (trace_id, parent_span_id, sampled, trace_state) = match parent {
+            Some(parent) => (parent.trace_id, Some(parent.span_id), parent.sampled, parent.trace_state),
+            None => (rng.gen::<[u8; 16]>(), None, rng.gen::<f64>() < self.config.sample_ratio, None),
+        };
+
+        ServerSpan {
+            name,
+            context: SpanContext {
+                trace_id,
+                span_id: rng.gen::<[u8; 8]>(),
+                sampled,
+                trace_state,
+            },
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            events: Vec::new(),
+            error: None,
+            b3: self.config.b3,
+            propagate: self.exporter.is_some(),
+            exporter: self.exporter.clone().filter(|_| sampled),
+        }
+    }
+}
+
+pub struct ServerSpan {
+    name: String,
+    pub context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    events: Vec<(&'static str, SystemTime)>,
+    error: Option<String>,
+    b3: B3Propagation,
+    /// whether spans are exported at all, without a collector the caller's headers are forwarded untouched
+    propagate: bool,
+    exporter: Option<Sender<Value>>,
+}
+
+impl ServerSpan {
+    pub fn set_attribute<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn add_event(&mut self, name: &'static str) {
+        self.events.push((name, SystemTime::now()));
+    }
+
+    pub fn set_error<E: std::fmt::Display>(&mut self, error: E) {
+        self.error = Some(error.to_string());
+    }
+
+    /// Drops the span without exporting it, used for requests the ingress answers itself such as health checks.
+    pub fn discard(&mut self) {
+        self.exporter = None;
+    }
+
+    /// Writes this span's context into the headers of the request forwarded upstream, replacing the caller's. Does
+    /// nothing without a collector, as the span would be missing from the trace.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        if !self.propagate {
+            return;
+        }
+
+        headers.remove(TRACEPARENT);
+        headers.remove(TRACESTATE);
+        headers.remove(B3);
+        headers.remove(B3_TRACE_ID);
+        headers.remove(B3_SPAN_ID);
+        headers.remove(B3_PARENT_SPAN_ID);
+        headers.remove(B3_SAMPLED);
+
+        if let Ok(value) = HeaderValue::from_str(&self.context.to_traceparent()) {
+            headers.insert(TRACEPARENT, value);
+        }
+
+        if let Some(value) = self.context.trace_state.as_ref().and_then(|s| HeaderValue::from_str(s).ok()) {
+            headers.insert(TRACESTATE, value);
+        }
+
+        match self.b3 {
+            B3Propagation::Disabled => {}
+            B3Propagation::Single => {
+                if let Ok(value) = HeaderValue::from_str(&self.context.to_b3_single()) {
+                    headers.insert(B3, value);
+                }
+            }
+            B3Propagation::Multi => {
+                let mut insert = |name: &'static str, value: String| {
+                    if let Ok(value) = HeaderValue::from_str(&value) {
+                        headers.insert(name, value);
+                    }
+                };
+
+                insert(B3_TRACE_ID, hex(&self.context.trace_id));
+                insert(B3_SPAN_ID, hex(&self.context.span_id));
+                insert(B3_SAMPLED, (self.context.sampled as u8).to_string());
+                if let Some(parent) = &self.parent_span_id {
+                    insert(B3_PARENT_SPAN_ID, hex(parent));
+                }
+            }
+        }
+    }
+
+    /// Ends the span and hands it to the exporter if it was sampled.
+    pub fn end(self) {
+        let exporter = match &self.exporter {
+            Some(exporter) => exporter,
+            None => return,
+        };
+
+        let attributes = self.attributes.iter().map(|(key, value)| {
+            let value = match value {
+                Value::Number(n) if n.is_i64() => json!({ "intValue": n.to_string() }),
+                Value::Number(n) => json!({ "doubleValue": n }),
+                Value::Bool(b) => json!({ "boolValue": b }),
+                Value::String(s) => json!({ "stringValue": s }),
+                other => json!({ "stringValue": other.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        }).collect::<Vec<_>>();
+
+        let events = self.events.iter().map(|(name, time)| {
+            json!({ "name": name, "timeUnixNano": unix_nanos(*time) })
+        }).collect::<Vec<_>>();
+
+        let status = match &self.error {
+            Some(message) => json!({ "code": STATUS_CODE_ERROR, "message": message }),
+            None => json!({ "code": STATUS_CODE_OK }),
+        };
+
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": attributes,
+            "events": events,
+            "status": status,
+        });
+
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(hex(parent));
+        }
+
+        if let Some(trace_state) = &self.context.trace_state {
+            span["traceState"] = json!(trace_state);
+        }
+
+        if exporter.try_send(span).is_err() {
+            warn!("telemetry: dropping a span, the exporter is falling behind");
+        }
+    }
+}
+
+/// Batches finished spans and posts them to the collector.
+///
+/// Export failures are logged and the batch is dropped, tracing must never hold up proxying.
+async fn export_spans(endpoint: String, service_name: String, mut receiver: Receiver<Value>) {
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let client = Client::new();
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(BATCH_INTERVAL);
+
+    loop {
+        let closed = tokio::select! {
+            span = receiver.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    false
+                },
+                None => true,
+            },
+            _ = interval.tick() => false,
+        };
+
+        if !batch.is_empty() && (closed || batch.len() >= MAX_BATCH_SIZE || receiver.is_empty()) {
+            let payload = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [
+                            { "key": "service.name", "value": { "stringValue": service_name } },
+                        ],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": "iter_ingress" },
+                        "spans": std::mem::take(&mut batch),
+                    }],
+                }],
+            });
+
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(&url)
+                .header("Content-Type", "application/json")
+                .body(Body::from(payload.to_string()));
+
+            match request {
+                Ok(request) => match client.request(request).await {
+                    Ok(response) if !response.status().is_success() => {
+                        warn!("telemetry: collector responded with {}", response.status())
+                    }
+                    Err(e) => warn!("telemetry: could not export spans: {}", e),
+                    _ => {}
+                },
+                Err(e) => warn!("telemetry: invalid collector url {}: {}", url, e),
+            }
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn config(otlp_endpoint: Option<String>, b3: B3Propagation) -> TelemetryConfig {
+        TelemetryConfig {
+            otlp_endpoint,
+            sample_ratio: 1.0,
+            b3,
+            service_name: "iter-ingress-test".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_and_formats_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = SpanContext::from_traceparent(header, Some("congo=t61rcWkgMzE")).unwrap();
+
+        assert!(context.sampled);
+        assert_eq!(context.trace_state.as_deref(), Some("congo=t61rcWkgMzE"));
+        assert_eq!(context.to_traceparent(), header);
+
+        assert!(SpanContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None).is_none());
+        assert!(SpanContext::from_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).is_none());
+        assert!(SpanContext::from_traceparent("00-4bf92f3577b34da6-00f067aa0ba902b7-01", None).is_none());
+    }
+
+    #[test]
+    fn parses_b3_headers() {
+        let context = SpanContext::from_b3_single("a3ce929d0e0e4736-00f067aa0ba902b7-0").unwrap();
+        assert_eq!(hex(&context.trace_id), "0000000000000000a3ce929d0e0e4736");
+        assert!(!context.sampled);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(B3_TRACE_ID, HeaderValue::from_static("4bf92f3577b34da6a3ce929d0e0e4736"));
+        headers.insert(B3_SPAN_ID, HeaderValue::from_static("00f067aa0ba902b7"));
+
+        let tracer = Tracer::new(config(None, B3Propagation::Multi));
+        let context = tracer.extract(&headers).unwrap();
+        assert_eq!(hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+
+        let tracer = Tracer::new(config(None, B3Propagation::Disabled));
+        assert!(tracer.extract(&headers).is_none());
+    }
+
+    #[tokio::test]
+    async fn continues_trace_and_injects_new_span() {
+        let tracer = Tracer::new(config(Some("http://127.0.0.1:4318".to_string()), B3Propagation::Disabled));
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+
+        let span = tracer.start_server_span("GET /".to_string(), &headers);
+        assert_eq!(hex(&span.context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span.parent_span_id.map(|id| hex(&id)), Some("00f067aa0ba902b7".to_string()));
+
+        span.inject(&mut headers);
+        let forwarded = headers.get(TRACEPARENT).unwrap().to_str().unwrap();
+        assert!(forwarded.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!forwarded.contains("00f067aa0ba902b7"));
+    }
+
+    #[test]
+    fn forwards_trace_headers_untouched_without_a_collector() {
+        let tracer = Tracer::new(config(None, B3Propagation::Single));
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+
+        tracer.start_server_span("GET /".to_string(), &headers).inject(&mut headers);
+
+        assert_eq!(headers.get(TRACEPARENT).unwrap(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert!(headers.get(B3).is_none());
+    }
+
+    #[tokio::test]
+    async fn exports_spans_to_collector() {
+        let (sender, mut received) = unbounded_channel::<Value>();
+
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        assert_eq!(req.uri().path(), "/v1/traces");
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let tracer = Tracer::new(config(Some(endpoint), B3Propagation::Disabled));
+        let mut span = tracer.start_server_span("GET example.com".to_string(), &HeaderMap::new());
+        span.set_attribute("http.status_code", 200);
+        span.add_event("routing");
+        let trace_id = hex(&span.context.trace_id);
+        span.end();
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        let span = &payload["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+
+        assert_eq!(span["traceId"], json!(trace_id));
+        assert_eq!(span["name"], json!("GET example.com"));
+        assert_eq!(span["kind"], json!(SPAN_KIND_SERVER));
+        assert!(span.get("parentSpanId").is_none());
+        assert_eq!(span["attributes"][0]["value"]["intValue"], json!("200"));
+        assert_eq!(span["events"][0]["name"], json!("routing"));
+    }
+}
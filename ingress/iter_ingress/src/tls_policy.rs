@@ -0,0 +1,419 @@
+//! # TLS Policy
+//!
+//! Per-host handshake settings: the lowest protocol version accepted, the cipher suites and key exchange groups
+//! offered, and whether clients have to present a certificate. Hosts without a policy get rustls' safe defaults.
+//!
+//! Ingresses set a policy with the annotations below, a config file with a host's `tls_policy`. With a client CA,
+//! clients have to present a certificate issued by one of the CAs in the bundle, and the verified subject is passed
+//! to the upstream in `X-Client-Cert-Subject`. A host whose CA bundle can't be loaded refuses every handshake,
+//! rather than serving without client authentication. Bundles are reloaded every minute.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use rustls::server::{AllowAnyAuthenticatedClient, NoClientAuth};
+use rustls::{ConfigBuilder, RootCertStore, ServerConfig, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion, WantsVerifier};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::certificate_state::{CertKey, CertificateState, Host, KeyType, StapledKey};
+use crate::kube_config_tracker::RoutingTable;
+use crate::user_certs::SecretRef;
+
+/// `1.2` or `1.3`, the lowest tls version a host accepts.
+pub const TLS_MIN_VERSION_ANNOTATION: &str = "iter.earth/tls-min-version";
+
+/// Comma separated cipher suites a host offers, by their rustls names, e.g. `TLS13_AES_256_GCM_SHA384`.
+pub const TLS_CIPHER_SUITES_ANNOTATION: &str = "iter.earth/tls-cipher-suites";
+
+/// Comma separated key exchange groups a host offers, `X25519`, `secp256r1` or `secp384r1`.
+pub const TLS_KX_GROUPS_ANNOTATION: &str = "iter.earth/tls-kx-groups";
+
+/// Names a secret in the ingress' namespace holding a PEM CA bundle under [`CLIENT_CA_KEY`]. Clients of the
+/// ingress' hosts have to present a certificate issued by one of them.
+pub const TLS_CLIENT_CA_ANNOTATION: &str = "iter.earth/tls-client-ca-secret";
+
+pub const CLIENT_CA_KEY: &str = "ca.crt";
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            other => Err(format!("expected 1.2 or 1.3, got {:?}", other)),
+        }
+    }
+}
+
+/// Where a host's client CA bundle is loaded from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientCa {
+    Secret(SecretRef),
+    File(PathBuf),
+}
+
+impl std::fmt::Display for ClientCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientCa::Secret(secret_ref) => write!(f, "secret {}", secret_ref),
+            ClientCa::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsPolicy {
+    pub min_version: Option<TlsVersion>,
+    /// empty for the defaults
+    pub cipher_suites: Vec<String>,
+    /// empty for the defaults
+    pub kx_groups: Vec<String>,
+    pub client_ca: Option<ClientCa>,
+}
+
+impl TlsPolicy {
+    /// Checks the names are known to rustls and leave something to negotiate with.
+    pub fn new(
+        min_version: Option<&str>,
+        cipher_suites: Vec<String>,
+        kx_groups: Vec<String>,
+        client_ca: Option<ClientCa>,
+    ) -> Result<TlsPolicy, String> {
+        let policy = TlsPolicy {
+            min_version: min_version.map(|version| version.parse()).transpose()?,
+            cipher_suites,
+            kx_groups,
+            client_ca,
+        };
+
+        policy.builder()?;
+        Ok(policy)
+    }
+
+    /// The ingress' policy, `None` when it sets none of the annotations.
+    pub fn from_annotations(annotations: &BTreeMap<String, String>, namespace: &str) -> Result<Option<TlsPolicy>, String> {
+        let list = |annotation: &str| -> Vec<String> {
+            annotations.get(annotation)
+                .map(|value| value.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect())
+                .unwrap_or_default()
+        };
+
+        let client_ca = annotations.get(TLS_CLIENT_CA_ANNOTATION).map(|name| ClientCa::Secret(SecretRef {
+            namespace: namespace.to_string(),
+            name: name.clone(),
+        }));
+
+        let policy = TlsPolicy::new(
+            annotations.get(TLS_MIN_VERSION_ANNOTATION).map(|version| version.as_str()),
+            list(TLS_CIPHER_SUITES_ANNOTATION),
+            list(TLS_KX_GROUPS_ANNOTATION),
+            client_ca,
+        )?;
+
+        Ok(Some(policy).filter(|policy| *policy != TlsPolicy::default()))
+    }
+
+    fn builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, String> {
+        let cipher_suites: Vec<SupportedCipherSuite> = match self.cipher_suites.is_empty() {
+            true => rustls::DEFAULT_CIPHER_SUITES.to_vec(),
+            false => self.cipher_suites.iter().map(|name| {
+                rustls::ALL_CIPHER_SUITES.iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == *name)
+                    .copied()
+                    .ok_or_else(|| format!("unknown cipher suite {:?}", name))
+            }).collect::<Result<_, _>>()?,
+        };
+
+        let kx_groups: Vec<&'static SupportedKxGroup> = match self.kx_groups.is_empty() {
+            true => rustls::ALL_KX_GROUPS.to_vec(),
+            false => self.kx_groups.iter().map(|name| {
+                rustls::ALL_KX_GROUPS.iter()
+                    .find(|group| format!("{:?}", group.name) == *name)
+                    .copied()
+                    .ok_or_else(|| format!("unknown key exchange group {:?}", name))
+            }).collect::<Result<_, _>>()?,
+        };
+
+        let versions: &[&'static SupportedProtocolVersion] = match self.min_version {
+            Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+            Some(TlsVersion::Tls12) | None => rustls::DEFAULT_VERSIONS,
+        };
+
+        ServerConfig::builder()
+            .with_cipher_suites(&cipher_suites)
+            .with_kx_groups(&kx_groups)
+            .with_protocol_versions(versions)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The server config built for each key type, with the certificate it serves.
+type ServerConfigs = HashMap<KeyType, (Arc<StapledKey>, Arc<ServerConfig>)>;
+
+enum ClientAuth {
+    None,
+    Verify(RootCertStore),
+    /// the host wants client certificates, but its CA bundle couldn't be loaded
+    Unavailable,
+}
+
+/// A host's [`TlsPolicy`] with its CA bundle loaded, which builds the server config for each of its certificates.
+pub struct HostTlsPolicy {
+    pub policy: TlsPolicy,
+    client_ca_pem: Option<Vec<u8>>,
+    client_auth: ClientAuth,
+    /// reused for as long as the certificate is served, so tls sessions can be resumed
+    configs: Mutex<ServerConfigs>,
+}
+
+impl HostTlsPolicy {
+    pub fn new(policy: TlsPolicy, client_ca_pem: Option<Result<Vec<u8>, String>>) -> Result<HostTlsPolicy, String> {
+        let (client_ca_pem, client_auth) = match client_ca_pem {
+            None => (None, ClientAuth::None),
+            Some(Ok(pem)) => {
+                let roots = client_roots(&pem);
+                (Some(pem), roots.map(ClientAuth::Verify)?)
+            }
+            Some(Err(e)) => return Err(e),
+        };
+
+        Ok(HostTlsPolicy {
+            policy,
+            client_ca_pem,
+            client_auth,
+            configs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Refuses every handshake, for a host whose client CA couldn't be loaded.
+    fn unavailable(policy: TlsPolicy) -> HostTlsPolicy {
+        HostTlsPolicy {
+            policy,
+            client_ca_pem: None,
+            client_auth: ClientAuth::Unavailable,
+            configs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The server config to serve `cert` with, `None` to refuse the handshake.
+    pub fn server_config(&self, cert: &CertKey) -> Option<Arc<ServerConfig>> {
+        if let ClientAuth::Unavailable = self.client_auth {
+            return None;
+        }
+
+        let mut configs = self.configs.lock().unwrap();
+        if let Some((key, config)) = configs.get(&cert.key_type) {
+            if Arc::ptr_eq(key, &cert.certified_key) {
+                return Some(config.clone());
+            }
+        }
+
+        let verifier = match &self.client_auth {
+            ClientAuth::Verify(roots) => AllowAnyAuthenticatedClient::new(roots.clone()),
+            _ => NoClientAuth::new(),
+        };
+
+        // the policy was checked when it was parsed
+        let config = Arc::new(self.policy.builder().ok()?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(cert.certified_key.clone()));
+        configs.insert(cert.key_type, (cert.certified_key.clone(), config.clone()));
+        Some(config)
+    }
+}
+
+fn client_roots(pem: &[u8]) -> Result<RootCertStore, String> {
+    let certs = rustls_pemfile::certs(&mut &pem[..]).map_err(|e| format!("could not read CA bundle: {}", e))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err("no CA certificates in the bundle".to_string());
+    }
+
+    Ok(roots)
+}
+
+/// Keeps the policies in the certificate state up to date with the routing table and the CA bundles.
+pub struct TlsPolicies {
+    client: Option<Client>,
+    routing_table: Arc<RoutingTable>,
+    state: Arc<CertificateState>,
+}
+
+impl TlsPolicies {
+    pub fn new(client: Option<Client>, routing_table: Arc<RoutingTable>, state: Arc<CertificateState>) -> TlsPolicies {
+        TlsPolicies { client, routing_table, state }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let routes_changed = Arc::new(Notify::new());
+        self.routing_table.subscribe(Box::new({
+            let routes_changed = routes_changed.clone();
+            move |_| routes_changed.notify_one()
+        })).await;
+
+        loop {
+            self.sync().await;
+
+            tokio::select! {
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => {}
+                _ = routes_changed.notified() => {}
+            }
+        }
+    }
+
+    async fn sync(&self) {
+        let wanted = self.routing_table.tls_policies().await;
+        let current = self.state.tls_policies.read().await.clone();
+
+        let mut bundles: HashMap<ClientCa, Result<Vec<u8>, String>> = HashMap::new();
+        let mut policies: HashMap<Host, Arc<HostTlsPolicy>> = HashMap::new();
+
+        for (host, policy) in wanted {
+            let existing = current.get(&host).filter(|existing| existing.policy == policy);
+
+            let client_ca_pem = match &policy.client_ca {
+                Some(client_ca) => {
+                    if !bundles.contains_key(client_ca) {
+                        bundles.insert(client_ca.clone(), self.load_client_ca(client_ca).await);
+                    }
+                    Some(bundles[client_ca].clone())
+                }
+                None => None,
+            };
+
+            // unchanged policies keep their server configs
+            if let Some(existing) = existing {
+                let unchanged = match (&client_ca_pem, &existing.client_auth) {
+                    (None, _) => true,
+                    (Some(Ok(pem)), ClientAuth::Verify(_)) => existing.client_ca_pem.as_ref() == Some(pem),
+                    // a bundle which can't be read for now, the one already loaded keeps being used
+                    (Some(Err(e)), ClientAuth::Verify(_)) => {
+                        warn!("tls_policy: could not reload client CA for {}, keeping the loaded one: {}", host, e);
+                        true
+                    }
+                    (Some(_), _) => false,
+                };
+                if unchanged {
+                    policies.insert(host, existing.clone());
+                    continue;
+                }
+            }
+
+            let client_ca = policy.client_ca.clone();
+            let host_policy = match HostTlsPolicy::new(policy.clone(), client_ca_pem) {
+                Ok(host_policy) => {
+                    if let Some(client_ca) = client_ca {
+                        info!("tls_policy: verifying client certificates for {} against {}", host, client_ca);
+                    }
+                    host_policy
+                }
+                Err(e) => {
+                    warn!("tls_policy: refusing connections to {}, no client CA from {}: {}", host, client_ca.map(|ca| ca.to_string()).unwrap_or_default(), e);
+                    HostTlsPolicy::unavailable(policy)
+                }
+            };
+            policies.insert(host, Arc::new(host_policy));
+        }
+
+        *self.state.tls_policies.write().await = policies;
+    }
+
+    async fn load_client_ca(&self, client_ca: &ClientCa) -> Result<Vec<u8>, String> {
+        match client_ca {
+            ClientCa::Secret(secret_ref) => {
+                let client = self.client.clone().ok_or_else(|| "secrets are only available in kubernetes".to_string())?;
+                let secret = Api::<Secret>::namespaced(client, &secret_ref.namespace)
+                    .get(&secret_ref.name)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                secret.data
+                    .and_then(|data| data.get(CLIENT_CA_KEY).map(|pem| pem.0.clone()))
+                    .ok_or_else(|| format!("{} is missing", CLIENT_CA_KEY))
+            }
+            ClientCa::File(path) => tokio::fs::read(path).await.map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fallback_cert::self_signed;
+
+    #[test]
+    fn parses_policies_from_annotations() {
+        let annotations: BTreeMap<String, String> = [
+            (TLS_MIN_VERSION_ANNOTATION.to_string(), "1.3".to_string()),
+            (TLS_CIPHER_SUITES_ANNOTATION.to_string(), "TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256".to_string()),
+            (TLS_CLIENT_CA_ANNOTATION.to_string(), "partner-ca".to_string()),
+        ].into_iter().collect();
+
+        let policy = TlsPolicy::from_annotations(&annotations, "web").unwrap().unwrap();
+        assert_eq!(policy.min_version, Some(TlsVersion::Tls13));
+        assert_eq!(policy.cipher_suites.len(), 2);
+        assert_eq!(policy.client_ca, Some(ClientCa::Secret(SecretRef { namespace: "web".to_string(), name: "partner-ca".to_string() })));
+
+        assert_eq!(TlsPolicy::from_annotations(&BTreeMap::new(), "web").unwrap(), None);
+
+        let invalid = |annotation: &str, value: &str| {
+            let annotations = [(annotation.to_string(), value.to_string())].into_iter().collect();
+            TlsPolicy::from_annotations(&annotations, "web").is_err()
+        };
+        assert!(invalid(TLS_MIN_VERSION_ANNOTATION, "1.1"));
+        assert!(invalid(TLS_KX_GROUPS_ANNOTATION, "ffdhe2048"));
+        // tls 1.3 only with nothing but tls 1.2 suites leaves nothing to negotiate
+        assert!(TlsPolicy::new(Some("1.3"), vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()], vec![], None).is_err());
+    }
+
+    #[test]
+    fn refuses_handshakes_without_a_client_ca() {
+        let cert = self_signed("partner.example.com", KeyType::Rsa, 30).unwrap();
+        let policy = TlsPolicy { client_ca: Some(ClientCa::File(PathBuf::from("ca.crt"))), ..TlsPolicy::default() };
+
+        assert!(HostTlsPolicy::new(policy.clone(), Some(Ok(b"not a certificate".to_vec()))).is_err());
+        assert!(HostTlsPolicy::unavailable(policy.clone()).server_config(&cert).is_none());
+
+        let host_policy = HostTlsPolicy::new(policy, Some(Ok(ca_pem()))).unwrap();
+        let config = host_policy.server_config(&cert).unwrap();
+        assert!(Arc::ptr_eq(&config, &host_policy.server_config(&cert).unwrap()));
+    }
+
+    fn ca_pem() -> Vec<u8> {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509NameBuilder, X509}};
+        use openssl::x509::extension::BasicConstraints;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "Partner CA").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+        cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        cert.build().to_pem().unwrap()
+    }
+}
@@ -0,0 +1,274 @@
+//! # Tunnels
+//!
+//! Requests after which a connection stops speaking http: `Upgrade` requests, such as websockets, and `CONNECT`.
+//!
+//! Upgrade requests are proxied like any other request, headers and body included. When the upstream answers with
+//! `101 Switching Protocols` the client and upstream connections are spliced together for whatever protocol they
+//! agreed on. `CONNECT` requests are answered by the ingress itself, which opens a tcp connection to the routed
+//! backend and splices the client onto it.
+//!
+//! Tunnels keep working after one side half-closes, and are counted in `iter_ingress_active_tunnels` so draining
+//! waits for them. Their idle timeout and maximum lifetime are part of the [`Settings`](crate::settings::Settings).
+
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Method, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::forwarding::{upstream_proxy_header, ClientInfo};
+use crate::kube_config_tracker::Backend;
+use crate::stream_proxy::splice;
+use crate::{Code, IngressLoadBalancerError};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TunnelSettings {
+    pub idle_timeout: Duration,
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Whether the client asks to switch the connection to another protocol, e.g. `Upgrade: websocket`.
+pub fn is_upgrade<B>(request: &Request<B>) -> bool {
+    let connection_upgrade = request.headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    connection_upgrade && request.headers().contains_key(UPGRADE)
+}
+
+pub fn is_connect<B>(request: &Request<B>) -> bool {
+    request.method() == Method::CONNECT
+}
+
+/// Splices the upgraded client connection onto the upstream once both sides have switched protocols. `guard` is
+/// dropped once the tunnel closes.
+pub fn spawn_upgraded<G: Send + 'static>(description: String, client: OnUpgrade, upstream: OnUpgrade, settings: TunnelSettings, guard: G) {
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(e) => return warn!("tunnel: {} could not be upgraded: {}", description, e),
+        };
+
+        run(&description, client, upstream, &settings).await;
+    });
+}
+
+/// Answers a `CONNECT` request by connecting to the backend, the client connection is spliced onto it once the
+/// response has been sent. `guard` is dropped once the tunnel closes.
+pub async fn connect<G: Send + 'static>(
+    request: &mut Request<Body>,
+    route: &Backend,
+    client: &ClientInfo,
+    settings: TunnelSettings,
+    guard: G,
+) -> Result<Response<Body>, IngressLoadBalancerError> {
+    let backend = route.address();
+
+    let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&backend)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => return Err(IngressLoadBalancerError::general(Code::InternalServerError, format!("could not connect to {}: {}", backend, e))),
+        Err(_) => return Err(IngressLoadBalancerError::general(Code::InternalServerError, format!("timed out connecting to {}", backend))),
+    };
+
+    if let Some(proxy_header) = upstream_proxy_header(route, client) {
+        upstream.write_all(&proxy_header)
+            .await
+            .map_err(|e| IngressLoadBalancerError::general(Code::InternalServerError, format!("could not send PROXY header to {}: {}", backend, e)))?;
+    }
+
+    let client_upgrade = hyper::upgrade::on(request);
+    let description = format!("CONNECT {} -> {}", client.addr.ip(), backend);
+
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        match client_upgrade.await {
+            Ok(client) => run(&description, client, upstream, &settings).await,
+            Err(e) => warn!("tunnel: {} could not be upgraded: {}", description, e),
+        }
+    });
+
+    Ok(Response::new(Body::empty()))
+}
+
+async fn run<A, B>(description: &str, client: A, upstream: B, settings: &TunnelSettings)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+    let spliced = splice(client, upstream, settings.idle_timeout, (&sent, &received));
+
+    let result = match settings.max_lifetime {
+        Some(max_lifetime) => tokio::time::timeout(max_lifetime, spliced)
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "tunnel reached its maximum lifetime"))),
+        None => spliced.await,
+    };
+
+    match result {
+        Ok((sent, received)) => info!("tunnel: {} closed, {} bytes sent, {} bytes received", description, sent, received),
+        Err(e) => info!("tunnel: {} closed: {}", description, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use futures::{SinkExt, StreamExt};
+    use hyper::service::{make_service_fn, service_fn};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::certificate_state::CertificateState;
+    use crate::kube_config_tracker::RoutingTable;
+    use crate::metrics::Metrics;
+    use crate::proxy::{proxy_request, ProxyState};
+    use crate::serverless::{KubernetesScaler, Serverless, ServerlessSettings};
+    use crate::shutdown::Shutdown;
+    use crate::telemetry::{B3Propagation, TelemetryConfig, Tracer};
+
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn websocket_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(message)) = websocket.next().await {
+                        if message.is_text() || message.is_binary() {
+                            websocket.send(message).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Runs the proxy in front of a single upstream, returning its address and metrics.
+    async fn proxy_to(upstream: SocketAddr, tunnel: TunnelSettings) -> (SocketAddr, Arc<Metrics>) {
+        let routing_table = Arc::new(RoutingTable::new());
+        routing_table.apply("default/echo".to_string(), vec![
+            Backend::with_prefix("127.0.0.1".to_string(), "/".to_string(), "127.0.0.1".to_string(), upstream.port()),
+        ]).await;
+
+        let metrics = Arc::new(Metrics::new());
+        let state = Arc::new(ProxyState {
+            routing_table,
+            cert_state: Arc::new(CertificateState::new()),
+            tracer: Arc::new(Tracer::new(TelemetryConfig {
+                otlp_endpoint: None,
+                sample_ratio: 1.0,
+                b3: B3Propagation::Disabled,
+                service_name: "iter-ingress-test".to_string(),
+            })),
+            metrics: metrics.clone(),
+            shutdown: Arc::new(Shutdown::new(Duration::from_secs(0), Duration::from_secs(0))),
+            tunnel,
+            serverless: Arc::new(Serverless::new(ServerlessSettings {
+                idle_timeout: Duration::from_secs(60),
+                hold_timeout: Duration::from_secs(1),
+                max_held_requests: 1,
+            }, Arc::new(KubernetesScaler::default()))),
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = make_service_fn(move |_| {
+            let state = state.clone();
+            let client = ClientInfo { addr: "127.0.0.1:1".parse().unwrap(), server_addr: addr, https: false, server_name: None, client_cert_subject: None };
+            async move { Ok::<_, Error>(service_fn(move |req| proxy_request(state.clone(), client.clone(), req))) }
+        });
+        tokio::spawn(hyper::Server::from_tcp(listener).unwrap().serve(service));
+
+        (addr, metrics)
+    }
+
+    fn settings() -> TunnelSettings {
+        TunnelSettings { idle_timeout: Duration::from_secs(5), max_lifetime: None }
+    }
+
+    #[tokio::test]
+    async fn proxies_websockets_and_counts_tunnels() {
+        let (proxy, metrics) = proxy_to(websocket_echo_server().await, settings()).await;
+
+        let (mut websocket, response) = tokio_tungstenite::connect_async(format!("ws://{}/echo", proxy)).await.unwrap();
+        assert_eq!(response.status(), 101);
+
+        websocket.send(Message::Text("hello".to_string())).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), Message::Text("hello".to_string()));
+        assert_eq!(metrics.active_tunnels.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        websocket.close(None).await.unwrap();
+        drop(websocket);
+        tokio::time::timeout(Duration::from_secs(2), metrics.tunnels_closed()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_tunnels_at_their_max_lifetime() {
+        let settings = TunnelSettings { idle_timeout: Duration::from_secs(5), max_lifetime: Some(Duration::from_millis(200)) };
+        let (proxy, metrics) = proxy_to(websocket_echo_server().await, settings).await;
+
+        let (mut websocket, _) = tokio_tungstenite::connect_async(format!("ws://{}/echo", proxy)).await.unwrap();
+        websocket.send(Message::Text("hello".to_string())).await.unwrap();
+        assert!(websocket.next().await.unwrap().is_ok());
+
+        // the proxy drops the connection without a close frame, so the stream ends or errors
+        let closed = tokio::time::timeout(Duration::from_secs(2), websocket.next()).await.unwrap();
+        assert!(!matches!(closed, Some(Ok(Message::Text(_)))));
+        tokio::time::timeout(Duration::from_secs(2), metrics.tunnels_closed()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_keep_working_after_half_close() {
+        // answers once the client has finished sending, like a request and response over a half-closed connection
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+        });
+
+        let (proxy, _) = proxy_to(upstream_addr, settings()).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", upstream_addr).as_bytes()).await.unwrap();
+
+        let mut response = [0u8; 12];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200");
+        let mut rest = Vec::new();
+        while !rest.ends_with(b"\r\n\r\n") {
+            rest.push(client.read_u8().await.unwrap());
+        }
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"ping");
+    }
+}
@@ -0,0 +1,184 @@
+//! # User Certificates
+//!
+//! Certificates teams bring themselves, e.g. EV or corporate CA certificates. An ingress lists them in
+//! `spec.tls`: each entry names a `kubernetes.io/tls` secret in the ingress' namespace and the hosts it is for.
+//! Those hosts are served with the secret's certificate instead of one issued with ACME, and never ordered from
+//! the CA. A host listed in two entries, one with an RSA and one with an ECDSA certificate, is served both.
+//!
+//! Every tls secret in the cluster is watched, so a certificate replaced in its secret is served straight away.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::ListParams;
+use kube::runtime::watcher::Event;
+use kube::{runtime, Api, Client, ResourceExt};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::certificate_state::{cert_key_from_pem, CertKey, CertificateState, Host, HostCerts};
+use crate::error::{Code, IngressLoadBalancerError};
+use crate::kube_config_tracker::RoutingTable;
+
+/// A `kubernetes.io/tls` secret named in an ingress' `spec.tls`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SecretRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl std::fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+pub struct UserCertificates {
+    client: Client,
+    routing_table: Arc<RoutingTable>,
+    state: Arc<CertificateState>,
+    /// every readable tls secret in the cluster
+    secrets: RwLock<HashMap<SecretRef, CertKey>>,
+    /// hosts already warned about, so the warning isn't repeated on every change
+    reported_missing: Mutex<HashSet<(Host, SecretRef)>>,
+}
+
+impl UserCertificates {
+    pub fn new(client: Client, routing_table: Arc<RoutingTable>, state: Arc<CertificateState>) -> UserCertificates {
+        UserCertificates {
+            client,
+            routing_table,
+            state,
+            secrets: RwLock::new(HashMap::new()),
+            reported_missing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Keeps the user certificates in the certificate state up to date with ingresses and secrets.
+    pub async fn run(self: Arc<Self>) {
+        let routes_changed = Arc::new(Notify::new());
+        self.routing_table.subscribe(Box::new({
+            let routes_changed = routes_changed.clone();
+            move |_| routes_changed.notify_one()
+        })).await;
+
+        let secrets: Api<Secret> = Api::all(self.client.clone());
+        let params = ListParams::default().fields("type=kubernetes.io/tls");
+        let mut stream = Box::pin(runtime::watcher(secrets, params));
+
+        loop {
+            tokio::select! {
+                event = stream.next() => match event {
+                    Some(Ok(Event::Applied(secret))) => self.apply(&secret).await,
+                    Some(Ok(Event::Deleted(secret))) => {
+                        self.secrets.write().await.remove(&secret_ref(&secret));
+                    }
+                    Some(Ok(Event::Restarted(secrets))) => {
+                        self.secrets.write().await.clear();
+                        for secret in &secrets {
+                            self.apply(secret).await;
+                        }
+                    }
+                    Some(Err(e)) => warn!("user_certs: error watching secrets: {}", e),
+                    None => return,
+                },
+                _ = routes_changed.notified() => {}
+            }
+
+            self.sync().await;
+        }
+    }
+
+    async fn apply(&self, secret: &Secret) {
+        let secret_ref = secret_ref(secret);
+        let data = secret.data.clone().unwrap_or_default();
+
+        let cert = match (data.get("tls.crt"), data.get("tls.key")) {
+            (Some(cert_pem), Some(key_pem)) => cert_key_from_pem(&cert_pem.0, &key_pem.0),
+            _ => Err(IngressLoadBalancerError::general(Code::InvalidCertificate, "tls.crt or tls.key is missing")),
+        };
+
+        match cert {
+            Ok(cert) => {
+                self.secrets.write().await.insert(secret_ref, cert);
+            }
+            Err(e) => {
+                // secrets nobody uses aren't worth a warning, sync warns about the ones an ingress names
+                debug!("user_certs: ignoring secret {}: {}", secret_ref, e);
+                self.secrets.write().await.remove(&secret_ref);
+            }
+        }
+    }
+
+    /// Serves every host named in `spec.tls` with its secret's certificate.
+    async fn sync(&self) {
+        let wanted = self.routing_table.user_certificates().await;
+        let secrets = self.secrets.read().await;
+        let (certs, missing) = certificates_for(&wanted, &secrets);
+
+        let mut reported = self.reported_missing.lock().await;
+        for (host, secret_ref) in missing.iter().filter(|missing| !reported.contains(missing)) {
+            warn!("user_certs: no valid certificate in secret {} for {}", secret_ref, host);
+        }
+        for (host, secret_ref) in reported.iter().filter(|reported| !missing.contains(reported)) {
+            if certs.contains_key(host) {
+                info!("user_certs: serving certificate from secret {} for {}", secret_ref, host);
+            }
+        }
+        *reported = missing;
+
+        *self.state.user_certs.write().await = certs;
+    }
+}
+
+/// The certificates for each host from the secrets it names, and the hosts whose secret doesn't exist or doesn't
+/// hold a valid certificate.
+fn certificates_for(
+    wanted: &HashMap<Host, Vec<SecretRef>>,
+    secrets: &HashMap<SecretRef, CertKey>,
+) -> (HashMap<Host, HostCerts>, HashSet<(Host, SecretRef)>) {
+    let mut certs: HashMap<Host, HostCerts> = HashMap::new();
+    let mut missing = HashSet::new();
+
+    for (host, secret_refs) in wanted {
+        for secret_ref in secret_refs {
+            match secrets.get(secret_ref) {
+                Some(cert) => certs.entry(host.clone()).or_default().insert(cert.clone()),
+                None => {
+                    missing.insert((host.clone(), secret_ref.clone()));
+                }
+            }
+        }
+    }
+
+    (certs, missing)
+}
+
+fn secret_ref(secret: &Secret) -> SecretRef {
+    SecretRef {
+        namespace: secret.namespace().unwrap_or_default(),
+        name: secret.name(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_hosts_from_the_secrets_they_name() {
+        let secret = |name: &str| SecretRef { namespace: "web".to_string(), name: name.to_string() };
+
+        let wanted: HashMap<Host, Vec<SecretRef>> = [
+            ("shop.example.com".to_string(), vec![secret("ev-cert")]),
+            ("api.example.com".to_string(), vec![secret("missing")]),
+        ].into_iter().collect();
+        let secrets: HashMap<SecretRef, CertKey> = [(secret("ev-cert"), crate::fallback_cert::self_signed("shop.example.com", crate::certificate_state::KeyType::Rsa, 30).unwrap())].into_iter().collect();
+
+        let (certs, missing) = certificates_for(&wanted, &secrets);
+        assert_eq!(certs.keys().collect::<Vec<_>>(), vec!["shop.example.com"]);
+        assert_eq!(missing, [("api.example.com".to_string(), secret("missing"))].into_iter().collect());
+    }
+}
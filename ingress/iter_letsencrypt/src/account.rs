@@ -1,7 +1,7 @@
 use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use hyper::{Body, Method, Request, Response};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use openssl::{pkey::{PKey, Private}};
 use p256::ecdsa::SigningKey;
 use serde::Serialize;
@@ -12,7 +12,7 @@ use crate::{
     error::LetsEncryptError,
     jwt::{generate_es256_key, get_jwt, JWSProtected, ESJWK, JwkThumbprint},
     nonce::get_nonce,
-    request::get_client, challenge::Http01Challenge, cert::{create_rsa_key, create_csr, Certificate}, order::{new_order, get_order}, response_debug_string,
+    request::get_client, challenge::Http01Challenge, cert::{create_rsa_key, create_csr, Certificate}, order::{new_order, get_order}, rate_limited, response_debug_string,
 };
 
 #[derive(Debug)]
@@ -60,7 +60,13 @@ async fn send_request<T: Serialize> (
         .body(Body::from(jws.to_string()))
         .unwrap();
 
-    Ok(client.request(req).await?)
+    let response = client.request(req).await?;
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(rate_limited(response).await);
+    }
+
+    Ok(response)
 }
 
 impl Account {
@@ -209,7 +215,11 @@ impl Account {
     }
 
     pub fn generate_csr(&self, domains: &[String]) -> Result<String, LetsEncryptError> {
-        let csr = create_csr(&self.private_key, domains)?
+        Self::generate_csr_for_key(&self.private_key, domains)
+    }
+
+    fn generate_csr_for_key(private_key: &PKey<Private>, domains: &[String]) -> Result<String, LetsEncryptError> {
+        let csr = create_csr(private_key, domains)?
             .to_der()
             .map_err(|e| LetsEncryptError::CSRError(format!("{:#?}", e)))?;
         // to der or pem??
@@ -217,9 +227,33 @@ impl Account {
 
     }
 
+    /// Revokes a certificate this account ordered. `cert` is the DER encoded leaf and `reason` a CRL reason code
+    /// from RFC 5280, e.g. 1 for keyCompromise or 5 for cessationOfOperation.
+    pub async fn revoke_certificate(&self, cert: &[u8], reason: u8) -> Result<(), LetsEncryptError> {
+        let url = self.directory.revoke_cert.clone().ok_or(LetsEncryptError::RevocationNotSupported)?;
+
+        let payload = json!({
+            "certificate": base64_url::encode(cert),
+            "reason": reason,
+        });
+
+        let response = self.send_request(Method::POST, &url, payload).await?;
+
+        if !response.status().is_success() {
+            return Err(LetsEncryptError::UnexpectedResponse(response_debug_string(response).await?));
+        }
+
+        Ok(())
+    }
+
     pub async fn generate_certificate<S: ServesChallenge>(&self, domains: &[String], challenge_handler: Arc<S>) -> Result<Certificate, LetsEncryptError> {
+        self.generate_certificate_for_key(domains, &self.private_key, challenge_handler).await
+    }
+
+    /// Orders a certificate for `private_key`, which may be an RSA or an ECDSA key.
+    pub async fn generate_certificate_for_key<S: ServesChallenge>(&self, domains: &[String], private_key: &PKey<Private>, challenge_handler: Arc<S>) -> Result<Certificate, LetsEncryptError> {
         let payload = json!({
-            "csr": self.generate_csr(domains)?
+            "csr": Self::generate_csr_for_key(private_key, domains)?
         });
 
         let (order_url, finalize_url) = new_order(&self, domains, challenge_handler).await?;
@@ -259,7 +293,7 @@ impl Account {
             .map_err(|e| LetsEncryptError::HyperError(e))?
             .to_vec();
 
-        let priv_key_pem = self.private_key.private_key_to_pem_pkcs8()
+        let priv_key_pem = private_key.private_key_to_pem_pkcs8()
             .map_err(|_| LetsEncryptError::PrivateKeyError)?;
 
         let cert = Certificate::new(priv_key_pem, cert);
@@ -1,7 +1,9 @@
 use std::time::{Duration, SystemTime};
 
 use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkey::{PKey, Private};
 use openssl::rsa::Rsa;
 use openssl::stack::Stack;
@@ -19,6 +21,15 @@ pub fn create_rsa_key(bits: u32) -> PKey<Private> {
     PKey::from_rsa(pri_key_rsa).expect("PKey::from_rsa")
 }
 
+/// Make an ECDSA private key on the given curve, e.g. `Nid::X9_62_PRIME256V1` for P-256.
+pub fn create_ec_key(curve: Nid) -> Result<PKey<Private>, LetsEncryptError> {
+    let key: Result<PKey<Private>, openssl::error::ErrorStack> = EcGroup::from_curve_name(curve)
+        .and_then(|group| EcKey::generate(&group))
+        .and_then(PKey::from_ec_key);
+
+    key.map_err(|_| LetsEncryptError::PrivateKeyError)
+}
+
 pub(crate) fn create_csr(pkey: &PKey<Private>, domains: &[String]) -> Result<X509Req, LetsEncryptError> {
     //
     // the csr builder
@@ -13,6 +13,9 @@ pub struct Directory {
     pub new_nonce: String,
     #[serde(rename = "newOrder")]
     pub new_order: String,
+    /// Missing from directories of CAs which don't support revocation
+    #[serde(rename = "revokeCert", default)]
+    pub revoke_cert: Option<String>,
 }
 
 impl Directory {
@@ -1,5 +1,7 @@
 
 
+use std::time::Duration;
+
 #[derive(Debug)]
 pub enum LetsEncryptError {
     HyperError(hyper::Error),
@@ -19,7 +21,10 @@ pub enum LetsEncryptError {
     InvalidCertificate,
     CSRError(String),
     PrivateKeyError,
-    CouldNotGetOrder
+    CouldNotGetOrder,
+    RevocationNotSupported,
+    /// The CA refused the request because a rate limit was hit, `retry_after` is how long it asked to wait
+    RateLimited { retry_after: Option<Duration>, detail: String },
 }
 
 impl From<hyper::Error> for LetsEncryptError {
@@ -1,3 +1,6 @@
+use std::time::{Duration, SystemTime};
+
+use hyper::header::RETRY_AFTER;
 use hyper::{Response, Body};
 
 use crate::error::LetsEncryptError;
@@ -25,4 +28,39 @@ pub async fn response_debug_string(response: Response<Body>) -> Result<String, L
             .map_err(|e| LetsEncryptError::HyperError(e))?
             .to_vec())
     }))
-}
\ No newline at end of file
+}
+
+/// The error for a response refused because a rate limit was hit, with the problem's detail.
+pub async fn rate_limited(response: Response<Body>) -> LetsEncryptError {
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+
+    #[derive(Deserialize)]
+    struct Problem {
+        detail: String,
+    }
+
+    let detail = match hyper::body::to_bytes(response.into_body()).await {
+        Ok(body) => match serde_json::from_slice::<Problem>(&body) {
+            Ok(problem) => problem.detail,
+            Err(_) => String::from_utf8_lossy(&body).to_string(),
+        },
+        Err(e) => e.to_string(),
+    };
+
+    LetsEncryptError::RateLimited { retry_after, detail }
+}
+
+/// A `Retry-After` header's value, either seconds or an HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
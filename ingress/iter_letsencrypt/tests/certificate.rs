@@ -40,4 +40,16 @@ async fn can_generate_csr() -> Result<(), LetsEncryptError> {
     ])?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn can_revoke_certificate() -> Result<(), LetsEncryptError> {
+    let (_handle, url) = with_directory_server();
+    let directory = Directory::from_url(&url).await?;
+    assert!(directory.revoke_cert.is_some());
+    let account = directory.new_account("test@example.com").await?;
+
+    account.revoke_certificate(b"DER", 1).await?;
+
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use std::time::{Duration, SystemTime};
+
+use iter_letsencrypt::parse_retry_after;
+
+#[test]
+fn parses_retry_after_seconds_and_dates() {
+    assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+    assert_eq!(parse_retry_after("soon"), None);
+
+    let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+    let wait = parse_retry_after(&later).unwrap();
+    assert!(wait > Duration::from_secs(3500) && wait <= Duration::from_secs(3600));
+}
@@ -169,6 +169,10 @@ pub fn post_challenge(url: &str) -> Response<Body> {
         .unwrap()
 }
 
+pub fn post_revoke_cert(_url: &str) -> Response<Body> {
+    Response::builder().status(200).body(Body::empty()).unwrap()
+}
+
 pub fn route_request(req: Request<Body>, url: &str) -> Response<Body> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/directory") => get_directory(url),
@@ -180,6 +184,7 @@ pub fn route_request(req: Request<Body>, url: &str) -> Response<Body> {
         (&Method::POST, "/acme/finalize/7738992/18234324") => post_finalize(url),
         (&Method::POST, "/acme/cert/fae41c070f967713109028") => post_certificate(url),
         (&Method::POST, "/acme/challenge/YTqpYUthlVfwBncUufE8IRWLMSRqcSs/216789597") => post_challenge(url),
+        (&Method::POST, "/acme/revoke-cert") => post_revoke_cert(url),
         (_, _) => Response::builder().status(404).body(Body::empty()).unwrap(),
     }
 }
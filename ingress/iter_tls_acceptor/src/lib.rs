@@ -1,5 +1,7 @@
 
 pub mod tls_acceptor;
 pub mod utils;
+pub mod rewind;
+pub mod proxy_protocol;
 
 pub use tokio_rustls::StartHandshake;
\ No newline at end of file
@@ -0,0 +1,408 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, pin::Pin, str::FromStr, sync::Arc, task::{Context, Poll}, time::Duration};
+
+use futures::{future::poll_fn, ready};
+use hyper::server::{conn::{AddrIncoming, AddrStream}, accept::Accept as HyperAccept};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel, UnboundedSender};
+use tracing::{info, warn};
+
+use crate::rewind::Rewind;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// How long a trusted source gets to send its PROXY header before the connection is dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Version, String> {
+        match s.trim().to_lowercase().as_str() {
+            "v1" | "1" => Ok(Version::V1),
+            "v2" | "2" => Ok(Version::V2),
+            other => Err(format!("unknown PROXY protocol version {:?}, expected v1 or v2", other)),
+        }
+    }
+}
+
+/// Which peers are allowed to send PROXY protocol headers, usually the load balancers in front of the ingress.
+///
+/// Connections from trusted sources may start with a v1 or v2 header, connections from anywhere else are never
+/// checked for one, so clients can't spoof their address.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocolConfig {
+    trusted_sources: Vec<IpNet>,
+}
+
+impl ProxyProtocolConfig {
+    /// Parses a comma separated list of CIDRs, such as `10.0.0.0/8,fd00::/8`.
+    pub fn parse(trusted_sources: &str) -> Result<ProxyProtocolConfig, String> {
+        let trusted_sources = trusted_sources
+            .split(',')
+            .map(|cidr| cidr.trim())
+            .filter(|cidr| !cidr.is_empty())
+            .map(|cidr| IpNet::from_str(cidr).map_err(|e| format!("invalid CIDR {:?}: {}", cidr, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ProxyProtocolConfig { trusted_sources })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.trusted_sources.is_empty()
+    }
+
+    pub fn trusts(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            v4 => v4,
+        };
+
+        self.trusted_sources.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    /// a complete header of `len` bytes, with the addresses it carried unless it was a health check or `UNKNOWN`
+    Header { len: usize, addresses: Option<(SocketAddr, SocketAddr)> },
+    Incomplete,
+    NotProxy,
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid PROXY header: {}", message))
+}
+
+fn parse(buf: &[u8]) -> std::io::Result<Parsed> {
+    let v1_matches = buf.len().min(V1_PREFIX.len());
+    let v2_matches = buf.len().min(V2_SIGNATURE.len());
+
+    if buf[..v1_matches] == V1_PREFIX[..v1_matches] {
+        if buf.len() < V1_PREFIX.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        return parse_v1(buf);
+    }
+
+    if buf[..v2_matches] == V2_SIGNATURE[..v2_matches] {
+        if buf.len() < V2_HEADER_LEN {
+            return Ok(Parsed::Incomplete);
+        }
+        return parse_v2(buf);
+    }
+
+    Ok(Parsed::NotProxy)
+}
+
+fn parse_v1(buf: &[u8]) -> std::io::Result<Parsed> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err(invalid("v1 header is too long")),
+        None => return Ok(Parsed::Incomplete),
+    };
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("v1 header is not ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let addresses = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |ip: &str| -> std::io::Result<IpAddr> {
+                let ip = IpAddr::from_str(ip).map_err(|_| invalid("v1 address"))?;
+                match (*family, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                    _ => Err(invalid("v1 address does not match its family")),
+                }
+            };
+            let port = |port: &str| port.parse::<u16>().map_err(|_| invalid("v1 port"));
+
+            Some((
+                SocketAddr::new(ip(source)?, port(source_port)?),
+                SocketAddr::new(ip(destination)?, port(destination_port)?),
+            ))
+        }
+        _ => return Err(invalid("malformed v1 header")),
+    };
+
+    Ok(Parsed::Header { len: end + 2, addresses })
+}
+
+fn parse_v2(buf: &[u8]) -> std::io::Result<Parsed> {
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let body = &buf[V2_HEADER_LEN..len];
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+
+    let addresses = match (version_command & 0x0f, family >> 4) {
+        // LOCAL, sent by the load balancer itself, e.g. for health checks
+        (0x0, _) => None,
+        // PROXY over ipv4
+        (0x1, 0x1) if body.len() >= 12 => {
+            let source = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let destination = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Some((SocketAddr::new(source.into(), port(8)), SocketAddr::new(destination.into(), port(10))))
+        }
+        // PROXY over ipv6
+        (0x1, 0x2) if body.len() >= 36 => {
+            let source: [u8; 16] = body[0..16].try_into().unwrap();
+            let destination: [u8; 16] = body[16..32].try_into().unwrap();
+            Some((SocketAddr::new(Ipv6Addr::from(source).into(), port(32)), SocketAddr::new(Ipv6Addr::from(destination).into(), port(34))))
+        }
+        // unix sockets and unspecified families carry no usable address
+        (0x1, 0x0) | (0x1, 0x3) => None,
+        _ => return Err(invalid("malformed v2 header")),
+    };
+
+    Ok(Parsed::Header { len, addresses })
+}
+
+/// Encodes a header announcing a connection from `source` to `destination`.
+pub fn encode(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    // both addresses must have the same family, so mixed pairs are sent as ipv6
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => (source, destination),
+        _ => (to_ipv6(source), to_ipv6(destination)),
+    };
+
+    match version {
+        Version::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), destination.ip(), source.port(), destination.port()).into_bytes()
+        }
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x21);
+
+            let mut addresses = Vec::new();
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    header.push(0x11);
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                }
+                (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                    header.push(0x21);
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                }
+                _ => unreachable!("addresses were converted to the same family"),
+            }
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(v4) => SocketAddr::new(v4.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+/// Reads a PROXY header if the stream starts with one, returning the addresses it carried and any bytes read past it.
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(Option<(SocketAddr, SocketAddr)>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 512];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        match parse(&buf)? {
+            Parsed::Header { len, addresses } => return Ok((addresses, buf.split_off(len))),
+            Parsed::NotProxy => return Ok((None, buf)),
+            Parsed::Incomplete => continue,
+        }
+    }
+}
+
+/// A client connection and the addresses it was made between. When a trusted load balancer sent a PROXY header
+/// these are the addresses from the header, otherwise those of the tcp connection.
+pub struct ClientStream {
+    stream: Rewind<AddrStream>,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+}
+
+impl ClientStream {
+    /// Reads the PROXY header off the connection if it comes from a trusted source.
+    pub async fn accept(mut stream: AddrStream, config: &ProxyProtocolConfig) -> std::io::Result<ClientStream> {
+        let (client_addr, server_addr) = (stream.remote_addr(), stream.local_addr());
+
+        if !config.trusts(client_addr.ip()) {
+            return Ok(ClientStream { stream: Rewind::new(Vec::new(), stream), client_addr, server_addr });
+        }
+
+        let (addresses, buffered) = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out waiting for PROXY header"))??;
+        let (client_addr, server_addr) = addresses.unwrap_or((client_addr, server_addr));
+
+        Ok(ClientStream { stream: Rewind::new(buffered, stream), client_addr, server_addr })
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// The address of the tcp peer, which is the load balancer when the connection was proxied.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.inner().remote_addr()
+    }
+
+    /// Puts bytes which were read from the stream back, so they are read again.
+    pub(crate) fn unread(&mut self, bytes: Vec<u8>) {
+        self.stream.unread(bytes);
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts plain tcp connections, reading PROXY headers from trusted sources before handing them to hyper.
+pub struct ClientAcceptor {
+    receiver: UnboundedReceiver<ClientStream>,
+}
+
+impl ClientAcceptor {
+    pub fn new(incoming: AddrIncoming, config: Arc<ProxyProtocolConfig>) -> ClientAcceptor {
+        let (sender, receiver) = unbounded_channel::<ClientStream>();
+        tokio::task::spawn(Self::accept_loop(incoming, config, sender));
+
+        ClientAcceptor {
+            receiver
+        }
+    }
+
+    async fn accept_loop(mut incoming: AddrIncoming, config: Arc<ProxyProtocolConfig>, sender: UnboundedSender<ClientStream>) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = poll_fn(|ctx| Pin::new(&mut incoming).poll_accept(ctx)) => accepted,
+                _ = sender.closed() => return info!("client_acceptor: acceptor dropped, no longer accepting"),
+            };
+
+            match accepted {
+                Some(Ok(stream)) => {
+                    let config = config.clone();
+                    let sender = sender.clone();
+                    tokio::task::spawn(async move {
+                        match ClientStream::accept(stream, &config).await {
+                            Ok(stream) => { let _ = sender.send(stream); }
+                            Err(e) => warn!("client_acceptor: {}", e),
+                        }
+                    });
+                },
+                Some(Err(e)) => warn!("client_acceptor: error accepting incoming: {}", e),
+                None => return info!("client_acceptor: incoming stream closed"),
+            }
+        }
+    }
+}
+
+impl HyperAccept for ClientAcceptor {
+    type Conn = ClientStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        match ready!(self.get_mut().receiver.poll_recv(cx)) {
+            Some(stream) => Poll::Ready(Some(Ok(stream))),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_v1_and_v2_headers() {
+        let source: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let destination: SocketAddr = "10.0.0.1:443".parse().unwrap();
+
+        for version in [Version::V1, Version::V2] {
+            let mut buf = encode(version, source, destination);
+            let len = buf.len();
+            buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+            assert_eq!(parse(&buf).unwrap(), Parsed::Header { len, addresses: Some((source, destination)) });
+            assert_eq!(parse(&buf[..len - 1]).unwrap(), Parsed::Incomplete);
+        }
+
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let buf = encode(Version::V2, v6, destination);
+        assert_eq!(buf[13], 0x21);
+        assert!(matches!(parse(&buf).unwrap(), Parsed::Header { addresses: Some((addr, _)), .. } if addr == v6));
+    }
+
+    #[test]
+    fn parses_unknown_and_rejects_garbage() {
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Parsed::Header { len: 15, addresses: None });
+        assert_eq!(parse(b"POST / HTTP/1.1\r\n").unwrap(), Parsed::NotProxy);
+        assert_eq!(parse(&[0x16, 0x03, 0x01]).unwrap(), Parsed::NotProxy);
+        assert!(parse(b"PROXY TCP4 10.0.0.1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 ::1 10.0.0.1 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn only_trusts_configured_sources() {
+        let config = ProxyProtocolConfig::parse("10.0.0.0/8, 192.168.1.1/32").unwrap();
+        assert!(config.trusts("10.1.2.3".parse().unwrap()));
+        assert!(config.trusts("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!config.trusts("192.168.1.2".parse().unwrap()));
+
+        assert!(!ProxyProtocolConfig::parse("").unwrap().is_enabled());
+        assert!(ProxyProtocolConfig::parse("10.0.0.0/33").is_err());
+    }
+}
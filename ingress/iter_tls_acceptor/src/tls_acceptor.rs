@@ -1,13 +1,22 @@
-use std::{pin::Pin, task::{Poll, Context}, sync::Arc};
+use std::{pin::Pin, task::{Poll, Context}, sync::Arc, time::Duration};
 
 use futures::{future::poll_fn, ready};
 use hyper::server::{conn::{AddrIncoming, AddrStream}, accept::Accept as HyperAccept};
-use rustls::{server::ClientHello, ServerConfig};
+use rustls::{server::{Acceptor, ClientHello}, ServerConfig};
+use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel, UnboundedSender};
 use tokio_rustls::{server::TlsStream, LazyConfigAcceptor};
+use tracing::{info, warn};
+
+use crate::proxy_protocol::{ClientStream, ProxyProtocolConfig};
+
+/// How long a client gets to send its client hello before the connection is dropped.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type TlsConn = TlsStream<ClientStream>;
 
 pub struct TlsAcceptor {
-    receiver: UnboundedReceiver<TlsStream<AddrStream>>,
+    receiver: UnboundedReceiver<TlsConn>,
 }
 
 #[async_trait::async_trait]
@@ -15,49 +24,126 @@ pub trait ResolvesServerConf {
     async fn resolve_server_config(self: Arc<Self>, client_hello: &ClientHello) -> Option<Arc<ServerConfig>>;
 }
 
+/// Takes over connections for hosts which terminate tls themselves, instead of the acceptor completing the handshake.
+#[async_trait::async_trait]
+pub trait HandlesPassthrough {
+    async fn is_passthrough(&self, server_name: &str) -> bool;
+
+    /// Forwards the still encrypted connection, which replays the client hello before anything else is read.
+    async fn forward(self: Arc<Self>, server_name: String, stream: ClientStream);
+}
+
+#[derive(Clone, Default)]
+pub struct TlsAcceptorOptions {
+    /// connections for hosts the handler wants are passed to it without terminating tls
+    pub passthrough: Option<Arc<dyn HandlesPassthrough + Send + Sync>>,
+    /// sources allowed to send a PROXY protocol header ahead of the tls handshake
+    pub proxy_protocol: Arc<ProxyProtocolConfig>,
+}
+
 impl TlsAcceptor {
     pub fn new <R: ResolvesServerConf + Send + Sync + 'static> (incoming: AddrIncoming, resolver: Arc<R>) -> TlsAcceptor {
-        let (sender, receiver) = unbounded_channel::<TlsStream<AddrStream>>();
-        tokio::task::spawn(Self::accept_loop(incoming, resolver, sender));
+        Self::with_options(incoming, resolver, TlsAcceptorOptions::default())
+    }
+
+    pub fn with_options <R: ResolvesServerConf + Send + Sync + 'static> (incoming: AddrIncoming, resolver: Arc<R>, options: TlsAcceptorOptions) -> TlsAcceptor {
+        let (sender, receiver) = unbounded_channel::<TlsConn>();
+        tokio::task::spawn(Self::accept_loop(incoming, resolver, options, sender));
 
         TlsAcceptor {
             receiver
         }
     }
 
-    async fn accept_loop <R: ResolvesServerConf + Send + Sync + 'static> (mut incoming: AddrIncoming, resolver: Arc<R>, sender: UnboundedSender<TlsStream<AddrStream>>) {
+    async fn accept_loop <R: ResolvesServerConf + Send + Sync + 'static> (mut incoming: AddrIncoming, resolver: Arc<R>, options: TlsAcceptorOptions, sender: UnboundedSender<TlsConn>) {
         loop {
-            match poll_fn(|ctx| Pin::new(&mut incoming).poll_accept(ctx)).await {
+            let accepted = tokio::select! {
+                accepted = poll_fn(|ctx| Pin::new(&mut incoming).poll_accept(ctx)) => accepted,
+                // the acceptor was dropped, e.g. by a server shutting down, so stop listening altogether
+                _ = sender.closed() => return info!("tls_accceptor: acceptor dropped, no longer accepting"),
+            };
+
+            match accepted {
                 Some(Ok(stream)) => {
-                    tokio::task::spawn(Self::handle_stream(stream, resolver.clone(), sender.clone()));
+                    tokio::task::spawn(Self::handle_stream(stream, resolver.clone(), options.clone(), sender.clone()));
                 },
-                Some(Err(e)) => eprintln!("tls_accceptor: error accepting incoming: {}", e),
-                None => println!("tls_accceptor: incoming stream closed"),
+                Some(Err(e)) => warn!("tls_accceptor: error accepting incoming: {}", e),
+                None => return info!("tls_accceptor: incoming stream closed"),
             }
         }
     }
 
-    async fn handle_stream <R: ResolvesServerConf + Send + Sync + 'static> (stream: AddrStream, resolver: Arc<R>, sender: UnboundedSender<TlsStream<AddrStream>>) {
-        let acceptor = rustls::server::Acceptor::default();
+    async fn handle_stream <R: ResolvesServerConf + Send + Sync + 'static> (stream: AddrStream, resolver: Arc<R>, options: TlsAcceptorOptions, sender: UnboundedSender<TlsConn>) {
+        let mut stream = match ClientStream::accept(stream, &options.proxy_protocol).await {
+            Ok(stream) => stream,
+            Err(err) => return warn!("tls_acceptor: {}", err),
+        };
+
+        // the client hello is read up front so passthrough hosts can be picked by sni, then replayed for the handshake
+        let (client_hello, server_name) = match tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(&mut stream)).await {
+            Ok(Ok(hello)) => hello,
+            Ok(Err(err)) => return warn!("tls_acceptor: could not read client hello: {}", err),
+            Err(_) => return warn!("tls_acceptor: timed out waiting for client hello"),
+        };
+        stream.unread(client_hello);
+
+        if let (Some(passthrough), Some(server_name)) = (options.passthrough, server_name) {
+            if passthrough.is_passthrough(&server_name).await {
+                return passthrough.forward(server_name, stream).await;
+            }
+        }
+
+        let acceptor = Acceptor::default();
         let tls_stream = match LazyConfigAcceptor::new(acceptor, stream).await {
-            Err(err) => return eprintln!("tls_acceptor: accept error: {}", err),
+            Err(err) => return warn!("tls_acceptor: accept error: {}", err),
             Ok(handshake) => match resolver.resolve_server_config(&handshake.client_hello()).await {
                 Some(config) => match handshake.into_stream(config).await {
                     Ok(stream) => stream,
-                    Err(err) => return eprintln!("tls_acceptor: handshake error: {}", err)
+                    Err(err) => return warn!("tls_acceptor: handshake error: {}", err)
                 }
-                None => return eprintln!("tls_acceptor: no server config for client"),
+                None => return warn!("tls_acceptor: no server config for client"),
             }
         };
 
         if let Err(e) = sender.send(tls_stream) {
-            eprintln!("tls_accceptor: error sending result: {}", e);
+            warn!("tls_accceptor: error sending result: {}", e);
+        }
+    }
+}
+
+/// Reads from the stream until a whole client hello has arrived, returning the bytes read and the requested server name.
+async fn read_client_hello(stream: &mut ClientStream) -> std::io::Result<(Vec<u8>, Option<String>)> {
+    let mut acceptor = Acceptor::default();
+    let mut client_hello = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        client_hello.extend_from_slice(&buf[..read]);
+
+        let mut unparsed = &buf[..read];
+        while !unparsed.is_empty() {
+            if acceptor.read_tls(&mut unparsed)? == 0 {
+                break;
+            }
+        }
+
+        match acceptor.accept() {
+            Ok(Some(accepted)) => {
+                let server_name = accepted.client_hello().server_name().map(|name| name.to_string());
+                return Ok((client_hello, server_name));
+            }
+            Ok(None) => continue,
+            Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
         }
     }
 }
 
 impl HyperAccept for TlsAcceptor {
-    type Conn = TlsStream<AddrStream>;
+    type Conn = TlsConn;
     type Error = std::io::Error;
 
     fn poll_accept(
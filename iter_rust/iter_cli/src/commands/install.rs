@@ -120,7 +120,8 @@ impl RunnableCommand for InstallCommand {
                     }),
                     spec: Some(PodSpec {
                         service_account_name: Some(ITER_SERVICE_ACCOUNT_NAME.to_string()),
-                        termination_grace_period_seconds: Some(0),
+                        // covers the ingress drain delay and shutdown grace period
+                        termination_grace_period_seconds: Some(40),
                         containers: vec![Container {
                             name: ITER_INGRESS_POD_NAME.to_string(),
                             image: Some(INGRESS_DAEMONSET_IMAGE.to_string()),
@@ -222,6 +223,7 @@ impl RunnableCommand for InstallCommand {
                         "pods".to_string(),
                         "services".to_string(),
                         "secrets".to_string(),
+                        "configmaps".to_string(),
                     ]),
                     verbs: vec!["get".to_string(), "list".to_string(), "watch".to_string()],
                     ..Default::default()
@@ -233,7 +235,37 @@ impl RunnableCommand for InstallCommand {
                         "".to_string(),
                     ]),
                     resources: Some(vec!["secrets".to_string()]),
-                    verbs: vec!["update".to_string(), "create".to_string()],
+                    verbs: vec!["update".to_string(), "create".to_string(), "delete".to_string()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    api_groups: Some(vec!["".to_string()]),
+                    resources: Some(vec!["configmaps".to_string()]),
+                    verbs: vec!["create".to_string(), "delete".to_string()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    api_groups: Some(vec!["coordination.k8s.io".to_string()]),
+                    resources: Some(vec!["leases".to_string()]),
+                    verbs: vec!["get".to_string(), "create".to_string(), "update".to_string()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    api_groups: Some(vec!["apps".to_string()]),
+                    resources: Some(vec!["deployments".to_string()]),
+                    verbs: vec!["get".to_string(), "patch".to_string()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    api_groups: Some(vec!["events.k8s.io".to_string(), "".to_string()]),
+                    resources: Some(vec!["events".to_string()]),
+                    verbs: vec!["create".to_string(), "patch".to_string()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    api_groups: Some(vec!["networking.k8s.io".to_string()]),
+                    resources: Some(vec!["ingresses/status".to_string()]),
+                    verbs: vec!["get".to_string(), "patch".to_string(), "update".to_string()],
                     ..Default::default()
                 },
             ]),